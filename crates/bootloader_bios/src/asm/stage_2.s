@@ -1,12 +1,13 @@
 .section .boot, "awx"
 .code16
 
-# This stage sets the target operating mode, loads the kernel from disk,
-# creates an e820 memory map, enters protected mode, and jumps to the
-# third stage.
+# This stage sets the target operating mode, loads the kernel (and the
+# initrd appended to the image, if any) from disk, creates an e820 memory
+# map, enters protected mode, and jumps to the third stage.
 
 second_stage_start_str: .asciz "Booting (second stage)..."
 kernel_load_failed_str: .asciz "Failed to load kernel from disk"
+initrd_load_failed_str: .asciz "Failed to load initrd from disk"
 
 kernel_load_failed:
     mov si, offset kernel_load_failed_str
@@ -14,6 +15,11 @@ kernel_load_failed:
 kernel_load_failed_spin:
     jmp kernel_load_failed_spin
 
+initrd_load_failed:
+    mov si, offset initrd_load_failed_str
+    call real_mode_println
+    jmp kernel_load_failed_spin
+
 stage_2:
     mov si, offset second_stage_start_str
     call real_mode_println
@@ -81,6 +87,56 @@ load_next_kernel_block_from_disk:
     sub ecx, 1
     jnz load_next_kernel_block_from_disk
 
+load_initrd_from_disk:
+    # The image may continue with a header sector ("H4INITRD", then the size
+    # as u64) followed by the initrd, read that sector first. Reading past the
+    # end of the disk means there is none.
+    mov si, offset dap
+    mov ah, 0x42
+    int 0x13
+    jc create_memory_map
+
+    movzx esi, word ptr [dap_buffer_addr]
+    cmp dword ptr [esi], 0x4e493448 # "H4IN"
+    jne create_memory_map
+    cmp dword ptr [esi + 4], 0x44525449 # "ITRD"
+    jne create_memory_map
+    cmp dword ptr [esi + 12], 0 # larger than 4 GiB
+    jne create_memory_map
+    mov ecx, [esi + 8]
+    test ecx, ecx
+    jz create_memory_map
+
+    # destination address, the first page after the kernel
+    add edi, 0xfff
+    and edi, 0xfffff000
+    mov [initrd_addr], edi
+    mov [initrd_size], ecx
+
+    # block count
+    add ecx, 511 # align up
+    shr ecx, 9
+
+load_next_initrd_block_from_disk:
+    # next block, the first one is right after the header
+    mov eax, [dap_start_lba]
+    add eax, 1
+    mov [dap_start_lba], eax
+
+    mov si, offset dap
+    mov ah, 0x42
+    int 0x13
+    jc initrd_load_failed
+
+    push ecx
+    mov ecx, 512 / 4
+    movzx esi, word ptr [dap_buffer_addr]
+    rep movsd [edi], [esi]
+    pop ecx
+
+    sub ecx, 1
+    jnz load_next_initrd_block_from_disk
+
 create_memory_map:
     lea di, es:[_memory_map]
     call do_e820
@@ -169,3 +225,9 @@ vga_print_char:
 
 vga_position:
     .double 0
+
+# Physical location of the initrd, the size is 0 if there is none
+initrd_addr:
+    .4byte 0
+initrd_size:
+    .4byte 0
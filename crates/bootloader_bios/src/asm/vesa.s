@@ -290,9 +290,8 @@ VBEEDID:
 	VBEEDID_extensionflag: .skip 1, 0
 	VBEEDID_checksum: .skip 1, 0
 
+# config_xres and config_yres are in the config sector (see linker.ld)
 config:
-  config_xres: .2byte 0
-  config_yres: .2byte 0
   vesa_minx: .2byte 640
   vesa_miny: .2byte 480
 
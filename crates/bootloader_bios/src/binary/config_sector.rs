@@ -0,0 +1,123 @@
+//! The sector of the image holding the kernel command line.
+//!
+//! It is loaded together with the rest of the bootloader (see `linker.ld`), a
+//! default is built in from `HUGO4OS_CMDLINE` and it can be overwritten in a
+//! finished image (see `just set-cmdline`). The screen resolution is needed
+//! before there is any Rust code to parse the command line, so it is stored
+//! separately too:
+//!
+//! ```text
+//! 0   magic ("H4OSCMD\0")
+//! 8   width  (u16, 0 for the best available mode)
+//! 10  height (u16)
+//! 12  command line (UTF-8, NUL terminated)
+//! ```
+
+/// Size of the sector in bytes.
+pub const SIZE: usize = 512;
+
+const MAGIC: &[u8; 8] = b"H4OSCMD\0";
+const WIDTH: usize = 8;
+const HEIGHT: usize = 10;
+const COMMAND_LINE: usize = 12;
+
+/// Longest command line that fits, without its NUL terminator.
+pub const COMMAND_LINE_MAX: usize = SIZE - COMMAND_LINE - 1;
+
+/// Build the sector for `command_line`, at compile time.
+pub const fn new(command_line: &str) -> [u8; SIZE] {
+    let command_line = command_line.as_bytes();
+    assert!(command_line.len() <= COMMAND_LINE_MAX, "kernel command line too long");
+
+    let mut sector = [0; SIZE];
+    let mut i = 0;
+    while i < MAGIC.len() {
+        sector[i] = MAGIC[i];
+        i += 1;
+    }
+
+    let (width, height) = resolution(command_line);
+    sector[WIDTH] = width as u8;
+    sector[WIDTH + 1] = (width >> 8) as u8;
+    sector[HEIGHT] = height as u8;
+    sector[HEIGHT + 1] = (height >> 8) as u8;
+
+    let mut i = 0;
+    while i < command_line.len() {
+        sector[COMMAND_LINE + i] = command_line[i];
+        i += 1;
+    }
+
+    sector
+}
+
+/// The command line stored in `sector`, or `None` if it isn't a config
+/// sector or doesn't hold valid UTF-8.
+pub fn command_line(sector: &'static [u8; SIZE]) -> Option<&'static str> {
+    if &sector[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+
+    let command_line = &sector[COMMAND_LINE..];
+    let length = command_line.iter().position(|&byte| byte == 0)?;
+    core::str::from_utf8(&command_line[..length]).ok()
+}
+
+/// Value of the last `resolution=<width>x<height>` option, (0, 0) if there is
+/// none or it is invalid.
+const fn resolution(command_line: &[u8]) -> (u16, u16) {
+    const OPTION: &[u8] = b"resolution=";
+
+    let mut found = (0, 0);
+    let mut start = 0;
+    while start < command_line.len() {
+        let mut end = start;
+        while end < command_line.len() && !command_line[end].is_ascii_whitespace() {
+            end += 1;
+        }
+
+        if starts_with(command_line, start, end, OPTION) {
+            let (width, next) = number(command_line, start + OPTION.len(), end);
+            if next < end && command_line[next] == b'x' {
+                let (height, next) = number(command_line, next + 1, end);
+                found = if next == end && width != 0 && height != 0 { (width, height) } else { (0, 0) };
+            } else {
+                found = (0, 0);
+            }
+        }
+
+        start = end + 1;
+    }
+
+    found
+}
+
+const fn starts_with(bytes: &[u8], start: usize, end: usize, prefix: &[u8]) -> bool {
+    if end - start < prefix.len() {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < prefix.len() {
+        if bytes[start + i] != prefix[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Decimal number starting at `start`, and the index after it. Values that
+/// don't fit are 0.
+const fn number(bytes: &[u8], start: usize, end: usize) -> (u16, usize) {
+    let mut value: u32 = 0;
+    let mut i = start;
+    while i < end && bytes[i].is_ascii_digit() {
+        value = value * 10 + (bytes[i] - b'0') as u32;
+        if value > u16::MAX as u32 {
+            return (0, end);
+        }
+        i += 1;
+    }
+    (value as u16, i)
+}
@@ -1,5 +1,5 @@
 use crate::boot_info::{MemoryRegion, MemoryRegionKind};
-use core::mem::MaybeUninit;
+use core::{mem::MaybeUninit, ops::Range};
 use x86_64::{
     structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
     PhysAddr,
@@ -92,12 +92,15 @@ where
     /// Converts this type to a boot info memory map.
     ///
     /// The memory map is placed in the given `regions` slice. The length of the given slice
-    /// must be at least the value returned by [`len`] pluse 1.
+    /// must be at least the value returned by [`len`] plus 3. The (possibly empty) `initrd`
+    /// range is reported as [`MemoryRegionKind::Initrd`], it must be below the first frame
+    /// that was free for allocation.
     ///
     /// The return slice is a subslice of `regions`, shortened to the actual number of regions.
     pub fn construct_memory_map(
         self,
         regions: &mut [MaybeUninit<MemoryRegion>],
+        initrd: Range<u64>,
     ) -> &mut [MemoryRegion] {
         let mut next_index = 0;
 
@@ -118,7 +121,7 @@ where
                             end: next_free.as_u64(),
                             kind: MemoryRegionKind::Bootloader,
                         };
-                        Self::add_region(used_region, regions, &mut next_index)
+                        Self::add_region_around(used_region, &initrd, regions, &mut next_index)
                             .expect("Failed to add memory region");
 
                         // add unused part normally
@@ -148,13 +151,43 @@ where
                 end: end.as_u64(),
                 kind,
             };
-            Self::add_region(region, regions, &mut next_index).unwrap();
+            Self::add_region_around(region, &initrd, regions, &mut next_index).unwrap();
         }
 
         let initialized = &mut regions[..next_index];
         unsafe { MaybeUninit::slice_assume_init_mut(initialized) }
     }
 
+    /// Adds `region`, with the part that overlaps `initrd` as a separate region.
+    fn add_region_around(
+        region: MemoryRegion,
+        initrd: &Range<u64>,
+        regions: &mut [MaybeUninit<MemoryRegion>],
+        next_index: &mut usize,
+    ) -> Result<(), ()> {
+        let start = region.start.max(initrd.start);
+        let end = region.end.min(initrd.end);
+        if start >= end {
+            return Self::add_region(region, regions, next_index);
+        }
+
+        if region.start < start {
+            let before = MemoryRegion { end: start, ..region };
+            Self::add_region(before, regions, next_index)?;
+        }
+        let initrd = MemoryRegion {
+            start,
+            end,
+            kind: MemoryRegionKind::Initrd,
+        };
+        Self::add_region(initrd, regions, next_index)?;
+        if end < region.end {
+            let after = MemoryRegion { start: end, ..region };
+            Self::add_region(after, regions, next_index)?;
+        }
+        Ok(())
+    }
+
     fn add_region(
         region: MemoryRegion,
         regions: &mut [MaybeUninit<MemoryRegion>],
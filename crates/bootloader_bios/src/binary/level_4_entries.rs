@@ -6,7 +6,7 @@ use rand::{
 use rand_chacha::ChaCha20Rng;
 use usize_conversions::IntoUsize;
 use x86_64::{
-    structures::paging::{Page, PageTableIndex, Size4KiB},
+    structures::paging::{Page, PageTable, PageTableIndex, Size4KiB},
     PhysAddr, VirtAddr,
 };
 use xmas_elf::program::ProgramHeader;
@@ -73,6 +73,34 @@ impl UsedLevel4Entries {
         used
     }
 
+    /// Creates an instance with all entries that are present in `page_table` marked as used.
+    ///
+    /// Unlike [`UsedLevel4Entries::new`], this ignores the config and never randomizes. Used by
+    /// the kernel to find room for programs in a new address space.
+    pub fn from_page_table(page_table: &PageTable) -> Self {
+        let mut used = UsedLevel4Entries {
+            entry_state: [false; 512],
+            rng: None,
+        };
+
+        for (index, entry) in page_table.iter().enumerate() {
+            used.entry_state[index] = !entry.is_unused();
+        }
+
+        used
+    }
+
+    /// Returns whether any of the p4 entries in the range `[address..address+size)` is in use.
+    pub fn is_range_used(&self, address: u64, size: u64) -> bool {
+        let start = VirtAddr::new(address);
+        let end_inclusive = (start + size) - 1u64;
+        let start_page = Page::<Size4KiB>::containing_address(start);
+        let end_page_inclusive = Page::<Size4KiB>::containing_address(end_inclusive);
+
+        (u16::from(start_page.p4_index())..=u16::from(end_page_inclusive.p4_index()))
+            .any(|p4_index| self.entry_state[usize::from(p4_index)])
+    }
+
     /// Mark all p4 entries in the range `[address..address+size)` as used.
     ///
     /// `size` can be a `u64` or `usize`.
@@ -113,8 +141,8 @@ impl UsedLevel4Entries {
     /// enabled, this will return a random available entry.
     ///
     /// Since this method marks each returned index as used, it can be used multiple times
-    /// to determine multiple unused virtual memory regions.
-    pub fn get_free_entry(&mut self) -> PageTableIndex {
+    /// to determine multiple unused virtual memory regions. Fails when all entries are in use.
+    pub fn get_free_entry(&mut self) -> Result<PageTableIndex, &'static str> {
         // Create an iterator over all available p4 indices.
         let mut free_entries = self
             .entry_state
@@ -132,35 +160,44 @@ impl UsedLevel4Entries {
             // Choose the first index.
             free_entries.next()
         };
-        let idx = idx_opt.expect("no usable level 4 entry found");
+        let idx = idx_opt.ok_or("no usable level 4 entry found")?;
 
         // Mark the entry as used.
         self.entry_state[idx] = true;
 
-        PageTableIndex::new(idx.try_into().unwrap())
+        Ok(PageTableIndex::new(idx.try_into().unwrap()))
     }
 
     /// Returns a virtual address in an unused level 4 entry and marks it as used.
     ///
     /// This functions call [`get_free_entry`] internally, so all of its docs applies here
-    /// too.
-    pub fn get_free_address(&mut self, size: u64, alignment: u64) -> VirtAddr {
-        assert!(alignment.is_power_of_two());
+    /// too. Also fails if `alignment` is not a power of two, or, with `CONFIG.aslr`, if `size`
+    /// doesn't fit in a level 4 entry.
+    pub fn get_free_address(
+        &mut self,
+        size: u64,
+        alignment: u64,
+    ) -> Result<VirtAddr, &'static str> {
+        if !alignment.is_power_of_two() {
+            return Err("alignment is not a power of two");
+        }
 
         let base =
-            Page::from_page_table_indices_1gib(self.get_free_entry(), PageTableIndex::new(0))
+            Page::from_page_table_indices_1gib(self.get_free_entry()?, PageTableIndex::new(0))
                 .start_address();
 
         let offset = if let Some(rng) = self.rng.as_mut() {
             // Choose a random offset.
             const LEVEL_4_SIZE: u64 = 4096 * 512 * 512 * 512;
-            let end = LEVEL_4_SIZE - size;
+            let end = LEVEL_4_SIZE
+                .checked_sub(size)
+                .ok_or("size doesn't fit in a level 4 entry")?;
             let uniform_range = Uniform::from(0..end / alignment);
             uniform_range.sample(rng) * alignment
         } else {
             0
         };
 
-        base + offset
+        Ok(base + offset)
     }
 }
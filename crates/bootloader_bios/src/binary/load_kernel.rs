@@ -1,4 +1,4 @@
-use core::mem::align_of;
+use core::mem::{align_of, size_of};
 
 use crate::{
     binary::{level_4_entries::UsedLevel4Entries, PAGE_SIZE},
@@ -22,14 +22,52 @@ use xmas_elf::{
 /// Used by [`Inner::make_mut`] and [`Inner::clean_copied_flag`].
 const COPIED: Flags = Flags::BIT_9;
 
+/// End of the lower half of the virtual address space, user programs must stay below it.
+const LOWER_HALF_END: u64 = 0x0000_8000_0000_0000;
+
+/// Controls how [`load_elf`] maps an ELF file.
+#[derive(Debug, Clone, Copy)]
+pub struct ElfLoadConfig {
+    /// Virtual address at which all physical memory is mapped, zero when it is identity mapped
+    /// (as is the case in the bootloader).
+    pub physical_memory_offset: VirtAddr,
+    /// Load the file as an unprivileged program: segments are copied into fresh frames (the file
+    /// doesn't have to be page aligned or physically contiguous), mapped `USER_ACCESSIBLE`, and
+    /// must not overlap level 4 entries that are already in use.
+    pub user: bool,
+}
+
+impl ElfLoadConfig {
+    /// The configuration used for loading the kernel.
+    pub const KERNEL: ElfLoadConfig = ElfLoadConfig {
+        physical_memory_offset: VirtAddr::zero(),
+        user: false,
+    };
+}
+
+/// Everything known about an ELF file after loading it.
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedElf {
+    pub entry_point: VirtAddr,
+    pub tls_template: Option<TlsTemplate>,
+    /// Offset added to all virtual addresses in the file, zero for non-PIE executables.
+    pub virtual_address_offset: u64,
+    /// Address of the program headers in memory, if they are part of a load segment.
+    pub program_headers: Option<VirtAddr>,
+    pub program_header_size: u16,
+    pub program_header_count: u16,
+}
+
 struct Loader<'a, M, F> {
     elf_file: ElfFile<'a>,
     inner: Inner<'a, M, F>,
 }
 
 struct Inner<'a, M, F> {
+    bytes: &'a [u8],
     kernel_offset: PhysAddr,
     virtual_address_offset: u64,
+    config: ElfLoadConfig,
     page_table: &'a mut M,
     frame_allocator: &'a mut F,
 }
@@ -44,12 +82,19 @@ where
         page_table: &'a mut M,
         frame_allocator: &'a mut F,
         used_entries: &mut UsedLevel4Entries,
+        config: ElfLoadConfig,
     ) -> Result<Self, &'static str> {
         log::info!("Elf file loaded at {:#p}", bytes);
-        let kernel_offset = PhysAddr::new(&bytes[0] as *const u8 as u64);
-        if !kernel_offset.is_aligned(PAGE_SIZE) {
-            return Err("Loaded kernel ELF file is not sufficiently aligned");
-        }
+        let kernel_offset = if config.user {
+            // Segments are copied, the physical location of the file doesn't matter
+            PhysAddr::zero()
+        } else {
+            let kernel_offset = PhysAddr::new(&bytes[0] as *const u8 as u64);
+            if !kernel_offset.is_aligned(PAGE_SIZE) {
+                return Err("Loaded kernel ELF file is not sufficiently aligned");
+            }
+            kernel_offset
+        };
 
         let elf_file = ElfFile::new(bytes)?;
         for program_header in elf_file.program_iter() {
@@ -57,9 +102,14 @@ where
         }
 
         let virtual_address_offset = match elf_file.header.pt2.type_().as_type() {
-            header::Type::None => unimplemented!(),
-            header::Type::Relocatable => unimplemented!(),
-            header::Type::Executable => 0,
+            header::Type::None => return Err("ELF file has no type"),
+            header::Type::Relocatable => return Err("relocatable ELF files are not supported"),
+            header::Type::Executable => {
+                if config.user {
+                    check_user_segments(&elf_file, used_entries)?;
+                }
+                0
+            }
             header::Type::SharedObject => {
                 // Find the highest virtual memory address and the biggest alignment.
                 let load_program_headers = elf_file
@@ -67,15 +117,28 @@ where
                     .filter(|h| matches!(h.get_type(), Ok(Type::Load)));
                 let size = load_program_headers
                     .clone()
-                    .map(|h| h.virtual_addr() + h.mem_size())
+                    .try_fold(0, |size: u64, h| {
+                        Some(size.max(h.virtual_addr().checked_add(h.mem_size())?))
+                    })
+                    .ok_or("segment overflows")?;
+                // An alignment of 0 means the same as 1, no alignment
+                let align = load_program_headers
+                    .map(|h| h.align().max(1))
                     .max()
-                    .unwrap_or(0);
-                let align = load_program_headers.map(|h| h.align()).max().unwrap_or(1);
-
-                used_entries.get_free_address(size, align).as_u64()
+                    .unwrap_or(1);
+
+                let address = used_entries.get_free_address(size, align)?.as_u64();
+                if config.user
+                    && address
+                        .checked_add(size)
+                        .map_or(true, |end| end > LOWER_HALF_END)
+                {
+                    return Err("no free level 4 entry in the lower half");
+                }
+                address
             }
-            header::Type::Core => unimplemented!(),
-            header::Type::ProcessorSpecific(_) => unimplemented!(),
+            header::Type::Core => return Err("core dumps can't be loaded"),
+            header::Type::ProcessorSpecific(_) => return Err("unsupported ELF type"),
         };
 
         used_entries.mark_segments(elf_file.program_iter(), virtual_address_offset);
@@ -84,8 +147,10 @@ where
         let loader = Loader {
             elf_file,
             inner: Inner {
+                bytes,
                 kernel_offset,
                 virtual_address_offset,
+                config,
                 page_table,
                 frame_allocator,
             },
@@ -127,13 +192,40 @@ where
             }
         }
 
-        self.inner.remove_copied_flags(&self.elf_file).unwrap();
+        self.inner.remove_copied_flags(&self.elf_file)?;
 
         Ok(tls_template)
     }
 
-    fn entry_point(&self) -> VirtAddr {
-        VirtAddr::new(self.elf_file.header.pt2.entry_point() + self.inner.virtual_address_offset)
+    fn entry_point(&self) -> Result<VirtAddr, &'static str> {
+        self.elf_file
+            .header
+            .pt2
+            .entry_point()
+            .checked_add(self.inner.virtual_address_offset)
+            .and_then(|address| VirtAddr::try_new(address).ok())
+            .ok_or("entry point is not a valid address")
+    }
+
+    /// Finds the virtual address of the program headers, either through a `PT_PHDR` segment or
+    /// the load segment containing them.
+    fn program_headers(&self) -> Option<VirtAddr> {
+        let offset = self.elf_file.header.pt2.ph_offset();
+        let address = self
+            .elf_file
+            .program_iter()
+            .find_map(|h| match h.get_type() {
+                Ok(Type::Phdr) => Some(h.virtual_addr()),
+                Ok(Type::Load)
+                    if (h.offset()..h.offset().saturating_add(h.file_size())).contains(&offset) =>
+                {
+                    h.virtual_addr().checked_add(offset - h.offset())
+                }
+                _ => None,
+            })?;
+
+        let address = address.checked_add(self.inner.virtual_address_offset)?;
+        VirtAddr::try_new(address).ok()
     }
 }
 
@@ -145,6 +237,10 @@ where
     fn handle_load_segment(&mut self, segment: ProgramHeader) -> Result<(), &'static str> {
         log::info!("Handling Segment: {:x?}", segment);
 
+        if self.config.user {
+            return self.copy_load_segment(segment);
+        }
+
         let phys_start_addr = self.kernel_offset + segment.offset();
         let start_frame: PhysFrame = PhysFrame::containing_address(phys_start_addr);
         let end_frame: PhysFrame =
@@ -183,6 +279,96 @@ where
         Ok(())
     }
 
+    /// Maps a load segment to fresh frames and copies its contents over, used for
+    /// [`ElfLoadConfig::user`]. Takes care of `.bss` too, as every frame is zeroed first.
+    fn copy_load_segment(&mut self, segment: ProgramHeader) -> Result<(), &'static str> {
+        if segment.mem_size() == 0 {
+            return Ok(());
+        }
+
+        let file_start = segment.offset() as usize;
+        let file_end = file_start
+            .checked_add(segment.file_size() as usize)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or("segment is outside of the file")?;
+        let data = &self.bytes[file_start..file_end];
+
+        let virt_start_addr = VirtAddr::new(segment.virtual_addr()) + self.virtual_address_offset;
+        let virt_end_addr = virt_start_addr + segment.mem_size();
+        let start_page: Page = Page::containing_address(virt_start_addr);
+        let end_page: Page = Page::containing_address(virt_end_addr - 1u64);
+
+        // `COPIED` lets relocations write to the frames directly
+        let mut segment_flags = Flags::PRESENT | Flags::USER_ACCESSIBLE | COPIED;
+        if !segment.flags().is_execute() {
+            segment_flags |= Flags::NO_EXECUTE;
+        }
+        if segment.flags().is_write() {
+            segment_flags |= Flags::WRITABLE;
+        }
+
+        for page in Page::range_inclusive(start_page, end_page) {
+            let frame = match self.page_table.translate(page.start_address()) {
+                // Shared with the previous segment, the page gets the permissions of both
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size4KiB(frame),
+                    offset: _,
+                    flags,
+                } => {
+                    let no_execute = flags & segment_flags & Flags::NO_EXECUTE;
+                    let flags = ((flags | segment_flags) - Flags::NO_EXECUTE) | no_execute;
+                    unsafe {
+                        self.page_table
+                            .update_flags(page, flags)
+                            .map_err(|_err| "update_flags failed")?
+                            .ignore();
+                    }
+                    frame
+                }
+                TranslateResult::Mapped { .. } | TranslateResult::InvalidFrameAddress(_) => {
+                    return Err("segment overlaps an existing mapping")
+                }
+                TranslateResult::NotMapped => {
+                    let frame = self
+                        .frame_allocator
+                        .allocate_frame()
+                        .ok_or("out of memory")?;
+                    unsafe {
+                        core::ptr::write_bytes(self.frame_ptr(frame), 0, Size4KiB::SIZE as usize);
+                        self.page_table
+                            .map_to(page, frame, segment_flags, self.frame_allocator)
+                            .map_err(|_err| "map_to failed")?
+                            .ignore();
+                    }
+                    frame
+                }
+            };
+
+            // Copy the part of the file that ends up in this page
+            let page_start = page.start_address().max(virt_start_addr);
+            let page_end = (page.start_address() + Size4KiB::SIZE).min(virt_end_addr);
+            let data_start = (page_start - virt_start_addr) as usize;
+            let data_end = ((page_end - virt_start_addr) as usize).min(data.len());
+            if data_start < data_end {
+                let offset_in_page = (page_start - page.start_address()) as usize;
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        data[data_start..data_end].as_ptr(),
+                        self.frame_ptr(frame).add(offset_in_page),
+                        data_end - data_start,
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pointer to the start of `frame`, through the physical memory mapping.
+    fn frame_ptr(&self, frame: PhysFrame) -> *mut u8 {
+        (self.config.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+    }
+
     fn handle_bss_section(
         &mut self,
         segment: &ProgramHeader,
@@ -236,8 +422,8 @@ where
             // segments now.
 
             let last_page = Page::containing_address(virt_start_addr + file_size - 1u64);
-            let new_frame = unsafe { self.make_mut(last_page) };
-            let new_bytes_ptr = new_frame.start_address().as_u64() as *mut u8;
+            let new_frame = unsafe { self.make_mut(last_page)? };
+            let new_bytes_ptr = self.frame_ptr(new_frame);
             unsafe {
                 core::ptr::write_bytes(
                     new_bytes_ptr.add(data_bytes_before_zero as usize),
@@ -253,10 +439,10 @@ where
         let end_page = Page::containing_address(zero_end);
         for page in Page::range_inclusive(start_page, end_page) {
             // allocate a new unused frame
-            let frame = self.frame_allocator.allocate_frame().unwrap();
+            let frame = self.frame_allocator.allocate_frame().ok_or("out of memory")?;
 
-            // zero frame, utilizing identity-mapping
-            let frame_ptr = frame.start_address().as_u64() as *mut PageArray;
+            // zero frame, utilizing the physical memory mapping
+            let frame_ptr = self.frame_ptr(frame) as *mut PageArray;
             unsafe { frame_ptr.write(ZERO_ARRAY) };
 
             // map frame
@@ -285,60 +471,70 @@ where
     ///
     /// ## Safety
     /// - `page` should be a page mapped by a Load segment.
-    ///  
-    /// ## Panics
-    /// Panics if the page is not mapped in `self.page_table`.
-    unsafe fn make_mut(&mut self, page: Page) -> PhysFrame {
+    ///
+    /// ## Errors
+    /// Fails if the page is not mapped to a 4KiB frame in `self.page_table`.
+    unsafe fn make_mut(&mut self, page: Page) -> Result<PhysFrame, &'static str> {
         let (frame, flags) = match self.page_table.translate(page.start_address()) {
             TranslateResult::Mapped {
-                frame,
+                frame: MappedFrame::Size4KiB(frame),
                 offset: _,
                 flags,
             } => (frame, flags),
-            TranslateResult::NotMapped => panic!("{:?} is not mapped", page),
-            TranslateResult::InvalidFrameAddress(_) => unreachable!(),
-        };
-        let frame = if let MappedFrame::Size4KiB(frame) = frame {
-            frame
-        } else {
             // We only map 4k pages.
-            unreachable!()
+            TranslateResult::Mapped { .. } => return Err("page is not mapped to a 4KiB frame"),
+            TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => {
+                return Err("page is not mapped")
+            }
         };
 
         if flags.contains(COPIED) {
             // The frame was already copied, we are free to modify it.
-            return frame;
+            return Ok(frame);
         }
 
-        // Allocate a new frame and copy the memory, utilizing that both frames are identity mapped.
-        let new_frame = self.frame_allocator.allocate_frame().unwrap();
-        let frame_ptr = frame.start_address().as_u64() as *const u8;
-        let new_frame_ptr = new_frame.start_address().as_u64() as *mut u8;
+        // Allocate a new frame and copy the memory, utilizing the physical memory mapping.
+        let new_frame = self.frame_allocator.allocate_frame().ok_or("out of memory")?;
+        let frame_ptr = self.frame_ptr(frame) as *const u8;
+        let new_frame_ptr = self.frame_ptr(new_frame);
         unsafe {
             core::ptr::copy_nonoverlapping(frame_ptr, new_frame_ptr, Size4KiB::SIZE as usize);
         }
 
         // Replace the underlying frame and update the flags.
-        self.page_table.unmap(page).unwrap().1.ignore();
+        self.page_table
+            .unmap(page)
+            .map_err(|_err| "unmap failed")?
+            .1
+            .ignore();
         let new_flags = flags | COPIED;
         unsafe {
             self.page_table
                 .map_to(page, new_frame, new_flags, self.frame_allocator)
-                .unwrap()
+                .map_err(|_err| "map_to failed")?
                 .ignore();
         }
 
-        new_frame
+        Ok(new_frame)
     }
 
     /// Cleans up the custom flags set by [`Inner::make_mut`].
     fn remove_copied_flags(&mut self, elf_file: &ElfFile) -> Result<(), &'static str> {
         for program_header in elf_file.program_iter() {
             if let Type::Load = program_header.get_type()? {
-                let start = self.virtual_address_offset + program_header.virtual_addr();
-                let end = start + program_header.mem_size();
-                let start = VirtAddr::new(start);
-                let end = VirtAddr::new(end);
+                if program_header.mem_size() == 0 {
+                    continue;
+                }
+
+                let start = self
+                    .virtual_address_offset
+                    .checked_add(program_header.virtual_addr())
+                    .ok_or("segment overflows")?;
+                let end = start
+                    .checked_add(program_header.mem_size())
+                    .ok_or("segment overflows")?;
+                let start = VirtAddr::try_new(start).map_err(|_err| "segment is not canonical")?;
+                let end = VirtAddr::try_new(end).map_err(|_err| "segment is not canonical")?;
                 let start_page = Page::containing_address(start);
                 let end_page = Page::containing_address(end - 1u64);
                 for page in Page::<Size4KiB>::range_inclusive(start_page, end_page) {
@@ -351,7 +547,7 @@ where
                             flags,
                         } => flags,
                         TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => {
-                            unreachable!("has the elf file not been mapped correctly?")
+                            return Err("segment has not been mapped");
                         }
                     };
 
@@ -360,7 +556,7 @@ where
                         unsafe {
                             self.page_table
                                 .update_flags(page, flags & !COPIED)
-                                .unwrap()
+                                .map_err(|_err| "update_flags failed")?
                                 .ignore();
                         }
                     }
@@ -372,7 +568,10 @@ where
 
     fn handle_tls_segment(&mut self, segment: ProgramHeader) -> Result<TlsTemplate, &'static str> {
         Ok(TlsTemplate {
-            start_addr: segment.virtual_addr() + self.virtual_address_offset,
+            start_addr: segment
+                .virtual_addr()
+                .checked_add(self.virtual_address_offset)
+                .ok_or("TLS segment overflows")?,
             mem_size: segment.mem_size(),
             file_size: segment.file_size(),
         })
@@ -387,7 +586,7 @@ where
         let data = if let SegmentData::Dynamic64(data) = data {
             data
         } else {
-            panic!("expected Dynamic64 segment")
+            return Err("expected Dynamic64 segment");
         };
 
         // Find the `Rela`, `RelaSize` and `RelaEnt` entries.
@@ -434,6 +633,9 @@ where
         };
         let total_size = rela_size.ok_or("RelaSize entry is missing")?;
         let entry_size = rela_ent.ok_or("RelaEnt entry is missing")?;
+        if entry_size != size_of::<Rela<u64>>() as u64 {
+            return Err("RelaEnt doesn't match the size of a Rela entry");
+        }
 
         // Apply the mappings.
         let entries = (total_size / entry_size) as usize;
@@ -445,52 +647,53 @@ where
 
         // Make sure the relocations are inside the elf file.
         let rela_end = rela_start.wrapping_add(entries);
-        assert!(rela_start <= rela_end);
+        if rela_start > rela_end {
+            return Err("the relocation table wraps around");
+        }
         let file_ptr_range = elf_file.input.as_ptr_range();
-        assert!(
-            file_ptr_range.start <= rela_start.cast(),
-            "the relocation table must start in the elf file"
-        );
-        assert!(
-            rela_end.cast() <= file_ptr_range.end,
-            "the relocation table must end in the elf file"
-        );
+        if file_ptr_range.start > rela_start.cast() {
+            return Err("the relocation table must start in the elf file");
+        }
+        if rela_end.cast() > file_ptr_range.end {
+            return Err("the relocation table must end in the elf file");
+        }
 
         let relas = unsafe { core::slice::from_raw_parts(rela_start, entries) };
         for rela in relas {
-            let idx = rela.get_symbol_table_index();
-            assert_eq!(
-                idx, 0,
-                "relocations using the symbol table are not supported"
-            );
+            if rela.get_symbol_table_index() != 0 {
+                return Err("relocations using the symbol table are not supported");
+            }
 
             match rela.get_type() {
                 // R_AMD64_RELATIVE
                 8 => {
                     check_is_in_load(elf_file, rela.get_offset())?;
-                    let addr = self.virtual_address_offset + rela.get_offset();
+                    let addr = self
+                        .virtual_address_offset
+                        .checked_add(rela.get_offset())
+                        .ok_or("relocation overflows")?;
                     let value = self
                         .virtual_address_offset
                         .checked_add(rela.get_addend())
-                        .unwrap();
+                        .ok_or("relocation overflows")?;
 
                     let ptr = addr as *mut u64;
                     if ptr as usize % align_of::<u64>() != 0 {
                         return Err("destination of relocation is not aligned");
                     }
 
-                    let virt_addr = VirtAddr::from_ptr(ptr);
+                    let virt_addr = VirtAddr::try_new(addr)
+                        .map_err(|_err| "destination of relocation is not canonical")?;
                     let page = Page::containing_address(virt_addr);
                     let offset_in_page = virt_addr - page.start_address();
 
-                    let new_frame = unsafe { self.make_mut(page) };
-                    let phys_addr = new_frame.start_address() + offset_in_page;
-                    let addr = phys_addr.as_u64() as *mut u64;
+                    let new_frame = unsafe { self.make_mut(page)? };
+                    let addr = unsafe { self.frame_ptr(new_frame).add(offset_in_page as usize) };
                     unsafe {
-                        addr.write(value);
+                        (addr as *mut u64).write(value);
                     }
                 }
-                ty => unimplemented!("relocation type {:x} not supported", ty),
+                _ => return Err("unsupported relocation type"),
             }
         }
 
@@ -513,6 +716,31 @@ fn check_is_in_load(elf_file: &ElfFile, virt_offset: u64) -> Result<(), &'static
     Err("offset is not in load segment")
 }
 
+/// Check that the load segments of a (non-PIE) user program are in the lower half and don't
+/// touch level 4 entries that are in use already.
+fn check_user_segments(
+    elf_file: &ElfFile,
+    used_entries: &UsedLevel4Entries,
+) -> Result<(), &'static str> {
+    for program_header in elf_file.program_iter() {
+        if let Type::Load = program_header.get_type()? {
+            let start = program_header.virtual_addr();
+            let end = start
+                .checked_add(program_header.mem_size())
+                .ok_or("segment overflows")?;
+            if end > LOWER_HALF_END {
+                return Err("segment is outside of the lower half");
+            }
+            if program_header.mem_size() > 0
+                && used_entries.is_range_used(start, program_header.mem_size())
+            {
+                return Err("segment overlaps memory that is in use");
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Loads the kernel ELF file given in `bytes` in the given `page_table`.
 ///
 /// Returns the kernel entry point address, it's thread local storage template (if any),
@@ -523,8 +751,35 @@ pub fn load_kernel(
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
     used_entries: &mut UsedLevel4Entries,
 ) -> Result<(VirtAddr, Option<TlsTemplate>), &'static str> {
-    let mut loader = Loader::new(bytes, page_table, frame_allocator, used_entries)?;
+    let loaded = load_elf(
+        bytes,
+        page_table,
+        frame_allocator,
+        used_entries,
+        ElfLoadConfig::KERNEL,
+    )?;
+
+    Ok((loaded.entry_point, loaded.tls_template))
+}
+
+/// Loads the ELF file given in `bytes` in the given `page_table`, see [`ElfLoadConfig`] for the
+/// available options. [`load_kernel`] is this function with [`ElfLoadConfig::KERNEL`].
+pub fn load_elf(
+    bytes: &[u8],
+    page_table: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    used_entries: &mut UsedLevel4Entries,
+    config: ElfLoadConfig,
+) -> Result<LoadedElf, &'static str> {
+    let mut loader = Loader::new(bytes, page_table, frame_allocator, used_entries, config)?;
     let tls_template = loader.load_segments()?;
 
-    Ok((loader.entry_point(), tls_template))
+    Ok(LoadedElf {
+        entry_point: loader.entry_point()?,
+        tls_template,
+        virtual_address_offset: loader.inner.virtual_address_offset,
+        program_headers: loader.program_headers(),
+        program_header_size: loader.elf_file.header.pt2.ph_entry_size(),
+        program_header_count: loader.elf_file.header.pt2.ph_count(),
+    })
 }
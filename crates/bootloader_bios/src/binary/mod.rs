@@ -1,6 +1,6 @@
 use crate::{
     binary::legacy_memory_region::{LegacyFrameAllocator, LegacyMemoryRegion},
-    boot_info::{BootInfo, FrameBuffer, FrameBufferInfo, MemoryRegion, TlsTemplate},
+    boot_info::{BootInfo, CommandLine, FrameBuffer, FrameBufferInfo, MemoryRegion, TlsTemplate},
 };
 use core::{alloc::Layout, arch::asm, mem::MaybeUninit, slice};
 use level_4_entries::UsedLevel4Entries;
@@ -16,6 +16,9 @@ use x86_64::{
 
 pub mod bios;
 
+/// Provides the layout of the sector holding the kernel command line.
+pub mod config_sector;
+
 /// Provides a function to gather entropy and build a RNG.
 mod entropy;
 mod gdt;
@@ -49,6 +52,16 @@ pub struct SystemInfo {
     pub framebuffer_info: FrameBufferInfo,
     /// Address of the _Root System Description Pointer_ structure of the ACPI standard.
     pub rsdp_addr: Option<PhysAddr>,
+    /// Start address of the kernel ELF file, which must stay in memory.
+    pub kernel_addr: PhysAddr,
+    /// Size of the kernel ELF file in bytes.
+    pub kernel_len: u64,
+    /// Start address of the initial ramdisk.
+    pub initrd_addr: PhysAddr,
+    /// Size of the initial ramdisk in bytes, 0 if there is none.
+    pub initrd_len: u64,
+    /// Arguments for the kernel, copied into the boot info.
+    pub command_line: &'static str,
 }
 
 /// Loads the kernel ELF executable into memory and switches to it.
@@ -217,7 +230,7 @@ where
         let offset = CONFIG
             .physical_memory_offset
             .map(VirtAddr::new)
-            .unwrap_or_else(|| used_entries.get_free_address(size, alignment));
+            .unwrap_or_else(|| used_entries.get_free_address(size, alignment).unwrap());
 
         for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
             let page = Page::containing_address(offset + frame.start_address().as_u64());
@@ -241,7 +254,7 @@ where
         let index = CONFIG
             .recursive_index
             .map(PageTableIndex::new)
-            .unwrap_or_else(|| used_entries.get_free_entry());
+            .unwrap_or_else(|| used_entries.get_free_entry().unwrap());
 
         let entry = &mut kernel_page_table.level_4_table()[index];
         if !entry.is_unused() {
@@ -307,12 +320,15 @@ where
     log::info!("Allocate bootinfo");
 
     // allocate and map space for the boot info
-    let (boot_info, memory_regions) = {
+    let (boot_info, memory_regions, command_line) = {
         let boot_info_layout = Layout::new::<BootInfo>();
-        let regions = frame_allocator.len() + 1; // one region might be split into used/unused
+        // one region might be split into used/unused, and one of those again around the initrd
+        let regions = frame_allocator.len() + 3;
         let memory_regions_layout = Layout::array::<MemoryRegion>(regions).unwrap();
         let (combined, memory_regions_offset) =
             boot_info_layout.extend(memory_regions_layout).unwrap();
+        let command_line_layout = Layout::array::<u8>(system_info.command_line.len()).unwrap();
+        let (combined, command_line_offset) = combined.extend(command_line_layout).unwrap();
 
         let boot_info_addr = boot_info_location(&mut mappings.used_entries, combined);
         assert!(
@@ -321,6 +337,7 @@ where
         );
 
         let memory_map_regions_addr = boot_info_addr + memory_regions_offset;
+        let command_line_addr = boot_info_addr + command_line_offset;
         let memory_map_regions_end = boot_info_addr + combined.size();
 
         let start_page = Page::containing_address(boot_info_addr);
@@ -353,13 +370,18 @@ where
             unsafe { &mut *boot_info_addr.as_mut_ptr() };
         let memory_regions: &'static mut [MaybeUninit<MemoryRegion>] =
             unsafe { slice::from_raw_parts_mut(memory_map_regions_addr.as_mut_ptr(), regions) };
-        (boot_info, memory_regions)
+        let command_line: &'static mut [u8] = unsafe {
+            slice::from_raw_parts_mut(command_line_addr.as_mut_ptr(), system_info.command_line.len())
+        };
+        command_line.copy_from_slice(system_info.command_line.as_bytes());
+        (boot_info, memory_regions, command_line)
     };
 
     log::info!("Create Memory Map");
 
     // build memory map
-    let memory_regions = frame_allocator.construct_memory_map(memory_regions);
+    let initrd = system_info.initrd_addr.as_u64()..system_info.initrd_addr.as_u64() + system_info.initrd_len;
+    let memory_regions = frame_allocator.construct_memory_map(memory_regions, initrd);
 
     log::info!("Create bootinfo");
 
@@ -382,6 +404,14 @@ where
         recursive_index: mappings.recursive_index.map(Into::into).into(),
         rsdp_addr: system_info.rsdp_addr.map(|addr| addr.as_u64()).into(),
         tls_template: mappings.tls_template.into(),
+        kernel_addr: system_info.kernel_addr.as_u64(),
+        kernel_len: system_info.kernel_len,
+        initrd_addr: system_info.initrd_addr.as_u64(),
+        initrd_len: system_info.initrd_len,
+        command_line: CommandLine {
+            ptr: command_line.as_ptr(),
+            len: command_line.len(),
+        },
     });
 
     boot_info
@@ -459,6 +489,7 @@ fn boot_info_location(used_entries: &mut UsedLevel4Entries, layout: Layout) -> V
                 u64::from_usize(layout.size()),
                 u64::from_usize(layout.align()),
             )
+            .unwrap()
         })
 }
 
@@ -470,7 +501,7 @@ fn frame_buffer_location(
         .framebuffer_address
         .map(VirtAddr::new)
         .unwrap_or_else(|| {
-            used_entries.get_free_address(u64::from_usize(framebuffer_size), Size4KiB::SIZE)
+            used_entries.get_free_address(u64::from_usize(framebuffer_size), Size4KiB::SIZE).unwrap()
         })
 }
 
@@ -478,7 +509,7 @@ fn kernel_stack_start_location(used_entries: &mut UsedLevel4Entries) -> VirtAddr
     CONFIG
         .kernel_stack_address
         .map(VirtAddr::new)
-        .unwrap_or_else(|| used_entries.get_free_address(CONFIG.kernel_stack_size(), 16))
+        .unwrap_or_else(|| used_entries.get_free_address(CONFIG.kernel_stack_size(), 16).unwrap())
 }
 
 fn enable_nxe_bit() {
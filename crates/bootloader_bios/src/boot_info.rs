@@ -59,6 +59,23 @@ pub struct BootInfo {
     pub rsdp_addr: Optional<u64>,
     /// The thread local storage (TLS) template of the kernel executable, if present.
     pub tls_template: Optional<TlsTemplate>,
+    /// The physical address of the kernel ELF file.
+    ///
+    /// The file stays in memory (in a [`MemoryRegionKind::Bootloader`] region), so the kernel
+    /// can read the sections that weren't loaded, like its symbol table.
+    pub kernel_addr: u64,
+    /// The size of the kernel ELF file in bytes.
+    pub kernel_len: u64,
+    /// The physical address of the initial ramdisk appended to the boot image.
+    ///
+    /// Its memory is reported as [`MemoryRegionKind::Initrd`].
+    pub initrd_addr: u64,
+    /// The size of the initial ramdisk in bytes, 0 if the image doesn't have one.
+    pub initrd_len: u64,
+    /// Arguments for the kernel, read from the config sector of the boot image.
+    ///
+    /// Empty if the image doesn't have a valid one.
+    pub command_line: CommandLine,
 }
 
 /// FFI-safe slice of [`MemoryRegion`] structs, semantically equivalent to
@@ -103,6 +120,25 @@ impl From<MemoryRegions> for &'static mut [MemoryRegion] {
     }
 }
 
+/// FFI-safe string, semantically equivalent to `&'static str`.
+///
+/// This type implements the [`Deref`][core::ops::Deref] trait, so it can be used like a `&str`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct CommandLine {
+    pub(crate) ptr: *const u8,
+    pub(crate) len: usize,
+}
+
+impl ops::Deref for CommandLine {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        // The bootloader only passes valid UTF-8
+        unsafe { core::str::from_utf8_unchecked(slice::from_raw_parts(self.ptr, self.len)) }
+    }
+}
+
 /// Represent a physical memory region.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(C)]
@@ -139,6 +175,10 @@ pub enum MemoryRegionKind {
     ///
     /// This memory should _not_ be used by the kernel.
     Bootloader,
+    /// The initial ramdisk, see [`BootInfo::initrd_addr`].
+    ///
+    /// The kernel can use this memory once it is done with the initrd.
+    Initrd,
     /// An unknown memory region reported by the UEFI firmware.
     ///
     /// This should only be used if the UEFI memory type is known as usable.
@@ -5,7 +5,7 @@
 #[cfg(not(target_os = "none"))]
 compile_error!("The bootloader crate must be compiled for the `x86_64-bootloader.json` target");
 
-use bootloader::binary::SystemInfo;
+use bootloader::binary::{config_sector, SystemInfo};
 use bootloader::boot_info::{FrameBufferInfo, PixelFormat};
 
 use core::{
@@ -45,8 +45,24 @@ extern "C" {
     static _kernel_start_addr: usize;
     static _kernel_end_addr: usize;
     static _kernel_size: usize;
+    static _boot_config_start: usize;
 }
 
+// values defined in `stage_2.s`
+extern "C" {
+    static initrd_addr: u32;
+    static initrd_size: u32;
+}
+
+/// Default kernel command line, the sector can be overwritten after building
+/// so it is only ever read through `_boot_config_start`.
+#[used]
+#[link_section = ".boot-config"]
+static CONFIG_SECTOR: [u8; config_sector::SIZE] = config_sector::new(match option_env!("HUGO4OS_CMDLINE") {
+    Some(command_line) => command_line,
+    None => "",
+});
+
 #[no_mangle]
 pub unsafe extern "C" fn stage_4() -> ! {
     // Set stack segment
@@ -59,20 +75,27 @@ pub unsafe extern "C" fn stage_4() -> ! {
     let kernel_size = &_kernel_size as *const _ as u64;
     let memory_map_addr = &_memory_map as *const _ as u64;
     let memory_map_entry_count = (mmap_ent & 0xff) as u64; // Extract lower 8 bits
+    let config_sector = &*(&_boot_config_start as *const _ as *const [u8; config_sector::SIZE]);
 
     bootloader_main(
         PhysAddr::new(kernel_start),
         kernel_size,
+        PhysAddr::new(initrd_addr.into()),
+        initrd_size.into(),
         VirtAddr::new(memory_map_addr),
         memory_map_entry_count,
+        config_sector,
     )
 }
 
 fn bootloader_main(
     kernel_start: PhysAddr,
     kernel_size: u64,
+    initrd_start: PhysAddr,
+    initrd_size: u64,
     memory_map_addr: VirtAddr,
     memory_map_entry_count: u64,
+    config_sector: &'static [u8; config_sector::SIZE],
 ) -> ! {
     use bootloader::binary::{
         bios::memory_descriptor::E820MemoryRegion, legacy_memory_region::LegacyFrameAllocator,
@@ -89,8 +112,9 @@ fn bootloader_main(
         .expect("no physical memory regions found");
 
     let mut frame_allocator = {
-        let kernel_end = PhysFrame::containing_address(kernel_start + kernel_size - 1u64);
-        let next_free = kernel_end + 1;
+        // The initrd is loaded right after the kernel
+        let loaded_end = (kernel_start + kernel_size).max(initrd_start + initrd_size);
+        let next_free = PhysFrame::containing_address(loaded_end - 1u64) + 1;
         LegacyFrameAllocator::new_starting_at(next_free, e820_memory_map.iter().copied())
     };
 
@@ -155,6 +179,12 @@ fn bootloader_main(
         panic!("{}: r: {}, g: {}, b: {}", msg, r, g, b);
     }
 
+    let command_line = config_sector::command_line(config_sector).unwrap_or_else(|| {
+        log::warn!("Invalid config sector, booting without a command line");
+        ""
+    });
+    log::info!("Command line: {:?}", command_line);
+
     let page_tables = create_page_tables(&mut frame_allocator);
 
     let kernel = {
@@ -166,6 +196,11 @@ fn bootloader_main(
         framebuffer_addr,
         framebuffer_info,
         rsdp_addr: detect_rsdp(),
+        kernel_addr: kernel_start,
+        kernel_len: kernel_size,
+        initrd_addr: initrd_start,
+        initrd_len: initrd_size,
+        command_line,
     };
 
     bootloader::binary::load_and_switch_to_kernel(
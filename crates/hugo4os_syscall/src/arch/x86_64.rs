@@ -1,18 +1,44 @@
+//! Syscall convention on x86_64:
+//!
+//! - `rax`: [`SyscallId`](crate::ids::SyscallId), holds the (encoded) result
+//!   afterwards
+//! - `rdi`, `rsi`, `rdx`, `r10`, `r8`, `r9`, `r12`, `r13`, `r14`, `r15`:
+//!   arguments, in that order
+//!
+//! `rcx` and `r11` are overwritten by the `syscall` instruction, so they can't
+//! be used for arguments. Everything the C calling convention considers
+//! caller-saved may be clobbered by the kernel.
+
+/// Run a syscall through the `syscall` instruction.
 #[macro_export] macro_rules! syscall {
+    ($($args:ident),+) => {
+        $crate::__syscall!("syscall", $($args),+)
+    };
+}
+
+/// Run a syscall through `int 0x80`, slower than [`syscall!`] but kept for
+/// compatibility.
+#[macro_export] macro_rules! syscall_compat {
+    ($($args:ident),+) => {
+        $crate::__syscall!("int 0x80", $($args),+)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export] macro_rules! __syscall {
     (
-        $a1:ident
-        $(, $a2:ident
-            $(, $a3:ident
-                $(, $a4:ident
-                    $(, $a5:ident
-                        $(, $a6:ident
-                            $(, $a7:ident
-                                $(, $a8:ident
-                                    $(, $a9:ident
-                                        $(, $a10:ident
-                                            $(, $a11:ident
-                                                $(, $a12:ident)?
-                                            )?
+        $instruction:literal,
+        $id:ident
+        $(, $a1:ident
+            $(, $a2:ident
+                $(, $a3:ident
+                    $(, $a4:ident
+                        $(, $a5:ident
+                            $(, $a6:ident
+                                $(, $a7:ident
+                                    $(, $a8:ident
+                                        $(, $a9:ident
+                                            $(, $a10:ident)?
                                         )?
                                     )?
                                 )?
@@ -24,23 +50,20 @@
         )?
     ) => {
         {
-            let mut __target: u64 = $a1;
-            let __target_ref = &mut __target as *mut u64;
+            let mut __result: u64 = $id;
             core::arch::asm!(
-                "int 0x80",
-                in("eax") __target_ref
-                $(, in("rsi") $a2
-                    $(, in("rdx") $a3
-                        $(, in("rcx") $a4
-                            $(, in("r8") $a5
-                                $(, in("r9") $a6
-                                    $(, in("r10") $a7
-                                        $(, in("r11") $a8
-                                            $(, in("r12") $a9
-                                                $(, in("r13") $a10
-                                                    $(, in("r14") $a11
-                                                        $(, in("r15") $a12)?
-                                                    )?
+                $instruction,
+                inlateout("rax") __result
+                $(, in("rdi") $a1
+                    $(, in("rsi") $a2
+                        $(, in("rdx") $a3
+                            $(, in("r10") $a4
+                                $(, in("r8") $a5
+                                    $(, in("r9") $a6
+                                        $(, in("r12") $a7
+                                            $(, in("r13") $a8
+                                                $(, in("r14") $a9
+                                                    $(, in("r15") $a10)?
                                                 )?
                                             )?
                                         )?
@@ -49,9 +72,10 @@
                             )?
                         )?
                     )?
-                )?
+                )?,
+                clobber_abi("C"),
             );
-            __target
+            __result
         }
     };
-}
\ No newline at end of file
+}
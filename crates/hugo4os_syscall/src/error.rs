@@ -0,0 +1,66 @@
+//! Error encoding shared by the kernel and programs.
+//!
+//! A syscall returns a single `u64`. Values in the top [`SyscallError::MAX_CODE`]
+//! of the range are errors (`-code` as an `i64`, like errno), anything below is
+//! the result. Stream ids, byte counts and pointers never get that large.
+
+/// Reasons a syscall can fail, the discriminant is the (stable) error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    /// The requested [`SyscallId`](crate::ids::SyscallId) does not exist.
+    UnknownSyscall = 1,
+    /// One of the argument registers holds a value the syscall can't use
+    /// (unknown kind, oversized buffer, ...).
+    InvalidArgument = 2,
+    /// The stream id does not refer to an open stream.
+    InvalidStream = 3,
+    /// A buffer argument is null, or not memory the program may access that way.
+    InvalidPointer = 4,
+    /// The hardware (or the kernel) can't do what was asked.
+    Unsupported = 5,
+    /// The program isn't allowed to do this.
+    PermissionDenied = 6,
+    /// The kernel returned an error code this version doesn't know about.
+    Other = 4095,
+}
+
+pub type SyscallResult<T = u64> = Result<T, SyscallError>;
+
+impl SyscallError {
+    /// Highest error code, everything above `u64::MAX - MAX_CODE` is an error.
+    pub const MAX_CODE: u64 = 4095;
+
+    pub fn code(self) -> u64 {
+        self as u64
+    }
+
+    pub fn from_code(code: u64) -> SyscallError {
+        match code {
+            1 => SyscallError::UnknownSyscall,
+            2 => SyscallError::InvalidArgument,
+            3 => SyscallError::InvalidStream,
+            4 => SyscallError::InvalidPointer,
+            5 => SyscallError::Unsupported,
+            6 => SyscallError::PermissionDenied,
+            _ => SyscallError::Other,
+        }
+    }
+
+    /// Turn a result into the value handed back in the return register.
+    pub fn encode(result: SyscallResult) -> u64 {
+        match result {
+            Ok(value) => value,
+            Err(error) => error.code().wrapping_neg(),
+        }
+    }
+
+    /// Inverse of [`SyscallError::encode`], used on the program side.
+    pub fn decode(value: u64) -> SyscallResult {
+        if value > u64::MAX - SyscallError::MAX_CODE {
+            Err(SyscallError::from_code(value.wrapping_neg()))
+        } else {
+            Ok(value)
+        }
+    }
+}
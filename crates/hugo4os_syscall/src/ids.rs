@@ -1,8 +1,45 @@
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u64)]
 pub enum SyscallId {
     StreamCreate = 0,
     StreamWrite,
     StreamRead,
     StreamFlush,
-}
\ No newline at end of file
+    StreamClose,
+    ProcessExit,
+    ProcessPollSignal,
+    ProcessRefuseClose,
+    ProcessSetCloseHandler,
+    SystemShutdown,
+    SystemReboot,
+    TimeMonotonic,
+    TimeWallClock,
+}
+
+impl SyscallId {
+    /// Amount of syscalls, used by the kernel to size its dispatch table.
+    pub const COUNT: usize = 13;
+}
+
+impl TryFrom<u64> for SyscallId {
+    type Error = u64;
+
+    fn try_from(id: u64) -> Result<SyscallId, u64> {
+        match id {
+            0 => Ok(SyscallId::StreamCreate),
+            1 => Ok(SyscallId::StreamWrite),
+            2 => Ok(SyscallId::StreamRead),
+            3 => Ok(SyscallId::StreamFlush),
+            4 => Ok(SyscallId::StreamClose),
+            5 => Ok(SyscallId::ProcessExit),
+            6 => Ok(SyscallId::ProcessPollSignal),
+            7 => Ok(SyscallId::ProcessRefuseClose),
+            8 => Ok(SyscallId::ProcessSetCloseHandler),
+            9 => Ok(SyscallId::SystemShutdown),
+            10 => Ok(SyscallId::SystemReboot),
+            11 => Ok(SyscallId::TimeMonotonic),
+            12 => Ok(SyscallId::TimeWallClock),
+            _ => Err(id),
+        }
+    }
+}
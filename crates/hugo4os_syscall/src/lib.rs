@@ -3,6 +3,10 @@
 extern crate alloc;
 
 pub mod stream;
+pub mod process;
+pub mod system;
+pub mod time;
+pub mod error;
 pub mod arch;
 pub mod ids;
 pub mod raw;
\ No newline at end of file
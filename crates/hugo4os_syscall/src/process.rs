@@ -0,0 +1,46 @@
+use crate::{raw, error::SyscallResult};
+
+/// Messages the kernel sends to a process, see [`poll_signal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum ProcessSignal {
+    CloseRequested = 1, // Exit soon, or call `refuse_close`
+}
+
+impl TryFrom<u64> for ProcessSignal {
+    type Error = u64;
+
+    fn try_from(signal: u64) -> Result<ProcessSignal, u64> {
+        match signal {
+            1 => Ok(ProcessSignal::CloseRequested),
+            _ => Err(signal),
+        }
+    }
+}
+
+/// Stop the current process, its resources are freed by the kernel.
+pub fn exit(code: i64) -> ! {
+    unsafe { raw::process_exit(code as u64).ok() };
+    unreachable!("The kernel returned from ProcessExit");
+}
+
+/// Check whether the kernel is waiting for a response.
+pub fn poll_signal() -> SyscallResult<Option<ProcessSignal>> {
+    let signal = unsafe { raw::process_poll_signal()? };
+    Ok(ProcessSignal::try_from(signal).ok())
+}
+
+/// Keep running after [`ProcessSignal::CloseRequested`].
+pub fn refuse_close() -> SyscallResult<()> {
+    unsafe { raw::process_refuse_close()? };
+    Ok(())
+}
+
+/// Register the function that runs when the process is about to be killed,
+/// it interrupts whatever the process was doing and has to [`exit`] when done.
+/// Without one the process can only be killed without warning.
+pub fn set_close_handler(handler: Option<extern "C" fn() -> !>) -> SyscallResult<()> {
+    let address = handler.map_or(0, |handler| handler as u64);
+    unsafe { raw::process_set_close_handler(address)? };
+    Ok(())
+}
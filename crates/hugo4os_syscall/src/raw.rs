@@ -1,16 +1,66 @@
-use crate::{syscall, ids::SyscallId};
+use crate::{syscall, ids::SyscallId, error::{SyscallError, SyscallResult}};
 
-pub unsafe fn stream_create() -> u64 {
+pub unsafe fn stream_create(kind: u64) -> SyscallResult {
     let id = SyscallId::StreamCreate as u64;
-    syscall!(id)
+    SyscallError::decode(syscall!(id, kind))
 }
 
-pub unsafe fn stream_write(stream_id: u64, buffer: u64, len: u64) -> u64 {
+pub unsafe fn stream_write(stream_id: u64, buffer: u64, len: u64) -> SyscallResult {
     let id = SyscallId::StreamWrite as u64;
-    syscall!(id, stream_id, buffer, len)
+    SyscallError::decode(syscall!(id, stream_id, buffer, len))
 }
 
-pub unsafe fn stream_read(stream_id: u64, buffer_ptr: u64, count: u64) -> u64 {
+pub unsafe fn stream_read(stream_id: u64, buffer_ptr: u64, count: u64) -> SyscallResult {
     let id = SyscallId::StreamRead as u64;
-    syscall!(id, stream_id, buffer_ptr, count)
+    SyscallError::decode(syscall!(id, stream_id, buffer_ptr, count))
+}
+
+pub unsafe fn stream_flush(stream_id: u64) -> SyscallResult {
+    let id = SyscallId::StreamFlush as u64;
+    SyscallError::decode(syscall!(id, stream_id))
+}
+
+pub unsafe fn stream_close(stream_id: u64) -> SyscallResult {
+    let id = SyscallId::StreamClose as u64;
+    SyscallError::decode(syscall!(id, stream_id))
+}
+
+pub unsafe fn process_exit(code: u64) -> SyscallResult {
+    let id = SyscallId::ProcessExit as u64;
+    SyscallError::decode(syscall!(id, code))
+}
+
+pub unsafe fn process_poll_signal() -> SyscallResult {
+    let id = SyscallId::ProcessPollSignal as u64;
+    SyscallError::decode(syscall!(id))
+}
+
+pub unsafe fn process_refuse_close() -> SyscallResult {
+    let id = SyscallId::ProcessRefuseClose as u64;
+    SyscallError::decode(syscall!(id))
+}
+
+pub unsafe fn process_set_close_handler(handler: u64) -> SyscallResult {
+    let id = SyscallId::ProcessSetCloseHandler as u64;
+    SyscallError::decode(syscall!(id, handler))
+}
+
+pub unsafe fn system_shutdown() -> SyscallResult {
+    let id = SyscallId::SystemShutdown as u64;
+    SyscallError::decode(syscall!(id))
+}
+
+pub unsafe fn system_reboot() -> SyscallResult {
+    let id = SyscallId::SystemReboot as u64;
+    SyscallError::decode(syscall!(id))
+}
+
+pub unsafe fn time_monotonic() -> SyscallResult {
+    let id = SyscallId::TimeMonotonic as u64;
+    SyscallError::decode(syscall!(id))
+}
+
+pub unsafe fn time_wall_clock() -> SyscallResult {
+    let id = SyscallId::TimeWallClock as u64;
+    SyscallError::decode(syscall!(id))
 }
\ No newline at end of file
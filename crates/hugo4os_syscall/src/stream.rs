@@ -1,14 +1,40 @@
 
 use alloc::vec::Vec;
 
-use crate::raw;
+use crate::{raw, error::SyscallResult};
 
 pub trait StreamWrite {
-    fn write(&mut self, data: &[u8]);
+    /// Returns the amount of bytes actually written, which may be less than
+    /// `data.len()` when the stream is full.
+    fn write(&mut self, data: &[u8]) -> SyscallResult<usize>;
 }
 
 pub trait StreamRead {
-    fn read(&mut self, count: usize) -> Vec<u8>;
+    /// Reads at most `count` bytes, the returned `Vec` is shorter when less
+    /// data was available.
+    fn read(&mut self, count: usize) -> SyscallResult<Vec<u8>>;
+}
+
+/// What the kernel connects a stream to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum StreamKind {
+    Pipe = 0,   // Bytes written can be read back, by anyone holding the stream
+    Console,    // Written bytes end up on screen
+    Serial,     // Written bytes are sent over the serial port
+}
+
+impl TryFrom<u64> for StreamKind {
+    type Error = u64;
+
+    fn try_from(kind: u64) -> Result<StreamKind, u64> {
+        match kind {
+            0 => Ok(StreamKind::Pipe),
+            1 => Ok(StreamKind::Console),
+            2 => Ok(StreamKind::Serial),
+            _ => Err(kind),
+        }
+    }
 }
 
 pub struct Stream {
@@ -16,29 +42,53 @@ pub struct Stream {
 }
 
 impl Stream {
-    pub fn new() -> Stream {
-        Stream {
-            id: unsafe { raw::stream_create() },
-        }
+    pub fn new() -> SyscallResult<Stream> {
+        Stream::open(StreamKind::Pipe)
+    }
+
+    pub fn open(kind: StreamKind) -> SyscallResult<Stream> {
+        Ok(Stream {
+            id: unsafe { raw::stream_create(kind as u64)? },
+        })
+    }
+
+    pub fn console() -> SyscallResult<Stream> {
+        Stream::open(StreamKind::Console)
+    }
+
+    pub fn serial() -> SyscallResult<Stream> {
+        Stream::open(StreamKind::Serial)
+    }
+
+    pub fn flush(&mut self) -> SyscallResult<()> {
+        unsafe { raw::stream_flush(self.id)? };
+        Ok(())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        // Nothing sensible to do with an error here
+        let _ = unsafe { raw::stream_close(self.id) };
     }
 }
 
 impl StreamWrite for Stream {
-    fn write(&mut self, data: &[u8]) {
-        unsafe {
-            raw::stream_write(self.id, &data[0] as *const u8 as u64, data.len() as u64);
-        }
+    fn write(&mut self, data: &[u8]) -> SyscallResult<usize> {
+        let written = unsafe { raw::stream_write(self.id, data.as_ptr() as u64, data.len() as u64)? };
+        Ok(written as usize)
     }
 }
 
 impl StreamRead for Stream {
-    fn read(&mut self, count: usize) -> Vec<u8> {
+    fn read(&mut self, count: usize) -> SyscallResult<Vec<u8>> {
         let mut buffer: Vec<u8> = Vec::with_capacity(count);
         unsafe {
-            raw::stream_read(self.id, buffer.as_mut_ptr() as u64, count as u64);
+            let read = raw::stream_read(self.id, buffer.as_mut_ptr() as u64, count as u64)?;
+            buffer.set_len(read as usize);
         }
 
-        buffer
+        Ok(buffer)
     }
 }
 
@@ -48,26 +98,27 @@ pub struct BufferedStream<const SIZE: usize> {
 }
 
 impl<const SIZE: usize> BufferedStream<SIZE> {
-    pub fn new() -> BufferedStream<SIZE> {
-        BufferedStream {
-            inner: Stream::new(),
+    pub fn new() -> SyscallResult<BufferedStream<SIZE>> {
+        Ok(BufferedStream {
+            inner: Stream::new()?,
             buffer: [0; SIZE],
-        }
+        })
     }
 
-    pub fn flush(&mut self) {
-        self.inner.write(&self.buffer)
+    pub fn flush(&mut self) -> SyscallResult<()> {
+        self.inner.write(&self.buffer)?;
+        self.inner.flush()
     }
 }
 
 impl<const SIZE: usize> StreamRead for BufferedStream<SIZE> {
-    fn read(&mut self, count: usize) -> Vec<u8> {
+    fn read(&mut self, count: usize) -> SyscallResult<Vec<u8>> {
         self.inner.read(count)
     }
 }
 
 impl<const SIZE: usize> StreamWrite for BufferedStream<SIZE> {
-    fn write(&mut self, data: &[u8]) {
+    fn write(&mut self, data: &[u8]) -> SyscallResult<usize> {
         self.inner.write(data)
     }
 }
\ No newline at end of file
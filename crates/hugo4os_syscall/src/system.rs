@@ -0,0 +1,20 @@
+use crate::{raw, error::SyscallError};
+
+/// Turn the machine off. Only returns when it can't, with
+/// [`SyscallError::Unsupported`], or when this program isn't allowed to
+/// ([`SyscallError::PermissionDenied`], only the first program started is).
+pub fn shutdown() -> SyscallError {
+    match unsafe { raw::system_shutdown() } {
+        Err(error) => error,
+        Ok(_) => unreachable!("The kernel returned from SystemShutdown"),
+    }
+}
+
+/// Restart the machine. Only returns when this program isn't allowed to,
+/// like [`shutdown`].
+pub fn reboot() -> SyscallError {
+    match unsafe { raw::system_reboot() } {
+        Err(error) => error,
+        Ok(_) => unreachable!("The kernel returned from SystemReboot"),
+    }
+}
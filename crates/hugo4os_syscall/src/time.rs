@@ -0,0 +1,17 @@
+use core::time::Duration;
+
+use crate::{raw, error::SyscallResult};
+
+/// Time since the kernel started, never goes backwards.
+pub fn monotonic() -> SyscallResult<Duration> {
+    let nanos = unsafe { raw::time_monotonic()? };
+    Ok(Duration::from_nanos(nanos))
+}
+
+/// Time since the Unix epoch (UTC), fails with
+/// [`SyscallError::Unsupported`](crate::error::SyscallError::Unsupported)
+/// when the machine has no clock that knows the date.
+pub fn wall_clock() -> SyscallResult<Duration> {
+    let nanos = unsafe { raw::time_wall_clock()? };
+    Ok(Duration::from_nanos(nanos))
+}
@@ -0,0 +1,261 @@
+//! The local APIC of each CPU and the IO-APICs, which replace the 8259 PICs
+//! when the MADT describes them.
+//!
+//! Both are memory mapped. The local APIC registers are 16 bytes apart, an
+//! IO-APIC only has a register select (0x00) and a data window (0x10).
+
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+
+use hugo4os::kernel::acpi::madt::{Madt, Polarity, TriggerMode};
+use hugo4os::kernel::memory::{MemoryError, MemoryManager};
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+
+use crate::{interrupts::PIT_FREQUENCY, X86_64};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const END_OF_INTERRUPT: usize = 0xb0;
+const SPURIOUS_VECTOR: usize = 0xf0;
+const INTERRUPT_COMMAND_LOW: usize = 0x300;
+const INTERRUPT_COMMAND_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const LVT_LINT0: usize = 0x350;
+const LVT_LINT1: usize = 0x360;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3e0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+const ACTIVE_LOW: u32 = 1 << 13;
+const LEVEL_TRIGGERED: u32 = 1 << 15;
+const MASKED: u32 = 1 << 16;
+
+// IO-APIC registers
+const IO_APIC_VERSION: u32 = 0x01;
+const IO_APIC_REDIRECTION: u32 = 0x10;
+
+/// Whether the CPU has a local APIC at all.
+pub fn is_supported() -> bool {
+    unsafe { __cpuid(1).edx & (1 << 9) != 0 }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    base: usize,
+}
+
+impl LocalApic {
+    /// Map and enable the local APIC of this CPU, with its timer masked.
+    /// Interrupts it drops are delivered as `spurious_vector`.
+    pub fn init(madt: &Madt, spurious_vector: u8) -> Result<LocalApic, MemoryError> {
+        let base = X86_64::map_physical(madt.local_apic_address as usize, 0x400)?;
+        let apic = LocalApic { base };
+
+        unsafe {
+            let mut apic_base = Msr::new(IA32_APIC_BASE);
+            let value = apic_base.read();
+            apic_base.write(value | APIC_GLOBAL_ENABLE);
+
+            apic.write(TASK_PRIORITY, 0);
+            apic.write(LVT_TIMER, MASKED);
+            apic.write(LVT_LINT0, MASKED);
+            apic.write(LVT_LINT1, MASKED);
+
+            // Connect LINT0/1 to NMI when the firmware says they are
+            let id = apic.id();
+            for nmi in &madt.local_apic_nmis {
+                let this_cpu = match nmi.processor {
+                    Some(uid) => madt.processors.iter().any(|processor| processor.uid == uid && processor.apic_id == id),
+                    None => true,
+                };
+                if !this_cpu {
+                    continue;
+                }
+
+                let mut entry = DELIVERY_NMI;
+                if nmi.polarity == Polarity::ActiveLow {
+                    entry |= ACTIVE_LOW;
+                }
+                if nmi.trigger == TriggerMode::Level {
+                    entry |= LEVEL_TRIGGERED;
+                }
+                apic.write(if nmi.lint == 0 { LVT_LINT0 } else { LVT_LINT1 }, entry);
+            }
+
+            apic.write(SPURIOUS_VECTOR, SOFTWARE_ENABLE | spurious_vector as u32);
+        }
+
+        Ok(apic)
+    }
+
+    pub fn id(&self) -> u32 {
+        unsafe { self.read(ID) >> 24 }
+    }
+
+    /// Fire `vector` `frequency` times per second. The APIC timer runs at the
+    /// bus frequency, which is measured with the PIT first.
+    pub fn start_timer(&self, vector: u8, frequency: u32) {
+        unsafe {
+            self.write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+            self.write(LVT_TIMER, MASKED);
+
+            const CALIBRATION_MS: u32 = 10;
+            self.write(TIMER_INITIAL_COUNT, u32::MAX);
+            pit_wait(CALIBRATION_MS);
+            let ticks = u32::MAX - self.read(TIMER_CURRENT_COUNT);
+            let ticks_per_second = ticks * (1000 / CALIBRATION_MS);
+
+            self.write(LVT_TIMER, vector as u32 | TIMER_PERIODIC);
+            self.write(TIMER_INITIAL_COUNT, (ticks_per_second / frequency).max(1));
+        }
+    }
+
+    pub fn end_of_interrupt(&self) {
+        unsafe { self.write(END_OF_INTERRUPT, 0) }
+    }
+
+    /// Send interrupt `vector` to the processor with local APIC `apic_id`.
+    pub fn send_ipi(&self, apic_id: u32, vector: u8) {
+        self.send_command(apic_id, vector as u32);
+    }
+
+    /// Start the processor with local APIC `apic_id` (INIT, then two startup
+    /// IPIs), in real mode at the start of physical page `page`.
+    pub fn start_processor(&self, apic_id: u32, page: u8) {
+        self.send_command(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+        unsafe { pit_wait(10) };
+
+        // Only older processors need the second one, one that already
+        // started ignores it
+        for _ in 0..2 {
+            self.send_command(apic_id, DELIVERY_STARTUP | page as u32);
+            unsafe { pit_wait(1) };
+        }
+    }
+
+    /// Send a non-maskable interrupt to the processor with local APIC `apic_id`.
+    pub fn send_nmi(&self, apic_id: u32) {
+        self.send_command(apic_id, DELIVERY_NMI);
+    }
+
+    /// Put the processor with local APIC `apic_id` back into waiting for a
+    /// startup IPI, whatever it was doing.
+    pub fn reset_processor(&self, apic_id: u32) {
+        self.send_command(apic_id, DELIVERY_INIT | LEVEL_ASSERT);
+    }
+
+    fn send_command(&self, apic_id: u32, command: u32) {
+        // The two halves must not be interleaved with another command
+        crate::interrupts::with_disabled(|| unsafe {
+            self.write(INTERRUPT_COMMAND_HIGH, apic_id << 24);
+            self.write(INTERRUPT_COMMAND_LOW, command);
+            while self.read(INTERRUPT_COMMAND_LOW) & DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        });
+    }
+
+    unsafe fn read(&self, register: usize) -> u32 {
+        ptr::read_volatile((self.base + register) as *const u32)
+    }
+
+    unsafe fn write(&self, register: usize, value: u32) {
+        ptr::write_volatile((self.base + register) as *mut u32, value)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    base: usize,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    /// Map the IO-APIC at physical address `address` and mask all of its inputs.
+    pub fn init(address: u32, gsi_base: u32) -> Result<IoApic, MemoryError> {
+        let base = X86_64::map_physical(address as usize, 0x20)?;
+        let mut io_apic = IoApic { base, gsi_base, entries: 0 };
+
+        unsafe {
+            io_apic.entries = ((io_apic.read(IO_APIC_VERSION) >> 16) & 0xff) + 1;
+            for entry in 0..io_apic.entries {
+                io_apic.write(IO_APIC_REDIRECTION + entry * 2, MASKED);
+            }
+        }
+
+        Ok(io_apic)
+    }
+
+    /// Whether global system interrupt `gsi` is one of its inputs.
+    pub fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    /// Deliver global system interrupt `gsi` as `vector` to the local APIC
+    /// with id `apic_id`. ISA interrupts default to active high and edge
+    /// triggered.
+    pub fn route(&self, gsi: u32, vector: u8, polarity: Polarity, trigger: TriggerMode, apic_id: u32) {
+        let mut low = vector as u32;
+        if polarity == Polarity::ActiveLow {
+            low |= ACTIVE_LOW;
+        }
+        if trigger == TriggerMode::Level {
+            low |= LEVEL_TRIGGERED;
+        }
+
+        let register = IO_APIC_REDIRECTION + (gsi - self.gsi_base) * 2;
+        unsafe {
+            self.write(register + 1, apic_id << 24);
+            self.write(register, low);
+        }
+    }
+
+    unsafe fn read(&self, register: u32) -> u32 {
+        ptr::write_volatile(self.base as *mut u32, register);
+        ptr::read_volatile((self.base + 0x10) as *const u32)
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        ptr::write_volatile(self.base as *mut u32, register);
+        ptr::write_volatile((self.base + 0x10) as *mut u32, value);
+    }
+}
+
+/// Busy wait `ms` milliseconds (at most 54) with PIT channel 2, which isn't
+/// connected to an interrupt.
+///
+/// Unsafe because only one processor may use it at a time.
+pub unsafe fn pit_wait(ms: u32) {
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel_2 = Port::<u8>::new(0x42);
+    let count = (PIT_FREQUENCY * ms / 1000) as u16;
+
+    // Gate low and speaker off while programming it
+    let value = gate.read() & !0b11;
+    gate.write(value);
+
+    command.write(0b1011_0000); // Channel 2, low then high byte, count down once
+    channel_2.write(count as u8);
+    channel_2.write((count >> 8) as u8);
+
+    gate.write(value | 1);
+    while gate.read() & (1 << 5) == 0 {
+        core::hint::spin_loop();
+    }
+    gate.write(value);
+}
@@ -0,0 +1,98 @@
+# Startup code of the other processors, see `smp.rs`.
+#
+# A startup IPI starts a processor in real mode at the start of a page below
+# 1 MiB (cs = page >> 4, ip = 0). The kernel copies this code there, fills in
+# the parameters at its end and sends the IPI. It goes straight to long mode
+# with the control registers of the first processor and a page table that
+# also maps this page at its own address, then calls the entry point on the
+# stack it was given. Everything is addressed relative to the start, it runs
+# at a different address than it is linked at.
+
+.pushsection .text.ap_trampoline, "ax"
+.code16
+
+.global ap_trampoline_start
+.global ap_trampoline_parameters
+.global ap_trampoline_end
+
+ap_trampoline_start:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+
+    # Turn the offsets in the GDT and far pointers into physical addresses
+    xor eax, eax
+    mov ax, cs
+    shl eax, 4
+    add [gdt_base], eax
+    add [long_mode_offset], eax
+    lgdt [gdt_pointer]
+
+    mov eax, [cr4_value]
+    mov cr4, eax
+    mov eax, [cr3_value]
+    mov cr3, eax
+    mov ecx, 0xc0000080
+    mov eax, [efer_value]
+    xor edx, edx
+    wrmsr
+    mov eax, [cr0_value]
+    mov cr0, eax
+
+    # jmp far dword [ap_long_mode_pointer], the offset doesn't fit 16 bits
+    .byte 0x66, 0xff, 0x2e
+    .word long_mode_offset
+
+.code64
+ap_long_mode:
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov rsp, [rip + ap_stack]
+    mov rdi, [rip + ap_argument]
+    xor ebp, ebp                # Ends backtraces
+    call [rip + ap_entry]
+ap_halt:
+    cli
+    hlt
+    jmp ap_halt
+
+.align 8
+ap_trampoline_parameters:
+ap_cr0:         .quad 0
+ap_cr3:         .quad 0
+ap_cr4:         .quad 0
+ap_efer:        .quad 0
+ap_stack:       .quad 0
+ap_entry:       .quad 0
+ap_argument:    .quad 0
+
+ap_gdt_pointer:
+    .word ap_gdt_end - ap_gdt - 1
+    .long ap_gdt - ap_trampoline_start
+ap_long_mode_pointer:
+    .long ap_long_mode - ap_trampoline_start
+    .word ap_gdt_code - ap_gdt
+
+.align 8
+ap_gdt:
+    .quad 0
+ap_gdt_code:
+    .quad 0x00af9a000000ffff    # 64-bit code
+    .quad 0x00cf92000000ffff    # Data
+ap_gdt_end:
+
+ap_trampoline_end:
+
+# Offsets from the start, the code runs with ds = cs
+gdt_pointer = ap_gdt_pointer - ap_trampoline_start
+gdt_base = gdt_pointer + 2
+long_mode_offset = ap_long_mode_pointer - ap_trampoline_start
+cr0_value = ap_cr0 - ap_trampoline_start
+cr3_value = ap_cr3 - ap_trampoline_start
+cr4_value = ap_cr4 - ap_trampoline_start
+efer_value = ap_efer - ap_trampoline_start
+
+.popsection
@@ -0,0 +1,305 @@
+//! Backtraces for crash reports.
+//!
+//! The kernel is built with frame pointers (see `targets/x86_64-hugo4os.json`),
+//! so every frame starts with the frame pointer of its caller followed by the
+//! return address:
+//!
+//! ```text
+//! frame pointer + 8 -> return address
+//! frame pointer     -> frame pointer of the caller
+//! ```
+//!
+//! Return addresses are looked up in the symbol table of the kernel ELF, which
+//! the bootloader leaves in memory. Nothing here allocates, it has to work
+//! when the heap is what broke.
+
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Once;
+use x86_64::VirtAddr;
+
+use crate::{memory, println};
+
+/// Frames printed at most, a corrupted stack could go on for a long time
+const MAX_FRAMES: usize = 32;
+
+// ELF constants
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const SYMBOL_SIZE: usize = 24;
+
+static SYMBOLS: Once<SymbolTable> = Once::new();
+
+/// Instruction and frame pointer of the last kernel exception, which is what
+/// the panic that follows it should show a backtrace of.
+static EXCEPTION_INSTRUCTION_POINTER: AtomicU64 = AtomicU64::new(0);
+static EXCEPTION_FRAME_POINTER: AtomicU64 = AtomicU64::new(0);
+
+extern "C" {
+    /// Defined by `bootloader::entry_point!`, used to find where the kernel was loaded
+    fn _start();
+}
+
+/// Symbols of the kernel ELF.
+pub(crate) struct SymbolTable {
+    symbols: &'static [u8],
+    names: &'static [u8],
+    load_offset: u64, // Added to every symbol, non-zero when the kernel is position independent
+}
+
+impl SymbolTable {
+    /// From the contents of the `.symtab` and `.strtab` sections.
+    #[cfg(test)]
+    pub(crate) fn new(symbols: &'static [u8], names: &'static [u8], load_offset: u64) -> SymbolTable {
+        SymbolTable { symbols, names, load_offset }
+    }
+
+    fn parse(elf: &'static [u8]) -> Option<SymbolTable> {
+        if elf.get(0..4)? != b"\x7fELF" || *elf.get(4)? != 2 {
+            return None; // Not a 64-bit ELF
+        }
+
+        let entry = read_u64(elf, 0x18)?;
+        let section_headers = read_u64(elf, 0x28)? as usize;
+        let section_header_size = read_u16(elf, 0x3a)? as usize;
+        let section_count = read_u16(elf, 0x3c)? as usize;
+
+        let section = |index: usize| Some(section_headers.checked_add(index.checked_mul(section_header_size)?)?);
+        let contents = |header: usize| {
+            let offset = read_u64(elf, header + 0x18)? as usize;
+            let size = read_u64(elf, header + 0x20)? as usize;
+            elf.get(offset..offset.checked_add(size)?)
+        };
+
+        let symbol_table = (0..section_count)
+            .filter_map(section)
+            .find(|&header| read_u32(elf, header + 4) == Some(SHT_SYMTAB))?;
+        let string_table = section(read_u32(elf, symbol_table + 0x28)? as usize)?;
+
+        Some(SymbolTable {
+            symbols: contents(symbol_table)?,
+            names: contents(string_table)?,
+            load_offset: (_start as usize as u64).wrapping_sub(entry),
+        })
+    }
+
+    /// Name and start of the function containing `address`.
+    pub(crate) fn lookup(&self, address: u64) -> Option<(&'static str, u64)> {
+        let address = address.wrapping_sub(self.load_offset);
+
+        self.symbols.chunks_exact(SYMBOL_SIZE)
+            .filter(|symbol| symbol[4] & 0xf == STT_FUNC)
+            .find_map(|symbol| {
+                let start = read_u64(symbol, 8)?;
+                let size = read_u64(symbol, 16)?;
+                if address < start || address >= start.saturating_add(size.max(1)) {
+                    return None;
+                }
+
+                let name = self.names.get(read_u32(symbol, 0)? as usize..)?;
+                let name = &name[..name.iter().position(|&byte| byte == 0)?];
+                Some((core::str::from_utf8(name).ok()?, start.wrapping_add(self.load_offset)))
+            })
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// Rust symbol name without the (legacy) mangling and hash, other names are
+/// written as they are.
+pub(crate) struct Demangled<'a>(pub(crate) &'a str);
+
+impl Demangled<'_> {
+    /// The `<length><identifier>` parts between `_ZN` and `E`.
+    fn components(&self) -> Option<impl Iterator<Item = &str> + '_> {
+        let mut rest = self.0.strip_prefix("_ZN")?.strip_suffix('E')?;
+
+        // Check everything first, so nothing is written for names that turn out not to be mangled
+        let mut check = rest;
+        while !check.is_empty() {
+            check = split_component(check)?.1;
+        }
+
+        Some(core::iter::from_fn(move || {
+            let (component, next) = split_component(rest)?;
+            rest = next;
+            Some(component)
+        }))
+    }
+}
+
+fn split_component(mangled: &str) -> Option<(&str, &str)> {
+    let digits = mangled.bytes().take_while(u8::is_ascii_digit).count();
+    let length: usize = mangled[..digits].parse().ok()?;
+    let end = digits.checked_add(length)?;
+    Some((mangled.get(digits..end)?, &mangled[end..]))
+}
+
+fn is_hash(component: &str) -> bool {
+    component.len() == 17 && component.starts_with('h') && component[1..].bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let components = match self.components() {
+            Some(components) => components,
+            None => return f.write_str(self.0),
+        };
+
+        for (index, component) in components.filter(|component| !is_hash(component)).enumerate() {
+            if index > 0 {
+                f.write_str("::")?;
+            }
+
+            let mut rest = if component.starts_with("_$") { &component[1..] } else { component };
+            while !rest.is_empty() {
+                if let Some(next) = rest.strip_prefix("..") {
+                    f.write_str("::")?;
+                    rest = next;
+                    continue;
+                }
+
+                let escape = rest.strip_prefix('$').and_then(|escaped| escaped.find('$').map(|end| &escaped[..end]));
+                if let Some(escape) = escape {
+                    let replacement = match escape {
+                        "SP" => "@",
+                        "BP" => "*",
+                        "RF" => "&",
+                        "LT" => "<",
+                        "GT" => ">",
+                        "LP" => "(",
+                        "RP" => ")",
+                        "C" => ",",
+                        "u20" => " ",
+                        "u27" => "'",
+                        "u5b" => "[",
+                        "u5d" => "]",
+                        "u7b" => "{",
+                        "u7d" => "}",
+                        "u7e" => "~",
+                        _ => escape,
+                    };
+                    f.write_str(replacement)?;
+                    rest = &rest[escape.len() + 2..];
+                    continue;
+                }
+
+                let length = rest.chars().next().map_or(1, char::len_utf8);
+                f.write_str(&rest[..length])?;
+                rest = &rest[length..];
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Follows the chain of frame pointers, yielding return addresses.
+struct Frames {
+    frame_pointer: u64,
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let frame_pointer = self.frame_pointer;
+        let readable = |address: u64| VirtAddr::try_new(address).map_or(false, memory::is_readable);
+        if frame_pointer == 0 || frame_pointer % 8 != 0 || !readable(frame_pointer) || !readable(frame_pointer + 8) {
+            return None;
+        }
+
+        let (caller, return_address) = unsafe { (*(frame_pointer as *const u64), *((frame_pointer + 8) as *const u64)) };
+
+        // The stack grows down, a caller below this frame means the chain is broken
+        self.frame_pointer = if caller > frame_pointer { caller } else { 0 };
+        if return_address == 0 { None } else { Some(return_address) }
+    }
+}
+
+/// Called once memory is set up, `elf` is the kernel executable.
+pub fn init(elf: &'static [u8]) {
+    match SymbolTable::parse(elf) {
+        Some(symbols) => {
+            SYMBOLS.call_once(|| symbols);
+        }
+        None => println!("No symbol table in the kernel, backtraces will only show addresses"),
+    }
+}
+
+/// The frame pointer of the calling function.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let frame_pointer: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack, preserves_flags)) };
+    frame_pointer
+}
+
+/// The frames starting at `frame_pointer`, below the function
+/// `instruction_pointer` is in if given. Displays as one frame per line.
+pub struct Backtrace {
+    instruction_pointer: Option<u64>,
+    frame_pointer: u64,
+}
+
+impl Backtrace {
+    pub fn new(instruction_pointer: Option<u64>, frame_pointer: u64) -> Backtrace {
+        Backtrace { instruction_pointer, frame_pointer }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Backtrace:")?;
+
+        if let Some(instruction_pointer) = self.instruction_pointer {
+            write_frame(f, 0, instruction_pointer, instruction_pointer)?;
+        }
+
+        let first = self.instruction_pointer.is_some() as usize;
+        let frames = Frames { frame_pointer: self.frame_pointer };
+        for (index, return_address) in frames.take(MAX_FRAMES).enumerate() {
+            // The call itself comes right before the address it returns to, which
+            // could already be the start of the next function
+            write_frame(f, first + index, return_address, return_address - 1)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Remember the code a kernel exception interrupted, for the panic that
+/// follows. That prints the backtrace, printing here could wait forever on a
+/// serial port the faulting code holds.
+pub fn record_exception(instruction_pointer: u64, frame_pointer: u64) {
+    EXCEPTION_INSTRUCTION_POINTER.store(instruction_pointer, Ordering::Relaxed);
+    EXCEPTION_FRAME_POINTER.store(frame_pointer, Ordering::Relaxed);
+}
+
+/// Backtrace for the panic handler, of the exception that caused the panic or
+/// otherwise starting at the handler's `frame_pointer`.
+pub fn of_panic(frame_pointer: u64) -> Backtrace {
+    match EXCEPTION_INSTRUCTION_POINTER.load(Ordering::Relaxed) {
+        0 => Backtrace::new(None, frame_pointer),
+        instruction_pointer => Backtrace::new(Some(instruction_pointer), EXCEPTION_FRAME_POINTER.load(Ordering::Relaxed)),
+    }
+}
+
+fn write_frame(f: &mut fmt::Formatter<'_>, index: usize, address: u64, lookup: u64) -> fmt::Result {
+    match SYMBOLS.get().and_then(|symbols| symbols.lookup(lookup)) {
+        Some((name, start)) => write!(f, "\n  {:>2}: {:#018x} {}+{:#x}", index, address, Demangled(name), address - start),
+        None => write!(f, "\n  {:>2}: {:#018x} ??", index, address),
+    }
+}
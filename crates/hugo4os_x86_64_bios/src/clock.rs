@@ -0,0 +1,160 @@
+//! The time stamp counter as the fast clock, and the date from the CMOS RTC.
+//!
+//! The TSC frequency isn't reported anywhere reliable, so it is measured
+//! against the HPET when there is one and PIT channel 2 otherwise. Only an
+//! invariant TSC is used, others change speed with the processor's clock.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::ptr;
+
+use hugo4os::kernel::{acpi, memory::MemoryManager, time::{Clock, DateTime}};
+use x86_64::instructions::port::Port;
+
+use crate::{apic, interrupts, X86_64};
+
+const CALIBRATION_MS: u64 = 10;
+
+// HPET registers
+const HPET_CAPABILITIES: usize = 0x00;
+const HPET_CONFIGURATION: usize = 0x10;
+const HPET_COUNTER: usize = 0xf0;
+const HPET_ENABLE: u64 = 1 << 0;
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+// CMOS RTC registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const BINARY: u8 = 1 << 2;
+const HOURS_24: u8 = 1 << 1;
+const PM: u8 = 1 << 7;
+const NMI_DISABLE: u8 = 1 << 7; // In the register select port
+
+// CPUID
+const EXTENDED_FUNCTIONS: u32 = 0x8000_0000;
+const ADVANCED_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const INVARIANT_TSC: u32 = 1 << 8;
+
+impl Clock for X86_64 {
+    fn counter() -> u64 {
+        unsafe { _rdtsc() }
+    }
+
+    fn counter_frequency() -> Option<u64> {
+        if unsafe { __cpuid(1).edx } & (1 << 4) == 0 || !has_invariant_tsc() {
+            return None;
+        }
+
+        let hpet = acpi::get().and_then(|acpi| acpi.hpet);
+        let base = hpet.and_then(|hpet| X86_64::map_physical(hpet.address as usize, 0x100).ok());
+        let ticks = unsafe { base.and_then(|base| measure_with_hpet(base)).unwrap_or_else(|| measure_with_pit()) };
+        Some(ticks * 1000 / CALIBRATION_MS)
+    }
+
+    fn read_wall_clock() -> Option<DateTime> {
+        let fadt = acpi::get().and_then(|acpi| acpi.fadt);
+        if fadt.map_or(false, |fadt| fadt.boot_architecture.no_cmos_rtc) {
+            return None;
+        }
+
+        // The RTC interrupt handler selects registers too
+        let century_register = fadt.and_then(|fadt| fadt.century_register);
+        Some(interrupts::with_disabled(|| unsafe { read_cmos_date(century_register) }))
+    }
+}
+
+fn has_invariant_tsc() -> bool {
+    unsafe {
+        __cpuid(EXTENDED_FUNCTIONS).eax >= ADVANCED_POWER_MANAGEMENT
+            && __cpuid(ADVANCED_POWER_MANAGEMENT).edx & INVARIANT_TSC != 0
+    }
+}
+
+/// TSC ticks during [`CALIBRATION_MS`], counted with the HPET main counter
+/// at `base`. `None` when it reports a period of 0.
+unsafe fn measure_with_hpet(base: usize) -> Option<u64> {
+    let read = |register: usize| ptr::read_volatile((base + register) as *const u64);
+    let write = |register: usize, value: u64| ptr::write_volatile((base + register) as *mut u64, value);
+
+    let period = read(HPET_CAPABILITIES) >> 32; // Femtoseconds per HPET tick
+    if period == 0 {
+        return None;
+    }
+    write(HPET_CONFIGURATION, read(HPET_CONFIGURATION) | HPET_ENABLE);
+    let hpet_ticks = FEMTOSECONDS_PER_SECOND / 1000 * CALIBRATION_MS / period;
+
+    // Only the low half counts on a 32-bit counter, which can wrap
+    let start = read(HPET_COUNTER) as u32;
+    let tsc_start = _rdtsc();
+    while (read(HPET_COUNTER) as u32).wrapping_sub(start) < hpet_ticks as u32 {
+        core::hint::spin_loop();
+    }
+    Some(_rdtsc() - tsc_start)
+}
+
+unsafe fn measure_with_pit() -> u64 {
+    let start = _rdtsc();
+    apic::pit_wait(CALIBRATION_MS as u32);
+    _rdtsc() - start
+}
+
+/// Read the date twice, until nothing changed in between, so an update of
+/// the RTC halfway doesn't mix two seconds.
+unsafe fn read_cmos_date(century_register: Option<u8>) -> DateTime {
+    let read_all = || {
+        while read_cmos(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        let registers = [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(|register| read_cmos(register));
+        (registers, century_register.map(|register| read_cmos(register)))
+    };
+
+    let mut values = read_all();
+    loop {
+        let again = read_all();
+        if again == values {
+            break;
+        }
+        values = again;
+    }
+
+    let (registers, century) = values;
+    decode_date(registers, century, read_cmos(STATUS_B))
+}
+
+/// The date in the RTC registers (seconds, minutes, hours, day, month and
+/// year), in the format `status` (register B) says they are in.
+pub fn decode_date(registers: [u8; 6], century: Option<u8>, status: u8) -> DateTime {
+    let [second, minute, hour, day, month, year] = registers;
+    let decode = |value: u8| if status & BINARY != 0 { value } else { (value & 0x0f) + (value >> 4) * 10 };
+
+    // In 12 hour mode the top bit is PM, and 12 is midnight or noon
+    let mut hours = decode(hour & !PM);
+    if status & HOURS_24 == 0 {
+        hours %= 12;
+        if hour & PM != 0 {
+            hours += 12;
+        }
+    }
+
+    DateTime {
+        year: century.map_or(20, decode) as u16 * 100 + decode(year) as u16,
+        month: decode(month),
+        day: decode(day),
+        hour: hours,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+unsafe fn read_cmos(register: u8) -> u8 {
+    // Selecting a register also sets whether NMIs are enabled
+    Port::<u8>::new(0x70).write(register | NMI_DISABLE);
+    Port::<u8>::new(0x71).read()
+}
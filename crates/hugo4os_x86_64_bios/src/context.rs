@@ -0,0 +1,207 @@
+//! Saving and restoring processes on the timer interrupt.
+//!
+//! [`timer_entry`] pushes every register onto the stack the interrupt arrived
+//! on, forming a [`Context`], and hands its address to the scheduler
+//! ([`kernel::interrupts::timer`]). The scheduler answers with the context to
+//! continue, which can live on the kernel stack of another process:
+//!
+//! ```text
+//! kernel stack top -> ss, rsp, rflags, cs, rip (pushed by the CPU)
+//!                     rax, rbx, ..., r15
+//! context          -> FXSAVE area (x87, MMX and SSE state)
+//! ```
+
+use core::arch::asm;
+use core::mem::size_of;
+
+use hugo4os::kernel::{self, memory::MemoryManager};
+use x86_64::VirtAddr;
+use x86_64::registers::rflags::RFlags;
+
+use crate::{cpu, gdt, X86_64};
+use crate::interrupts::{self, InterruptIndex};
+
+/// Memory written by `fxsave64`, has to be 16-byte aligned.
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+pub struct FxArea([u8; 512]);
+
+impl FxArea {
+    /// State after `fninit`, with all SSE exceptions masked.
+    fn initial() -> FxArea {
+        let mut area = FxArea([0; 512]);
+        area.0[0..2].copy_from_slice(&0x037fu16.to_le_bytes());     // FCW
+        area.0[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());   // MXCSR
+        area
+    }
+}
+
+/// Everything [`timer_entry`] and the CPU push, lowest address first.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Context {
+    pub fx: FxArea,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    // Interrupt stack frame
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Address of [`timer_entry`], for the IDT.
+pub fn timer_entry_addr() -> VirtAddr {
+    VirtAddr::new(timer_entry as u64)
+}
+
+/// Write a context that `iretq`s to ring 3 at `entry` right below
+/// `kernel_stack_top`, where an interrupt from ring 3 would have put it.
+///
+/// Unsafe because the stack must be valid, unused and 16-byte aligned.
+pub unsafe fn initial_context(kernel_stack_top: VirtAddr, entry: VirtAddr, stack: VirtAddr) -> *mut Context {
+    let selectors = gdt::selectors();
+    let context = (kernel_stack_top - size_of::<Context>()).as_mut_ptr::<Context>();
+
+    context.write(Context {
+        fx: FxArea::initial(),
+        r15: 0,
+        r14: 0,
+        r13: 0,
+        r12: 0,
+        r11: 0,
+        r10: 0,
+        r9: 0,
+        r8: 0,
+        rbp: 0,
+        rdi: 0,
+        rsi: 0,
+        rdx: 0,
+        rcx: 0,
+        rbx: 0,
+        rax: 0,
+        rip: entry.as_u64(),
+        cs: selectors.user_code_selector.0 as u64,
+        rflags: RFlags::INTERRUPT_FLAG.bits(),
+        rsp: stack.as_u64(),
+        ss: selectors.user_data_selector.0 as u64,
+    });
+
+    context
+}
+
+/// Continue `context` at `entry` as if it was called, with the red zone and
+/// stack alignment the System V ABI expects. Returns false (and leaves it
+/// alone) when it wasn't interrupted in ring 3.
+///
+/// Unsafe because `context` must be a saved context.
+pub unsafe fn redirect(context: *mut Context, entry: VirtAddr) -> bool {
+    let context = &mut *context;
+    if context.cs & 3 != 3 {
+        return false;
+    }
+
+    context.rip = entry.as_u64();
+    context.rsp = ((context.rsp - 128) & !0xf) - 8; // Skip the red zone, push a fake return address
+    true
+}
+
+/// Target of the timer IRQ. The CPU aligns the stack before pushing its
+/// 5-word frame, so after 15 registers the FXSAVE area is aligned too. The GS
+/// base of the kernel is swapped in when ring 3 was interrupted, and out when
+/// the context it returns to is in ring 3.
+#[naked]
+unsafe extern "C" fn timer_entry() {
+    asm!(
+        "test byte ptr [rsp + 8], 3", // CS, see `cpu.rs`
+        "jz 2f",
+        "swapgs",
+        "2:",
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "sub rsp, 512",
+        "fxsave64 [rsp]",
+        "cld",
+        "mov rdi, rsp",
+        "call {handler}",
+        "mov rsp, rax", // Possibly another process's context
+        "fxrstor64 [rsp]",
+        "add rsp, 512",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "test byte ptr [rsp + 8], 3", // Of the context that continues
+        "jz 3f",
+        "swapgs",
+        "3:",
+        "iretq",
+        handler = sym timer_handler,
+        options(noreturn)
+    );
+}
+
+/// Runs [`kernel::interrupts::timer`] (abstraction) and prepares the switch
+/// it asks for, returns the context [`timer_entry`] should restore.
+extern "C" fn timer_handler(context: *mut Context) -> *mut Context {
+    let switch = kernel::interrupts::timer(context as usize);
+
+    interrupts::end_of_interrupt(InterruptIndex::Timer);
+
+    let switch = match switch {
+        Some(switch) => switch,
+        None => return context,
+    };
+
+    unsafe {
+        if let Some(top) = switch.kernel_stack_top {
+            let top = VirtAddr::new(top as u64);
+            cpu::current().set_kernel_stack(top);
+        }
+
+        if let Some(space) = switch.address_space {
+            X86_64::switch_address_space(space);
+        }
+    }
+
+    switch.context as *mut Context
+}
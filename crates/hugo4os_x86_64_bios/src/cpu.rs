@@ -0,0 +1,99 @@
+//! Data every processor has its own copy of, reached through the GS base.
+//!
+//! In ring 0 the GS base always points at the [`Cpu`] of the processor. Ring
+//! 3 can load any GS it wants, so the entry points that can come from ring 3
+//! and need it (`syscall`, `int 0x80`, the timer and exceptions) swap it in
+//! with `swapgs`, and swap the one of the process back when they return to
+//! ring 3. Processes don't get their own GS base back reliably, they don't
+//! use it.
+//!
+//! The first fields are read by assembly, their offsets must not change:
+//!
+//! ```text
+//! gs:0   address of the Cpu itself
+//! gs:8   top of the stack `syscall` switches to
+//! gs:16  stack pointer of the process during `syscall_entry`, until it is
+//!        pushed (interrupts are off, so no other entry can overwrite it)
+//! ```
+
+use core::arch::asm;
+use core::cell::Cell;
+use core::ptr;
+
+use hugo4os::kernel::cpu::CpuLocal;
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::structures::tss::TaskStateSegment;
+
+#[repr(C)]
+pub struct Cpu {
+    this: *const Cpu,
+    syscall_stack_top: Cell<u64>,
+    caller_rsp: Cell<u64>,
+    pub index: usize,
+    tss: *mut TaskStateSegment,
+    pub local: CpuLocal,
+}
+
+// Only the processor it belongs to uses it
+unsafe impl Sync for Cpu {}
+
+impl Cpu {
+    pub const fn new(index: usize, tss: *mut TaskStateSegment) -> Cpu {
+        Cpu {
+            this: ptr::null(),
+            syscall_stack_top: Cell::new(0),
+            caller_rsp: Cell::new(0),
+            index,
+            tss,
+            local: CpuLocal::new(),
+        }
+    }
+
+    /// Top of the stack the kernel runs on after a switch from ring 3
+    /// (16-byte aligned), shared with the `syscall` entry.
+    pub fn kernel_stack_top(&self) -> VirtAddr {
+        // The TSS is packed
+        unsafe { ptr::addr_of!((*self.tss).privilege_stack_table[0]).read_unaligned() }
+    }
+
+    /// Make interrupts and syscalls from ring 3 arrive on the stack ending at
+    /// `top`, used when switching processes.
+    ///
+    /// Unsafe because `top` must be the end of a valid stack.
+    pub unsafe fn set_kernel_stack(&self, top: VirtAddr) {
+        // The CPU reads this from memory on every switch to ring 0, the GDT
+        // only holds the address of the TSS
+        ptr::addr_of_mut!((*self.tss).privilege_stack_table[0]).write_unaligned(top);
+        self.syscall_stack_top.set(top.as_u64());
+    }
+}
+
+/// Point the GS base of this processor at `cpu`, for the rest of its life.
+///
+/// Unsafe because it must run on the processor `cpu` belongs to, once.
+pub unsafe fn init(cpu: &'static mut Cpu) {
+    cpu.this = cpu;
+    cpu.syscall_stack_top.set(cpu.kernel_stack_top().as_u64());
+    GsBase::write(VirtAddr::from_ptr(cpu as *const Cpu));
+}
+
+/// [`init`] for the processor that booted, number 0, with the TSS
+/// `gdt::init` loaded.
+pub fn init_first(tss: *mut TaskStateSegment) {
+    static mut FIRST: Cpu = Cpu::new(0, ptr::null_mut());
+    unsafe {
+        FIRST = Cpu::new(0, tss);
+        init(&mut FIRST);
+    }
+}
+
+/// The [`Cpu`] of the processor this runs on. Only valid in ring 0 after
+/// [`init`], with the GS base swapped in if it was entered from ring 3.
+pub fn current() -> &'static Cpu {
+    unsafe {
+        let cpu: *const Cpu;
+        asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, readonly, preserves_flags));
+        &*cpu
+    }
+}
@@ -0,0 +1,313 @@
+//! Entry points for every CPU exception. Page faults go on to `interrupts.rs`,
+//! the others all build an [`ExceptionReport`] for
+//! [`kernel::interrupts::exception`].
+//!
+//! Each vector gets a small stub that makes the stack look the same for all
+//! of them, [`exception_common`] saves the rest and calls [`exception_handler`]:
+//!
+//! ```text
+//!            -> ss, rsp, rflags, cs, rip (pushed by the CPU)
+//!               error code (pushed by the CPU, or 0 by the stub)
+//!               vector
+//!               rax, rbx, ..., r15
+//! frame      -> FXSAVE area (x87, MMX and SSE state)
+//! ```
+
+use core::arch::asm;
+use core::fmt;
+
+use hugo4os::kernel::{self, interrupts::{ExceptionAction, ExceptionKind, ExceptionReport}};
+use x86_64::{PrivilegeLevel, VirtAddr};
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+use crate::context::FxArea;
+use crate::{backtrace, gdt, interrupts, println, smp};
+
+/// Everything the stubs, [`exception_common`] and the CPU push, lowest
+/// address first.
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub fx: FxArea,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+
+    // Interrupt stack frame
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Error code of the exceptions caused by loading a segment or gate.
+struct SelectorError(u64);
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "not caused by a segment");
+        }
+
+        let table = match (self.0 >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        };
+        let external = if self.0 & 1 != 0 { ", external event" } else { "" };
+        write!(f, "{} entry {}{}", table, (self.0 >> 3) & 0x1fff, external)
+    }
+}
+
+/// Point every exception in `idt` at its stub.
+pub fn init(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_addr(entry(divide_error_entry));
+        idt.debug.set_handler_addr(entry(debug_entry));
+        idt.non_maskable_interrupt.set_handler_addr(entry(non_maskable_interrupt_entry));
+        idt.breakpoint
+            .set_handler_addr(entry(breakpoint_entry))
+            .set_privilege_level(PrivilegeLevel::Ring3); // Allow `int3` from user mode
+        idt.overflow
+            .set_handler_addr(entry(overflow_entry))
+            .set_privilege_level(PrivilegeLevel::Ring3); // Same for `into`
+        idt.bound_range_exceeded.set_handler_addr(entry(bound_range_exceeded_entry));
+        idt.invalid_opcode.set_handler_addr(entry(invalid_opcode_entry));
+        idt.device_not_available.set_handler_addr(entry(device_not_available_entry));
+        idt.double_fault
+            .set_handler_addr(entry(double_fault_entry))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(entry(invalid_tss_entry));
+        idt.segment_not_present.set_handler_addr(entry(segment_not_present_entry));
+        idt.stack_segment_fault.set_handler_addr(entry(stack_segment_fault_entry));
+        idt.general_protection_fault.set_handler_addr(entry(general_protection_fault_entry));
+        idt.page_fault.set_handler_addr(entry(page_fault_entry));
+        idt.x87_floating_point.set_handler_addr(entry(x87_floating_point_entry));
+        idt.alignment_check.set_handler_addr(entry(alignment_check_entry));
+        idt.machine_check.set_handler_addr(entry(machine_check_entry));
+        idt.simd_floating_point.set_handler_addr(entry(simd_floating_point_entry));
+        idt.virtualization.set_handler_addr(entry(virtualization_entry));
+        idt.vmm_communication_exception.set_handler_addr(entry(vmm_communication_entry));
+        idt.security_exception.set_handler_addr(entry(security_exception_entry));
+    }
+}
+
+fn entry(stub: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(stub as usize as u64)
+}
+
+/// Name and kind of exception `vector`.
+fn describe(vector: u8) -> (&'static str, ExceptionKind) {
+    match vector {
+        0 => ("Divide error", ExceptionKind::Arithmetic),
+        1 => ("Debug", ExceptionKind::Debug),
+        2 => ("Non-maskable interrupt", ExceptionKind::NonMaskable),
+        3 => ("Breakpoint", ExceptionKind::Breakpoint),
+        4 => ("Overflow", ExceptionKind::Arithmetic),
+        5 => ("Bound range exceeded", ExceptionKind::ProtectionFault),
+        6 => ("Invalid opcode", ExceptionKind::InvalidInstruction),
+        7 => ("Device not available", ExceptionKind::InvalidInstruction),
+        8 => ("Double fault", ExceptionKind::DoubleFault),
+        10 => ("Invalid TSS", ExceptionKind::ProtectionFault),
+        11 => ("Segment not present", ExceptionKind::ProtectionFault),
+        12 => ("Stack segment fault", ExceptionKind::StackFault),
+        13 => ("General protection fault", ExceptionKind::ProtectionFault),
+        16 => ("x87 floating point exception", ExceptionKind::Arithmetic),
+        17 => ("Alignment check", ExceptionKind::AlignmentCheck),
+        18 => ("Machine check", ExceptionKind::MachineCheck),
+        19 => ("SIMD floating point exception", ExceptionKind::Arithmetic),
+        20 => ("Virtualization exception", ExceptionKind::Other),
+        29 => ("VMM communication exception", ExceptionKind::Other),
+        30 => ("Security exception", ExceptionKind::Other),
+        _ => ("Reserved exception", ExceptionKind::Other),
+    }
+}
+
+/// Stub for an exception without an error code, pushes a 0 in its place.
+macro_rules! exception_entry {
+    ($name:ident, $vector:literal) => {
+        #[naked]
+        unsafe extern "C" fn $name() {
+            asm!(
+                "push 0",
+                concat!("push ", $vector),
+                "jmp {common}",
+                common = sym exception_common,
+                options(noreturn)
+            );
+        }
+    };
+    ($name:ident, $vector:literal, error_code) => {
+        #[naked]
+        unsafe extern "C" fn $name() {
+            asm!(
+                concat!("push ", $vector),
+                "jmp {common}",
+                common = sym exception_common,
+                options(noreturn)
+            );
+        }
+    };
+}
+
+exception_entry!(divide_error_entry, 0);
+exception_entry!(debug_entry, 1);
+exception_entry!(non_maskable_interrupt_entry, 2);
+exception_entry!(breakpoint_entry, 3);
+exception_entry!(overflow_entry, 4);
+exception_entry!(bound_range_exceeded_entry, 5);
+exception_entry!(invalid_opcode_entry, 6);
+exception_entry!(device_not_available_entry, 7);
+exception_entry!(double_fault_entry, 8, error_code);
+exception_entry!(invalid_tss_entry, 10, error_code);
+exception_entry!(segment_not_present_entry, 11, error_code);
+exception_entry!(stack_segment_fault_entry, 12, error_code);
+exception_entry!(general_protection_fault_entry, 13, error_code);
+exception_entry!(page_fault_entry, 14, error_code);
+exception_entry!(x87_floating_point_entry, 16);
+exception_entry!(alignment_check_entry, 17, error_code);
+exception_entry!(machine_check_entry, 18);
+exception_entry!(simd_floating_point_entry, 19);
+exception_entry!(virtualization_entry, 20);
+exception_entry!(vmm_communication_entry, 29, error_code);
+exception_entry!(security_exception_entry, 30, error_code);
+
+/// Shared by all stubs. The CPU aligns the stack before pushing its frame,
+/// after 7 words of it and the stubs plus 15 registers the FXSAVE area is
+/// aligned too. Swaps GS when ring 3 was interrupted (see `cpu.rs`).
+#[naked]
+unsafe extern "C" fn exception_common() {
+    asm!(
+        "test byte ptr [rsp + 24], 3", // CS, above the vector and error code
+        "jz 2f",
+        "swapgs",
+        "2:",
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "sub rsp, 512",
+        "fxsave64 [rsp]",
+        "cld",
+        "mov rdi, rsp",
+        "call {handler}",
+        "fxrstor64 [rsp]",
+        "add rsp, 512",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "add rsp, 16", // Vector and error code
+        "test byte ptr [rsp + 8], 3",
+        "jz 3f",
+        "swapgs",
+        "3:",
+        "iretq",
+        handler = sym exception_handler,
+        options(noreturn)
+    );
+}
+
+/// Handles a non-maskable interrupt, which can arrive while this processor
+/// holds any lock: it only reports itself when the serial port is free.
+fn asynchronous_exception(name: &str, frame: &ExceptionFrame) {
+    // Another processor panicked or is turning the machine off
+    if smp::is_stopping() {
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    crate::try_print(format_args!("{} at {:#x}\n", name, frame.rip));
+}
+
+/// Builds the report of the exception `frame` belongs to and does what
+/// [`kernel::interrupts::exception`] decides.
+extern "C" fn exception_handler(frame: &mut ExceptionFrame) {
+    let vector = frame.vector as u8;
+    if vector == 14 {
+        return interrupts::page_fault(frame);
+    }
+
+    let (name, kind) = describe(vector);
+    if kind.is_asynchronous() {
+        return asynchronous_exception(name, frame);
+    }
+
+    let has_error_code = matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30);
+    let selector_error = SelectorError(frame.error_code);
+    let decoded_error: Option<&dyn fmt::Display> = match vector {
+        10..=13 => Some(&selector_error),
+        _ => None,
+    };
+
+    let registers = [
+        ("rax", frame.rax), ("rbx", frame.rbx), ("rcx", frame.rcx), ("rdx", frame.rdx),
+        ("rsi", frame.rsi), ("rdi", frame.rdi), ("rbp", frame.rbp), ("r8", frame.r8),
+        ("r9", frame.r9), ("r10", frame.r10), ("r11", frame.r11), ("r12", frame.r12),
+        ("r13", frame.r13), ("r14", frame.r14), ("r15", frame.r15), ("rflags", frame.rflags),
+        ("cs", frame.cs), ("ss", frame.ss), ("cr2", Cr2::read_raw()), ("cr3", Cr3::read_raw().0.start_address().as_u64()),
+    ];
+
+    let report = ExceptionReport {
+        kind,
+        name,
+        vector,
+        error_code: if has_error_code { Some(frame.error_code) } else { None },
+        decoded_error,
+        instruction_pointer: frame.rip as usize,
+        stack_pointer: frame.rsp as usize,
+        user: frame.cs & 3 == 3,
+        registers: &registers,
+    };
+
+    if !report.user && !kind.is_resumable() {
+        backtrace::record_exception(frame.rip, frame.rbp);
+    }
+
+    // Panics when the kernel itself is at fault
+    match kernel::interrupts::exception(&report) {
+        ExceptionAction::Continue => println!("{}", report),
+        ExceptionAction::Killed(process) => {
+            println!("Killed process {}:\n{}", process, report);
+            interrupts::wait_for_switch();
+        }
+    }
+}
@@ -1,3 +1,8 @@
+use core::cell::UnsafeCell;
+
+use alloc::alloc::{alloc_zeroed, handle_alloc_error, Layout};
+use alloc::boxed::Box;
+
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
@@ -7,42 +12,101 @@ use lazy_static::lazy_static;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const KERNEL_STACK_SIZE: usize = 4096 * 5;
+
+#[repr(C, align(16))]
+struct KernelStack([u8; KERNEL_STACK_SIZE]);
+
+/// The kernel stack in a TSS changes with every process switch (see
+/// `Cpu::set_kernel_stack`), while the CPU reads it from memory.
+struct TssCell(UnsafeCell<TaskStateSegment>);
+
+// Only the processor it belongs to changes it
+unsafe impl Sync for TssCell {}
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe {&STACK});
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-
-        tss
+    /// TSS of the processor that booted, the others allocate theirs
+    static ref TSS: TssCell = {
+        static mut STACK: KernelStack = KernelStack([0; KERNEL_STACK_SIZE]);
+        static mut DOUBLE_FAULT_STACK: KernelStack = KernelStack([0; KERNEL_STACK_SIZE]);
+
+        let stack = VirtAddr::from_ptr(unsafe { &STACK }) + KERNEL_STACK_SIZE;
+        let double_fault_stack = VirtAddr::from_ptr(unsafe { &DOUBLE_FAULT_STACK }) + KERNEL_STACK_SIZE;
+        TssCell(UnsafeCell::new(new_tss(stack, double_fault_stack)))
     };
+
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(TSS.0.get());
 }
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
-    };
+fn new_tss(stack_top: VirtAddr, double_fault_stack_top: VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+
+    // Loaded by the CPU when an interrupt arrives while running in ring 3
+    tss.privilege_stack_table[0] = stack_top;
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_top;
+    tss
+}
+
+/// Every processor gets the same layout, only the TSS differs.
+fn new_gdt(tss: *mut TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+
+    // The order of these is dictated by `syscall`/`sysret` (see STAR in `syscall.rs`)
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+
+    // The descriptor only holds the address, the reference doesn't outlive this
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss }));
+    (gdt, Selectors { code_selector, data_selector, user_data_selector, user_code_selector, tss_selector })
+}
+
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
 }
 
-pub fn init() {
-    GDT.0.load();
+/// Load the GDT and TSS of the processor that booted, returns its TSS.
+pub fn init() -> *mut TaskStateSegment {
+    load(&GDT);
+    TSS.0.get()
+}
+
+/// Allocate and load a GDT and TSS for another processor, returns its TSS.
+pub fn init_other() -> *mut TaskStateSegment {
+    let stack = allocate_stack();
+    let double_fault_stack = allocate_stack();
+    let tss = Box::into_raw(Box::new(new_tss(stack, double_fault_stack)));
+    let gdt = Box::leak(Box::new(new_gdt(tss)));
+
+    load(gdt);
+    tss
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    gdt.0.load();
     unsafe {
         DS::set_reg(SegmentSelector(0x00));
         SS::set_reg(SegmentSelector(0x00));
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(gdt.1.code_selector);
+        load_tss(gdt.1.tss_selector);
+    }
+}
+
+/// Top of a new, zeroed [`KERNEL_STACK_SIZE`] stack on the heap.
+pub fn allocate_stack() -> VirtAddr {
+    let layout = Layout::new::<KernelStack>();
+    let stack = unsafe { alloc_zeroed(layout) };
+    if stack.is_null() {
+        handle_alloc_error(layout);
     }
-}
\ No newline at end of file
+    VirtAddr::from_ptr(stack) + KERNEL_STACK_SIZE
+}
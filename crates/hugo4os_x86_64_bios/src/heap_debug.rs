@@ -0,0 +1,116 @@
+//! Corruption checks for the kernel heap, enabled by the `heap-debug` feature.
+//!
+//! Every allocation gets a header and a canary on both sides:
+//!
+//! ```text
+//! padding | Header | front canary | data | back canary
+//! ```
+//!
+//! Freeing checks all of them and fills the data with [`FREED`], so use after
+//! free stands out. Problems are reported on serial, together with the size
+//! class the allocation came from, after which the kernel panics.
+
+use core::{alloc::Layout, mem, ptr};
+
+use hugo4os::constants::BLOCK_SIZES;
+
+use crate::{memory::size_class, println};
+
+const CANARY: u8 = 0xca;
+const CANARY_SIZE: usize = 16;
+
+/// Freed memory is filled with this
+const FREED: u8 = 0xdd;
+
+const ALLOCATED_MAGIC: u64 = 0xa110_ca7e_d0d0_cafe;
+const FREED_MAGIC: u64 = 0xf4ee_d0d0_dead_beef;
+
+/// The magic comes last, allocators keep their own links in the first words
+/// of freed memory.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    size: usize,
+    align: usize,
+    magic: u64,
+}
+
+/// What to ask the allocator for instead of `layout`, and where the data
+/// starts in it.
+pub fn inner_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(mem::align_of::<Header>());
+    let front = (mem::size_of::<Header>() + CANARY_SIZE + align - 1) & !(align - 1);
+    let size = front.checked_add(layout.size())?.checked_add(CANARY_SIZE)?;
+
+    Some((Layout::from_size_align(size, align).ok()?, front))
+}
+
+/// Write the header and canaries around the data of a new allocation,
+/// returns the pointer to hand out.
+///
+/// Unsafe because `inner` must be allocated with [`inner_layout`].
+pub unsafe fn guard(inner: *mut u8, layout: Layout, front: usize) -> *mut u8 {
+    let data = inner.add(front);
+
+    header(data).write(Header {
+        size: layout.size(),
+        align: layout.align(),
+        magic: ALLOCATED_MAGIC,
+    });
+    ptr::write_bytes(data.sub(CANARY_SIZE), CANARY, CANARY_SIZE);
+    ptr::write_bytes(data.add(layout.size()), CANARY, CANARY_SIZE);
+
+    data
+}
+
+/// Check an allocation that is about to be freed and poison it, returns what
+/// to pass on to the allocator.
+///
+/// Unsafe because `data` must come from [`guard`].
+pub unsafe fn check(data: *mut u8, layout: Layout) -> (*mut u8, Layout) {
+    let found = header(data).read();
+    let (inner, front) = inner_layout(layout).expect("Invalid layout");
+
+    let problem = if found.magic == FREED_MAGIC {
+        Some("double free")
+    } else if found.magic != ALLOCATED_MAGIC {
+        Some("free of memory that wasn't allocated, or a corrupted header")
+    } else if found.size != layout.size() || found.align != layout.align() {
+        Some("freed with a different layout than it was allocated with")
+    } else if !is_intact(data.sub(CANARY_SIZE)) {
+        Some("front canary overwritten (underflow)")
+    } else if !is_intact(data.add(layout.size())) {
+        Some("back canary overwritten (overflow)")
+    } else {
+        None
+    };
+
+    if let Some(problem) = problem {
+        report(problem, data, layout, found, inner);
+    }
+
+    ptr::write_bytes(data, FREED, layout.size());
+    (*header(data)).magic = FREED_MAGIC;
+
+    (data.sub(front), inner)
+}
+
+fn header(data: *mut u8) -> *mut Header {
+    unsafe { data.sub(CANARY_SIZE + mem::size_of::<Header>()) as *mut Header }
+}
+
+unsafe fn is_intact(canary: *const u8) -> bool {
+    (0..CANARY_SIZE).all(|offset| *canary.add(offset) == CANARY)
+}
+
+fn report(problem: &str, data: *mut u8, layout: Layout, found: Header, inner: Layout) -> ! {
+    println!("[heap-debug] {} at {:p}", problem, data);
+    println!("[heap-debug]   freed as:     {} bytes, aligned to {}", layout.size(), layout.align());
+    println!("[heap-debug]   allocated as: {} bytes, aligned to {} (magic {:#x})", found.size, found.align, found.magic);
+    match size_class(&inner) {
+        Some(index) => println!("[heap-debug]   size class:   {} bytes", BLOCK_SIZES[index]),
+        None => println!("[heap-debug]   size class:   none, from the fallback allocator"),
+    }
+
+    panic!("Heap corruption: {} at {:p}", problem, data);
+}
@@ -1,46 +1,73 @@
+use core::arch::asm;
+
+use alloc::{boxed::Box, vec::Vec};
+use hugo4os::constants::SCHEDULER_FREQUENCY;
 use hugo4os::kernel::interrupts::Interrupts;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 use x86_64::instructions::port::Port;
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+use x86_64::PrivilegeLevel;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin;
+use spin::{self, Once};
 
-use hugo4os::kernel::{abstractions, self};
+use hugo4os::kernel::{self, acpi::madt::Madt, memory::{Access, MemoryError, PageFault}};
+use hugo4os::task::process_manager::{self, KERNEL_PROCESS_ID};
+use x86_64::instructions::hlt;
+use x86_64::registers::control::Cr2;
 
 pub use x86_64::instructions::interrupts::enable_and_hlt;
 pub use x86_64::instructions::interrupts::without_interrupts as with_disabled;
 pub use x86_64::instructions::interrupts::disable;
 pub use x86_64::instructions::interrupts::enable;
+pub use x86_64::instructions::interrupts::are_enabled;
 
-use crate::{println, X86_64};
+use crate::apic::{self, IoApic, LocalApic};
+use crate::exceptions::ExceptionFrame;
+use crate::{backtrace, memory, println, X86_64};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// Input frequency of the PIT, in Hz
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
 lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-
-        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(super::gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+    /// IDT of the processor that booted, the others allocate theirs
+    static ref IDT: InterruptDescriptorTable = new_idt();
+}
 
-        idt[InterruptIndex::Timer as usize].set_handler_fn(timer_handler);
-        idt[InterruptIndex::Keyboard as usize].set_handler_fn(keyboard_handler);
-        idt[InterruptIndex::RealTimeClock as usize].set_handler_fn(realtime_clock_handler);
-        idt[InterruptIndex::Syscall as usize].set_handler_fn(syscall_handler);
+fn new_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
 
-        idt
-    };
+    super::exceptions::init(&mut idt);
+
+    idt[InterruptIndex::Keyboard as usize].set_handler_fn(keyboard_handler);
+    idt[InterruptIndex::RealTimeClock as usize].set_handler_fn(realtime_clock_handler);
+    idt[InterruptIndex::Wakeup as usize].set_handler_fn(wakeup_handler);
+    idt[InterruptIndex::Spurious as usize].set_handler_fn(spurious_handler);
+    unsafe {
+        idt[InterruptIndex::Timer as usize].set_handler_addr(super::context::timer_entry_addr());
+        idt[InterruptIndex::Syscall as usize]
+            .set_handler_addr(super::syscall::int80_entry_addr())
+            .set_privilege_level(PrivilegeLevel::Ring3); // Allow `int 0x80` from user mode
+    }
+
+    idt
 }
 
 pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// What delivers the device interrupts, see [`init_controller`].
+static CONTROLLER: Once<Controller> = Once::new();
+
+enum Controller {
+    Pic,
+    Apic(LocalApic),
+}
+
 impl Interrupts for X86_64 {
     #[inline]
     fn enable() {
@@ -52,6 +79,11 @@ impl Interrupts for X86_64 {
         disable()
     }
 
+    #[inline]
+    fn are_enabled() -> bool {
+        are_enabled()
+    }
+
     #[inline]
     fn with_disabled(f: impl FnOnce()) {
         with_disabled(f)
@@ -61,6 +93,10 @@ impl Interrupts for X86_64 {
     fn enable_and_halt() {
         enable_and_hlt()
     }
+
+    fn init_controller(madt: Option<&Madt>) {
+        init_controller(madt)
+    }
 }
 
 pub fn init() {
@@ -81,10 +117,91 @@ pub fn init() {
     enable();
 }
 
+/// Route the device interrupts through the local APIC and IO-APICs when the
+/// MADT describes them, and through the 8259 PICs otherwise. Called once
+/// memory is set up, with interrupts disabled.
+fn init_controller(madt: Option<&Madt>) {
+    let madt = madt.filter(|madt| !madt.io_apics.is_empty() && apic::is_supported());
+    let controller = match madt.map(init_apic) {
+        Some(Ok(controller)) => controller,
+        Some(Err(error)) => {
+            println!("Could not set up the APIC, using the PIC instead: {:?}", error);
+            init_pic()
+        }
+        None => init_pic(),
+    };
+    CONTROLLER.call_once(|| controller);
+}
+
+fn init_pic() -> Controller {
+    // Let the PIT drive the scheduler (defaults to ~18.2Hz)
+    let divisor = (PIT_FREQUENCY / SCHEDULER_FREQUENCY) as u16;
+    let mut pit_command = Port::new(0x43);
+    let mut pit_channel_0 = Port::new(0x40);
+
+    unsafe {
+        pit_command.write(0x36u8); // Channel 0, low then high byte, square wave
+        pit_channel_0.write(divisor as u8);
+        pit_channel_0.write((divisor >> 8) as u8);
+    }
+
+    Controller::Pic
+}
+
+fn init_apic(madt: &Madt) -> Result<Controller, MemoryError> {
+    // Everything is mapped before the local APIC is enabled, so the PIC still
+    // works when this fails
+    let io_apics = madt.io_apics.iter()
+        .map(|io_apic| IoApic::init(io_apic.address, io_apic.gsi_base))
+        .collect::<Result<Vec<_>, _>>()?;
+    let local_apic = LocalApic::init(madt, InterruptIndex::Spurious as u8)?;
+
+    // The vectors stay the same as with the PIC, only the timer is replaced
+    // by the one in the local APIC
+    for (irq, index) in [(1, InterruptIndex::Keyboard), (8, InterruptIndex::RealTimeClock)] {
+        let isa = madt.isa_irq(irq);
+        if let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.handles(isa.gsi)) {
+            io_apic.route(isa.gsi, index as u8, isa.polarity, isa.trigger, local_apic.id());
+        }
+    }
+
+    unsafe { PICS.lock().write_masks(0xff, 0xff); }
+    local_apic.start_timer(InterruptIndex::Timer as u8, SCHEDULER_FREQUENCY);
+
+    Ok(Controller::Apic(local_apic))
+}
+
+/// Load a new IDT and enable the local APIC on a processor other than the
+/// first, after [`init_controller`] chose the APIC. Device interrupts stay
+/// with the first processor, this one only gets [`InterruptIndex::Wakeup`].
+pub fn init_other(madt: &Madt) -> Result<(), MemoryError> {
+    let idt: &'static InterruptDescriptorTable = Box::leak(Box::new(new_idt()));
+    idt.load();
+    LocalApic::init(madt, InterruptIndex::Spurious as u8)?;
+    Ok(())
+}
+
+/// The local APIC, `None` when interrupts go through the PIC.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    match CONTROLLER.get() {
+        Some(Controller::Apic(local_apic)) => Some(local_apic),
+        _ => None,
+    }
+}
+
+/// Tell the interrupt controller the handler of `index` is done, so it can
+/// deliver the next one.
+pub fn end_of_interrupt(index: InterruptIndex) {
+    match CONTROLLER.get() {
+        Some(Controller::Apic(local_apic)) => local_apic.end_of_interrupt(),
+        _ => unsafe { PICS.lock().notify_end_of_interrupt(index as u8) },
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,           // Handled
+    Timer = PIC_1_OFFSET,           // Handled (see `context.rs`)
     Keyboard,                       // Handled
     SecondaryPIC,
     SerialPort2,
@@ -107,51 +224,88 @@ pub enum InterruptIndex {
     // Start custom interrupts
 
     Syscall = 0x80,                 // Same address as linux for compatability
+    Wakeup = 0xf0,                  // Sent to a halted processor when it has tasks to run
+    Spurious = 0xff,                // Dropped by the local APIC, needs no end of interrupt
 }
 
 // Exceptions
 
-extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", _stack_frame);
-}
+/// Called by the exception stub (see `exceptions.rs`), which already swapped GS.
+pub fn page_fault(frame: &ExceptionFrame) {
+    let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+    let address = Cr2::read();
+    let user = error_code.contains(PageFaultErrorCode::USER_MODE);
+
+    // User mode can't be holding the page tables, so a process may wait until
+    // whoever holds them is done. Syscalls check their buffers before using
+    // them, a fault in the kernel is never waited for: it may hold the lock
+    // itself.
+    let wait = user && process_manager::current_process_id() != KERNEL_PROCESS_ID;
+    if wait {
+        enable();
+    }
 
-extern "x86-interrupt" fn simd_floating_point_handler(_stack_frame: InterruptStackFrame) {
-    println!("SIMD EXCEPTION: {:#?}", _stack_frame);
-}
+    if memory::handle_page_fault(address, error_code, wait) {
+        return;
+    }
 
-extern "x86-interrupt" fn double_fault_handler(_stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}\n", _stack_frame);
-}
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        Access::Execute
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        Access::Write
+    } else {
+        Access::Read
+    };
 
-extern "x86-interrupt" fn page_fault_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: PageFaultErrorCode,
-) {
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", x86_64::registers::control::Cr2::read());
-    println!("Error Code: {:?}", _error_code);
-    println!("{:#?}", _stack_frame);
-}
+    let fault = PageFault {
+        address: address.as_u64() as usize,
+        instruction_pointer: frame.rip as usize,
+        stack_pointer: frame.rsp as usize,
+        access,
+        present: error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+        user,
+    };
 
-// Interrupt vectors
+    if !user {
+        backtrace::record_exception(frame.rip, frame.rbp);
+    }
 
-extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
-    kernel::interrupts::timer();
+    // Panics when the kernel itself is at fault
+    let process = kernel::interrupts::page_fault(fault);
+    println!("Killed process {}: {}", process, fault);
+    wait_for_switch();
+}
+
+/// After the running process exited in an exception handler: it won't be
+/// scheduled again, wait for the timer to switch away.
+pub fn wait_for_switch() -> ! {
+    enable();
+    loop {
+        hlt();
+    }
+}
 
+/// Reset the processor with an empty IDT: the breakpoint can't be handled,
+/// neither can the double fault that follows, which shuts the processor down.
+pub fn triple_fault() -> ! {
+    disable();
+    let idt = DescriptorTablePointer { limit: 0, base: VirtAddr::zero() };
     unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer as u8);
+        lidt(&idt);
+        asm!("int3", options(noreturn));
     }
 }
 
+// Interrupt vectors
+
+// These don't use per-CPU data, so they don't swap GS (see `cpu.rs`)
+
 extern "x86-interrupt" fn keyboard_handler(_stack_frame: InterruptStackFrame) {
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     kernel::interrupts::keyboard(scancode);
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
-    }
+    end_of_interrupt(InterruptIndex::Keyboard);
 }
 
 extern "x86-interrupt" fn realtime_clock_handler(_stack_frame: InterruptStackFrame) {
@@ -165,91 +319,13 @@ extern "x86-interrupt" fn realtime_clock_handler(_stack_frame: InterruptStackFra
 
         // RTC Won't fire again if you dont read the C register
         let _: u8 = d_port.read();
-        
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::RealTimeClock as u8)
-    }
-}
-
-/// The x86_64 implementation of syscalls
-/// 
-/// Receives `int 0x80` (most probably called from [`hugo4os_syscall::syscall!`]).
-/// It then puts all the parameters (stored in 12 CPU registers) in variables
-/// and executes [`kernel::interrupts::syscall`] (abstraction). It then takes the
-/// return value from that function, and writes it to the pointer specified in
-/// the `eax` register (also used to specify which syscall to run).
-extern "x86-interrupt" fn syscall_handler(_stack_frame: InterruptStackFrame) {
-    let regs = unsafe {
-        let mut r15: u64;
-        let mut r14: u64;
-        let mut r13: u64;
-        let mut r12: u64;
-        let mut r11: u64;
-        let mut r10: u64;
-        let mut r9: u64;
-        let mut r8: u64;
-        let mut eax: u64;
-        let mut rcx: u64;
-        let mut rdx: u64;
-        let mut rsi: u64;
-        core::arch::asm!(
-            "",
-            out("rsi") rsi,
-            out("rdx") rdx,
-            out("rcx") rcx,
-            out("eax") eax, // Specifies which syscall to run, and will be reused as a pointer to return value
-            out("r8") r8,
-            out("r9") r9,
-            out("r10") r10,
-            out("r11") r11,
-            out("r12") r12,
-            out("r13") r13,
-            out("r14") r14,
-            out("r15") r15,
-            options(nomem, nostack)
-        );
-        let eax = eax as *mut u64;
-
-        SyscallRegs { r15, r14, r13, r12, r11, r10, r9, r8, eax, rcx, rdx, rsi }
-    };
+    }
 
-    let result = kernel::interrupts::syscall(regs.into());
-    unsafe { core::ptr::write(regs.eax, result) }
+    end_of_interrupt(InterruptIndex::RealTimeClock);
 }
 
-#[repr(C)]
-#[derive(Debug, Clone, Copy)]
-pub struct SyscallRegs {
-    pub r15: u64,
-    pub r14: u64,
-    pub r13: u64,
-    pub r12: u64,
-    pub r11: u64,
-    pub r10: u64,
-    pub r9: u64,
-    pub r8: u64,
-    pub eax: *mut u64,
-    pub rcx: u64,
-    pub rdx: u64,
-    pub rsi: u64,
-}
-
-impl Into<abstractions::interrupts::InputSyscall> for SyscallRegs {
-    fn into(self) -> abstractions::interrupts::InputSyscall {
-        abstractions::interrupts::InputSyscall {
-            args: [
-                self.rsi,
-                self.rdx,
-                self.rcx,
-                self.r8,
-                self.r9,
-                self.r10,
-                self.r11,
-                self.r12,
-                self.r13,
-                self.r14,
-                self.r15,
-            ],
-            target: self.eax,
-        }
-    }
-}
\ No newline at end of file
+extern "x86-interrupt" fn wakeup_handler(_stack_frame: InterruptStackFrame) {
+    end_of_interrupt(InterruptIndex::Wakeup);
+}
+
+extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {}
@@ -2,52 +2,200 @@
 #![no_main]
 #![feature(const_mut_refs)]
 #![feature(abi_x86_interrupt)]
+#![feature(naked_functions)]
 #![feature(alloc_error_handler)]
 #![feature(custom_test_frameworks)]
-#![test_runner(crate::tests::test_runner)]
+#![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
 
 use core::panic::PanicInfo;
+#[cfg(not(test))] use core::sync::atomic::{AtomicBool, Ordering};
 
-use bootloader::BootInfo;
+use alloc::vec::Vec;
+use bootloader::{BootInfo, boot_info::MemoryRegionKind};
 
-use hugo4os::kernel::architecture::Architecture;
-use memory::FixedSizeBlockAllocator;
+use hugo4os::kernel::{architecture::Architecture, boot, command_line::{self, LogLevel}, lock::{IrqMutex, IrqMutexGuard}, ports::Ports, stream::{self, StreamKind}};
+use memory::SlabAllocator;
 use rendering::FrameBuffer;
-
+use x86_64::VirtAddr;
+use x86_64::instructions::port::Port;
+
+pub mod apic;
+pub mod backtrace;
+pub mod clock;
+pub mod context;
+pub mod cpu;
+pub mod exceptions;
 pub mod gdt;
+#[cfg(feature = "heap-debug")] pub mod heap_debug;
 pub mod memory;
 pub mod rendering;
 pub mod interrupts;
+pub mod smp;
+pub mod syscall;
+pub mod usermode;
 
 #[global_allocator]
-pub static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+pub static ALLOCATOR: Locked<SlabAllocator> = Locked::new(SlabAllocator::new());
 
 bootloader::entry_point!(init);
 
 pub fn init(boot_info: &'static mut BootInfo) -> ! {
-    gdt::init();
+    let boot_info: &'static BootInfo = boot_info;
+    let invalid_options = command_line::init(&boot_info.command_line);
+
+    let tss = gdt::init();
+    cpu::init_first(tss);
+    syscall::init();
     interrupts::init();
     interrupts::disable();
-    memory::init(
-        boot_info.physical_memory_offset.into_option().unwrap(),
-        &boot_info.memory_regions
-    );
+    let physical_memory_offset = boot_info.physical_memory_offset.into_option().unwrap();
+    memory::init(physical_memory_offset, &boot_info.memory_regions);
+
+    // The bootloader leaves the kernel executable in memory, for its symbols
+    let kernel_elf = unsafe {
+        let start = (physical_memory_offset + boot_info.kernel_addr) as *const u8;
+        core::slice::from_raw_parts(start, boot_info.kernel_len as usize)
+    };
+    backtrace::init(kernel_elf);
+
+    for error in invalid_options {
+        println!("Ignoring kernel command line option: {}", error);
+    }
 
-    hugo4os::kernel_main::<X86_64>(FrameBuffer::new(boot_info.framebuffer.as_ref().unwrap()))
+    println_verbose!("{}", memory::allocator_stats());
+
+    stream::register_sink(StreamKind::Serial, serial_sink);
+
+    #[cfg(feature = "bench")]
+    syscall::benchmark();
+
+    #[cfg(test)]
+    test_main();
+
+    let boot_info = handoff(boot_info, physical_memory_offset);
+    if let Some(framebuffer) = boot_info.framebuffer {
+        rendering::PANIC_FRAMEBUFFER.call_once(|| framebuffer);
+    }
+
+    hugo4os::kernel_main(boot_info)
+}
+
+/// Translate what the bootloader passed for the kernel.
+fn handoff(boot_info: &'static BootInfo, physical_memory_offset: u64) -> boot::BootInfo<X86_64> {
+    let mut memory_map: Vec<boot::MemoryRegion> = boot_info.memory_regions.iter()
+        .map(|region| boot::MemoryRegion {
+            start: region.start as usize,
+            end: region.end as usize,
+            kind: match region.kind {
+                MemoryRegionKind::Usable => boot::MemoryRegionKind::Usable,
+                MemoryRegionKind::Bootloader => boot::MemoryRegionKind::Bootloader,
+                MemoryRegionKind::Initrd => boot::MemoryRegionKind::Initrd,
+                _ => boot::MemoryRegionKind::Reserved,
+            },
+        })
+        .collect();
+    memory_map.sort_unstable_by_key(|region| region.start);
+
+    // Its memory isn't usable, so it stays where the bootloader put it
+    let initrd = (boot_info.initrd_len != 0).then(|| unsafe {
+        let start = (physical_memory_offset + boot_info.initrd_addr) as *const u8;
+        core::slice::from_raw_parts(start, boot_info.initrd_len as usize)
+    });
+
+    boot::BootInfo {
+        framebuffer: boot_info.framebuffer.as_ref().map(FrameBuffer::new),
+        memory_map,
+        rsdp_address: boot_info.rsdp_addr.into_option().map(|address| address as usize),
+        command_line: &boot_info.command_line,
+        initrd,
+        tls_template: boot_info.tls_template.into_option().map(|template| boot::TlsTemplate {
+            start: template.start_addr as usize,
+            file_size: template.file_size as usize,
+            memory_size: template.mem_size as usize,
+        }),
+    }
 }
 
 pub struct X86_64;
 impl Architecture for X86_64 {
     type FrameBuffer = FrameBuffer;
+
+    unsafe fn enter_user_mode(entry: usize, stack: usize) -> ! {
+        usermode::enter_user_mode(VirtAddr::new(entry as u64), VirtAddr::new(stack as u64))
+    }
+
+    unsafe fn initial_context(kernel_stack_top: usize, entry: usize, stack: usize) -> usize {
+        let context = context::initial_context(
+            VirtAddr::new(kernel_stack_top as u64),
+            VirtAddr::new(entry as u64),
+            VirtAddr::new(stack as u64),
+        );
+        context as usize
+    }
+
+    unsafe fn redirect_context(context: usize, entry: usize) -> bool {
+        context::redirect(context as *mut context::Context, VirtAddr::new(entry as u64))
+    }
+
+    fn reset() -> ! {
+        interrupts::triple_fault()
+    }
+}
+
+impl Ports for X86_64 {
+    unsafe fn read_port(port: u16, size: u8) -> u32 {
+        match size {
+            1 => Port::<u8>::new(port).read() as u32,
+            2 => Port::<u16>::new(port).read() as u32,
+            _ => Port::<u32>::new(port).read(),
+        }
+    }
+
+    unsafe fn write_port(port: u16, size: u8, value: u32) {
+        match size {
+            1 => Port::<u8>::new(port).write(value as u8),
+            2 => Port::<u16>::new(port).write(value as u16),
+            _ => Port::<u32>::new(port).write(value),
+        }
+    }
 }
 
+/// Set by the first panic, a panic while handling it only prints its message.
+#[cfg(not(test))]
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
 #[panic_handler]
 #[cfg(not(test))]
 fn panic(_info: &PanicInfo) -> ! {
-    println!("{}", _info);
+    use core::fmt::Write;
+    use hugo4os::kernel::{cpu::Cpus, rendering::panic_screen::PanicScreen};
+
+    interrupts::disable();
+    X86_64::stop_others();
+
+    // Whatever held the serial port won't run again
+    #[cfg(feature = "serial")]
+    if let Some(serial) = SERIAL1.as_ref() {
+        unsafe { serial.force_unlock() };
+    }
+
+    if PANICKING.swap(true, Ordering::Relaxed) {
+        println!("Panicked while panicking: {}", _info);
+    } else {
+        let backtrace = backtrace::of_panic(backtrace::frame_pointer());
+
+        // Screen first, printing to serial is what most likely panics again
+        if let Some(framebuffer) = rendering::PANIC_FRAMEBUFFER.get() {
+            let mut screen = unsafe { PanicScreen::take_over(framebuffer) };
+            let _ = write!(screen, "Kernel {}\n\n{}", _info, backtrace);
+        }
+
+        println!("{}", _info);
+        println!("{}", backtrace);
+    }
 
     loop {
         x86_64::instructions::hlt();
@@ -59,7 +207,7 @@ fn panic(_info: &PanicInfo) -> ! {
 fn panic(_info: &PanicInfo) -> ! {
     println!("Failed!");
     println!("Error: {}", _info);
-    tests::exit_qemu(0x10);
+    exit_qemu(0x10);
     loop {
         x86_64::instructions::hlt();
     }
@@ -76,33 +224,60 @@ fn alloc_error(_layout: alloc::alloc::Layout) -> ! {
 #[cfg(feature = "serial")] use uart_16550::SerialPort;
 
 #[cfg(feature = "serial")] lazy_static! {
-    pub static ref SERIAL1: spin::Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    /// The port chosen on the command line, `None` if printing is turned off
+    pub static ref SERIAL1: Option<spin::Mutex<SerialPort>> = command_line::options().serial_port.map(|port| {
+        let mut serial_port = unsafe { SerialPort::new(port) };
         serial_port.init();
         spin::Mutex::new(serial_port)
-    };
+    });
+}
+
+/// Sink for [`StreamKind::Serial`] streams
+fn serial_sink(bytes: &[u8]) {
+    #[cfg(feature = "serial")]
+    interrupts::with_disabled(|| {
+        if let Some(serial) = SERIAL1.as_ref() {
+            let mut serial = serial.lock();
+            for byte in bytes {
+                serial.send(*byte);
+            }
+        }
+    });
+
+    #[cfg(not(feature = "serial"))]
+    let _ = bytes;
 }
 
 #[macro_export]
 macro_rules! println_verbose {
     () => {
-        #[cfg(feature = "verbose")]
-        $crate::println!("\n");
+        if $crate::verbose() {
+            $crate::println!("\n");
+        }
     };
     ($($arg:tt)*) => {
-        #[cfg(feature = "verbose")]
-        $crate::print!("{}:{} [{}] {}\n", file!(), line!(), module_path!(), format_args!($($arg)*));
+        if $crate::verbose() {
+            $crate::print!("{}:{} [{}] {}\n", file!(), line!(), module_path!(), format_args!($($arg)*));
+        }
     };
 }
 
 #[macro_export]
 macro_rules! print_verbose {
     ($($arg:tt)*) => {
-        #[cfg(feature = "verbose")]
-        $crate::print!("{}:{} [{}] {}", file!(), line!(), module_path!(), format_args!($($arg)*));
+        if $crate::verbose() {
+            $crate::print!("{}:{} [{}] {}", file!(), line!(), module_path!(), format_args!($($arg)*));
+        }
     };
 }
 
+/// Whether [`println_verbose`] prints, `log=debug` on the command line or the
+/// `verbose` feature.
+#[doc(hidden)]
+pub fn verbose() -> bool {
+    command_line::options().log_level >= LogLevel::Debug
+}
+
 #[macro_export]
 macro_rules! println_debug {
     () => {
@@ -131,7 +306,7 @@ macro_rules! println {
 
 #[cfg(feature = "serial")]
 #[macro_export] macro_rules! print {
-    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::_print(format_args!($($arg)*)));
 }
 #[cfg(not(feature = "serial"))]
 #[macro_export] macro_rules! print {
@@ -143,15 +318,30 @@ macro_rules! println {
 pub fn _print(args: fmt::Arguments) {
     use fmt::Write;
     x86_64::instructions::interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed!");
+        if let Some(serial) = SERIAL1.as_ref() {
+            serial.lock().write_fmt(args).expect("Printing to serial failed!");
+        }
     })
 }
 
-/// Wrapper to add trait implementation support to spin::Mutex.
-pub struct Locked<A>(spin::Mutex<A>);
+/// Same as [`_print`], but drops the output when the port is in use instead of
+/// waiting. For handlers that may have interrupted whoever holds it.
+#[cfg(feature = "serial")]
+pub fn try_print(args: fmt::Arguments) {
+    use fmt::Write;
+    if let Some(mut serial) = SERIAL1.as_ref().and_then(|serial| serial.try_lock()) {
+        let _ = serial.write_fmt(args);
+    }
+}
+
+#[cfg(not(feature = "serial"))]
+pub fn try_print(_args: core::fmt::Arguments) {}
+
+/// Wrapper to add trait implementation support to IrqMutex.
+pub struct Locked<A>(IrqMutex<A>);
 impl<A> Locked<A> {
-    pub const fn new(inner: A) -> Locked<A> { Locked(spin::Mutex::new(inner)) }
-    pub fn lock(&self) -> spin::MutexGuard<A> { self.0.lock() }
+    pub const fn new(inner: A) -> Locked<A> { Locked(IrqMutex::new(inner)) }
+    pub fn lock(&self) -> IrqMutexGuard<A> { self.0.lock() }
 }
 
 // Tests
@@ -167,8 +357,11 @@ pub fn exit_qemu(exit_code: u32) {
 
 #[cfg(test)]
 pub fn test_runner(tests: &[&dyn Testable]) {
-    println!("Running {} tests", tests.len());
-    for (_i, test) in tests.iter().enumerate() {
+    let filter = command_line::options().test_filter.unwrap_or("");
+    let tests = tests.iter().filter(|test| test.name().contains(filter));
+
+    println!("Running {} tests", tests.clone().count());
+    for test in tests {
         test.run();
     }
 
@@ -177,6 +370,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 pub trait Testable {
+    fn name(&self) -> &'static str;
     fn run(&self) -> ();
 }
 
@@ -184,8 +378,12 @@ impl<T> Testable for T
 where
     T: Fn(),
 {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
     fn run(&self) {
-        print!("[{}] Running... ", core::any::type_name::<T>());
+        print!("[{}] Running... ", self.name());
         self();
         println!("Ok!");
     }
@@ -201,4 +399,556 @@ fn check_test_framework() {
 #[test_case]
 fn check_crash_catch() {
     x86_64::instructions::interrupts::int3();
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn exception_kinds_are_classified() {
+    use hugo4os::kernel::interrupts::ExceptionKind;
+
+    assert!(ExceptionKind::Breakpoint.is_resumable());
+    assert!(!ExceptionKind::NonMaskable.is_resumable());
+    assert!(ExceptionKind::NonMaskable.is_asynchronous());
+    assert!(!ExceptionKind::NonMaskable.is_fatal());
+    assert!(ExceptionKind::DoubleFault.is_fatal());
+    assert!(!ExceptionKind::ProtectionFault.is_resumable());
+    assert!(!ExceptionKind::ProtectionFault.is_fatal());
+}
+
+#[test_case]
+fn exception_report_format() {
+    use alloc::format;
+    use hugo4os::kernel::interrupts::{ExceptionKind, ExceptionReport};
+
+    let decoded = "external";
+    let report = ExceptionReport {
+        kind: ExceptionKind::ProtectionFault,
+        name: "General protection fault",
+        vector: 13,
+        error_code: Some(0x10),
+        decoded_error: Some(&decoded),
+        instruction_pointer: 0x1000,
+        stack_pointer: 0x2000,
+        user: true,
+        registers: &[("rax", 1), ("rbx", 2)],
+    };
+
+    let text = format!("{}", report);
+    let mut lines = text.lines();
+    assert_eq!(lines.next(), Some("EXCEPTION: General protection fault (vector 13) in user mode"));
+    assert_eq!(lines.next(), Some("  Error code: 0x10 (external)"));
+    assert_eq!(lines.next(), Some("  At 0x0000000000001000, stack 0x0000000000002000"));
+    assert_eq!(lines.next(), Some("     rax 0x0000000000000001    rbx 0x0000000000000002"));
+    assert_eq!(lines.next(), None);
+}
+
+#[test_case]
+fn non_maskable_interrupt_continues() {
+    use hugo4os::kernel::interrupts::{self, ExceptionAction, ExceptionKind, ExceptionReport};
+
+    let report = ExceptionReport {
+        kind: ExceptionKind::NonMaskable,
+        name: "Non-maskable interrupt",
+        vector: 2,
+        error_code: None,
+        decoded_error: None,
+        instruction_pointer: 0x1000,
+        stack_pointer: 0x2000,
+        user: false,
+        registers: &[],
+    };
+    assert_eq!(interrupts::exception(&report), ExceptionAction::Continue);
+}
+
+// Backtraces
+
+/// A `.symtab` entry: name offset, type, value and size.
+#[cfg(test)]
+fn test_symbol(name: u32, kind: u8, start: u64, size: u64) -> [u8; 24] {
+    let mut symbol = [0; 24];
+    symbol[0..4].copy_from_slice(&name.to_le_bytes());
+    symbol[4] = kind;
+    symbol[8..16].copy_from_slice(&start.to_le_bytes());
+    symbol[16..24].copy_from_slice(&size.to_le_bytes());
+    symbol
+}
+
+#[test_case]
+fn symbol_table_finds_functions() {
+    const FUNCTION: u8 = 2;
+    const OBJECT: u8 = 1;
+
+    let names = b"\0first\0data\0empty\0";
+    let symbols = [
+        test_symbol(1, FUNCTION, 0x1000, 0x10),
+        test_symbol(7, OBJECT, 0x2000, 0x10),
+        test_symbol(12, FUNCTION, 0x3000, 0),
+    ].concat();
+    let symbols = backtrace::SymbolTable::new(symbols.leak(), names, 0x100);
+
+    assert_eq!(symbols.lookup(0x1100), Some(("first", 0x1100)));
+    assert_eq!(symbols.lookup(0x110f), Some(("first", 0x1100)));
+    assert_eq!(symbols.lookup(0x1110), None);
+    assert_eq!(symbols.lookup(0x1000), None);
+    assert_eq!(symbols.lookup(0x2108), None); // Not a function
+    assert_eq!(symbols.lookup(0x3100), Some(("empty", 0x3100))); // Size 0 still covers its start
+}
+
+#[test_case]
+fn demangled_symbol_names() {
+    use alloc::format;
+    use backtrace::Demangled;
+
+    let demangle = |name| format!("{}", Demangled(name));
+    assert_eq!(demangle("_ZN7hugo4os6kernel4main17h0123456789abcdefE"), "hugo4os::kernel::main");
+    assert_eq!(
+        demangle("_ZN4core3ptr46drop_in_place$LT$alloc..vec..Vec$LT$u8$GT$$GT$17h0123456789abcdefE"),
+        "core::ptr::drop_in_place<alloc::vec::Vec<u8>>",
+    );
+    assert_eq!(demangle("_ZN4main27$u7b$$u7b$closure$u7d$$u7d$E"), "main::{{closure}}");
+    assert_eq!(demangle("_ZN4mainE"), "main");
+
+    // Not mangled, or mangled wrong
+    assert_eq!(demangle("memcpy"), "memcpy");
+    assert_eq!(demangle("_ZN9mainE"), "_ZN9mainE");
+}
+
+// Processes
+
+#[test_case]
+fn kernel_stack_switch_reaches_syscalls() {
+    let cpu = cpu::current();
+    let previous = cpu.kernel_stack_top();
+    let top = gdt::allocate_stack();
+
+    // What the timer does when it switches to a process
+    unsafe { cpu.set_kernel_stack(top) };
+    let syscall_stack_top: u64;
+    unsafe { core::arch::asm!("mov {}, gs:[8]", out(reg) syscall_stack_top) };
+    assert_eq!(cpu.kernel_stack_top(), top);
+    assert_eq!(syscall_stack_top, top.as_u64());
+
+    unsafe { cpu.set_kernel_stack(previous) };
+}
+
+#[test_case]
+fn scheduler_keeps_running_the_kernel_alone() {
+    use hugo4os::task::process_manager::{ProcessManager, KERNEL_PROCESS_ID};
+
+    let mut manager = ProcessManager::new();
+    for _ in 0..10 {
+        assert!(manager.schedule(0x1000).is_none());
+    }
+
+    let statistics = manager.statistics(KERNEL_PROCESS_ID).unwrap();
+    assert_eq!(statistics.ticks, 10);
+    assert_eq!(statistics.switches, 0);
+}
+
+#[test_case]
+fn kill_unknown_process() {
+    use hugo4os::task::process_manager::{ProcessManager, ProcessKillSignal, ProcessKillError};
+
+    let mut manager = ProcessManager::new();
+    assert_eq!(manager.kill(1, ProcessKillSignal::Terminate), Err(ProcessKillError::ProcessNotFound));
+}
+
+/// Processors that never need waking, the tests run them by hand.
+#[cfg(test)]
+struct TestCpus;
+
+#[cfg(test)]
+impl hugo4os::kernel::cpu::Cpus for TestCpus {
+    fn start_others(_entry: fn() -> !) -> usize {
+        1
+    }
+
+    fn current() -> usize {
+        0
+    }
+
+    fn wake(_index: usize) {}
+
+    fn stop_others() {}
+
+    fn local() -> &'static hugo4os::kernel::cpu::CpuLocal {
+        static LOCAL: hugo4os::kernel::cpu::CpuLocal = hugo4os::kernel::cpu::CpuLocal::new();
+        &LOCAL
+    }
+}
+
+#[test_case]
+fn shared_executor_steals_tasks() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use hugo4os::task::shared_executor::SharedExecutor;
+
+    let executor = SharedExecutor::new::<TestCpus>(2);
+    let ran = Arc::new(AtomicUsize::new(0));
+    for _ in 0..2 {
+        let ran = ran.clone();
+        executor.spawn(async move {
+            ran.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    // One was queued for each processor, the second takes the first one's
+    assert!(executor.run_next(1));
+    assert!(executor.run_next(1));
+    assert_eq!(ran.load(Ordering::Relaxed), 2);
+    assert!(!executor.run_next(0));
+
+    // Except when it is pinned
+    let pinned = ran.clone();
+    executor.spawn_pinned(0, async move {
+        pinned.fetch_add(1, Ordering::Relaxed);
+    });
+    assert!(!executor.run_next(1));
+    assert!(executor.run_next(0));
+    assert_eq!(ran.load(Ordering::Relaxed), 3);
+}
+
+#[test_case]
+fn shared_executor_task_woken_while_running() {
+    use alloc::{boxed::Box, sync::Arc};
+    use core::{sync::atomic::{AtomicUsize, Ordering}, task::Poll};
+    use futures_util::future::poll_fn;
+    use hugo4os::task::shared_executor::SharedExecutor;
+
+    let executor: &'static SharedExecutor = Box::leak(Box::new(SharedExecutor::new::<TestCpus>(2)));
+    let polls = Arc::new(AtomicUsize::new(0));
+
+    let counted = polls.clone();
+    executor.spawn(poll_fn(move |context| {
+        if counted.fetch_add(1, Ordering::Relaxed) > 0 {
+            return Poll::Ready(());
+        }
+
+        // Processor 1 takes it while processor 0 is still polling it, and
+        // leaves it alone instead of polling it at the same time
+        context.waker().wake_by_ref();
+        assert!(executor.run_next(1));
+        Poll::Pending
+    }));
+
+    assert!(executor.run_next(0));
+    assert_eq!(polls.load(Ordering::Relaxed), 1);
+
+    // Processor 0 queued it again when it was done
+    assert!(executor.run_next(0));
+    assert_eq!(polls.load(Ordering::Relaxed), 2);
+    assert!(!executor.run_next(0) && !executor.run_next(1));
+}
+
+// Memory
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+#[test_case]
+fn page_fault_in_stack_guard_is_overflow() {
+    use hugo4os::{constants::{USER_STACK_TOP, USER_STACK_SIZE}, kernel::memory::{Access, PageFault}};
+
+    let mut fault = PageFault {
+        address: USER_STACK_TOP - USER_STACK_SIZE - 8,
+        instruction_pointer: 0x40_1000,
+        stack_pointer: USER_STACK_TOP - USER_STACK_SIZE - 8,
+        access: Access::Write,
+        present: false,
+        user: true,
+    };
+    assert!(fault.is_stack_overflow());
+
+    fault.address = USER_STACK_TOP - USER_STACK_SIZE;
+    assert!(!fault.is_stack_overflow());
+}
+
+#[cfg(test)]
+const TEST_FRAME_COUNT: usize = 128;
+
+/// Stands in for physical memory in frame allocator tests, frame 0 is at its start.
+#[cfg(test)]
+#[repr(C, align(4096))]
+struct TestFrames([u8; 4096 * TEST_FRAME_COUNT]);
+
+#[cfg(test)]
+fn test_frame_allocator() -> memory::BuddyFrameAllocator {
+    static mut FRAMES: TestFrames = TestFrames([0; 4096 * TEST_FRAME_COUNT]);
+
+    let physical_memory_offset = VirtAddr::from_ptr(unsafe { &FRAMES });
+    unsafe { memory::BuddyFrameAllocator::from_frames(core::iter::once((0, TEST_FRAME_COUNT as u64)), physical_memory_offset) }
+}
+
+#[test_case]
+fn buddy_allocator_splits_and_merges() {
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+    let mut allocator = test_frame_allocator();
+    let free = allocator.counts().free;
+
+    // Single frames split every block down to order 0
+    let frames: Vec<_> = core::iter::from_fn(|| allocator.allocate_frame()).collect();
+    assert_eq!(frames.len(), free);
+    assert!(allocator.allocate_contiguous(64).is_none());
+
+    for frame in frames {
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+    assert_eq!(allocator.counts().free, free);
+    assert!(allocator.allocate_contiguous(64).is_some());
+}
+
+#[test_case]
+fn buddy_allocator_aligns_contiguous_frames() {
+    let mut allocator = test_frame_allocator();
+
+    for count in [1, 2, 3, 5, 8, 16] {
+        let start = allocator.allocate_contiguous(count).expect("Out of test frames");
+        let number = start.start_address().as_u64() / 4096;
+        assert_eq!(number % count.next_power_of_two() as u64, 0);
+    }
+
+    assert!(allocator.allocate_contiguous(0).is_none());
+    assert!(allocator.allocate_contiguous(4096).is_none());
+}
+
+#[test_case]
+fn buddy_allocator_runs_out() {
+    use x86_64::structures::paging::FrameAllocator;
+
+    let mut allocator = test_frame_allocator();
+    let total = allocator.counts().free;
+    assert!(allocator.allocate_contiguous(TEST_FRAME_COUNT).is_none());
+
+    while allocator.allocate_frame().is_some() {}
+    let counts = allocator.counts();
+    assert_eq!((counts.free, counts.used), (0, total));
+    assert!(allocator.allocate_contiguous(1).is_none());
+}
+
+/// Some user address no test maps anything else at.
+#[cfg(test)]
+const TEST_USER_ADDRESS: usize = 0x1000_0000;
+
+#[test_case]
+fn reserved_memory_is_mapped_on_first_use() {
+    use hugo4os::kernel::memory::{Access, MemoryError, MemoryManager, Protection};
+
+    let space = X86_64::create_address_space().unwrap();
+    X86_64::reserve(space, TEST_USER_ADDRESS, 0x2000, Protection::ReadWrite).unwrap();
+    assert_eq!(X86_64::reserve(space, TEST_USER_ADDRESS + 0x1000, 0x1000, Protection::Read), Err(MemoryError::AddressInUse));
+
+    unsafe { X86_64::switch_address_space(space) };
+    let used = memory::frame_counts().used;
+    assert!(X86_64::check_user_access(TEST_USER_ADDRESS, 0x1000, Access::Write));
+    assert!(memory::frame_counts().used > used);
+    assert!(!X86_64::check_user_access(TEST_USER_ADDRESS + 0x2000, 0x1000, Access::Read));
+    unsafe { X86_64::switch_address_space(X86_64::kernel_address_space()) };
+
+    X86_64::destroy_address_space(space).unwrap();
+}
+
+#[test_case]
+fn copy_on_write_shares_until_written() {
+    use hugo4os::kernel::memory::{AddressSpace, MemoryManager, Protection};
+
+    let free = memory::frame_counts().free;
+    let parent = X86_64::create_address_space().unwrap();
+    X86_64::map(parent, TEST_USER_ADDRESS, 0x1000, Protection::ReadWrite).unwrap();
+    X86_64::write(parent, TEST_USER_ADDRESS, b"parent").unwrap();
+
+    let child = X86_64::clone_address_space(parent).unwrap();
+    let shared = memory::frame_counts().used;
+    X86_64::write(child, TEST_USER_ADDRESS, b"child!").unwrap();
+    assert!(memory::frame_counts().used > shared);
+
+    let read = |space: AddressSpace| unsafe {
+        X86_64::switch_address_space(space);
+        *(TEST_USER_ADDRESS as *const [u8; 6])
+    };
+    assert_eq!(&read(parent), b"parent");
+    assert_eq!(&read(child), b"child!");
+    unsafe { X86_64::switch_address_space(X86_64::kernel_address_space()) };
+
+    // The last one to go frees the frames they shared
+    X86_64::destroy_address_space(child).unwrap();
+    X86_64::destroy_address_space(parent).unwrap();
+    assert_eq!(memory::frame_counts().free, free);
+}
+
+// Time
+
+#[test_case]
+fn rtc_date_in_bcd() {
+    use hugo4os::kernel::time::DateTime;
+
+    let status = 1 << 1; // 24 hour mode
+    let date = clock::decode_date([0x56, 0x34, 0x23, 0x31, 0x12, 0x99], Some(0x19), status);
+    assert_eq!(date, DateTime { year: 1999, month: 12, day: 31, hour: 23, minute: 34, second: 56 });
+
+    let binary = status | 1 << 2;
+    let date = clock::decode_date([56, 34, 23, 9, 7, 22], None, binary);
+    assert_eq!(date, DateTime { year: 2022, month: 7, day: 9, hour: 23, minute: 34, second: 56 });
+}
+
+#[test_case]
+fn rtc_date_in_12_hour_mode() {
+    let hour = |register: u8| clock::decode_date([0, 0, register, 1, 1, 0x22], None, 0).hour;
+    assert_eq!(hour(0x12), 0); // Midnight
+    assert_eq!(hour(0x01), 1);
+    assert_eq!(hour(0x80 | 0x12), 12); // Noon
+    assert_eq!(hour(0x80 | 0x11), 23);
+}
+
+#[test_case]
+fn date_to_unix_time_and_back() {
+    use hugo4os::kernel::time::DateTime;
+
+    let date = DateTime { year: 2022, month: 7, day: 9, hour: 12, minute: 34, second: 56 };
+    assert_eq!(date.to_unix_seconds(), 1_657_370_096);
+    assert_eq!(DateTime::from_unix_seconds(1_657_370_096), date);
+    assert_eq!(DateTime::from_unix_seconds(951_782_400), DateTime { year: 2000, month: 2, day: 29, hour: 0, minute: 0, second: 0 });
+}
+
+#[test_case]
+fn sleep_ends_at_its_deadline() {
+    use core::{future::Future, pin::Pin, task::{Context, Poll}};
+    use futures_util::task::noop_waker;
+    use hugo4os::kernel::time;
+
+    let waker = noop_waker();
+    let mut context = Context::from_waker(&waker);
+
+    let mut done = time::sleep_until(time::ticks());
+    assert_eq!(Pin::new(&mut done).poll(&mut context), Poll::Ready(()));
+
+    let mut forever = time::sleep_until(u64::MAX);
+    assert_eq!(Pin::new(&mut forever).poll(&mut context), Poll::Pending);
+}
+
+// Utilities
+
+#[test_case]
+fn ring_buffer_wraps_around() {
+    use hugo4os::util::ring_buffer::RingBuffer;
+
+    let mut buffer = RingBuffer::new(4);
+    assert_eq!(buffer.push(&[1, 2, 3]), 3);
+
+    let mut output = [0; 2];
+    assert_eq!(buffer.pop(&mut output), 2);
+    assert_eq!(output, [1, 2]);
+
+    assert_eq!(buffer.push(&[4, 5, 6, 7]), 3);
+    assert!(buffer.is_full());
+
+    let mut output = [0; 4];
+    assert_eq!(buffer.pop(&mut output), 4);
+    assert_eq!(output, [3, 4, 5, 6]);
+    assert!(buffer.is_empty());
+}
+
+// Kernel command line
+
+#[test_case]
+fn parse_command_line() {
+    use hugo4os::kernel::command_line::{LogLevel, OptionError, Options, Resolution};
+
+    let options = Options::parse("log=trace serial=0x2f8 bogus resolution=800x600");
+    assert_eq!(options.log_level, LogLevel::Trace);
+    assert_eq!(options.serial_port, Some(0x2f8));
+    assert_eq!(options.resolution, Some(Resolution { width: 800, height: 600 }));
+    assert_eq!(options.test_filter, None);
+
+    let mut options = Options::default();
+    assert_eq!(options.set("serial=com9"), Err(OptionError::InvalidValue("serial", "com9")));
+    assert_eq!(options.set("test"), Err(OptionError::MissingValue("test")));
+    assert_eq!(options, Options::default());
+}
+
+// Initrd
+
+#[test_case]
+fn find_file_in_tar_archive() {
+    use alloc::vec;
+    use hugo4os::loaders::tar::Archive;
+
+    let mut archive = vec![0u8; 512 * 4];
+    archive[..15].copy_from_slice(b"./fonts/one.ttf");
+    archive[124..135].copy_from_slice(b"00000000005");
+    archive[156] = b'0';
+    archive[257..262].copy_from_slice(b"ustar");
+    archive[512..517].copy_from_slice(b"hello");
+
+    let archive = Archive::new(&archive);
+    assert_eq!(archive.get("fonts/one.ttf"), Some(&b"hello"[..]));
+    assert_eq!(archive.get("fonts/two.ttf"), None);
+    assert_eq!(archive.files().count(), 1);
+}
+
+// ACPI
+
+#[test_case]
+fn parse_madt() {
+    use alloc::vec;
+    use hugo4os::kernel::acpi::{self, madt::{Madt, Polarity, TriggerMode}};
+
+    let mut table = vec![0u8; 44];
+    table[..4].copy_from_slice(b"APIC");
+    table[36..40].copy_from_slice(&0xfee0_0000u32.to_le_bytes());
+    table[40] = 1;
+    table.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);                     // Processor 0, enabled
+    table.extend_from_slice(&[0, 8, 1, 1, 0, 0, 0, 0]);                     // Processor 1, disabled
+    table.extend_from_slice(&[1, 12, 2, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0]); // IO-APIC at 0xfec00000
+    table.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0x0f, 0]);          // IRQ 0 -> GSI 2, active low, level
+    let length = table.len() as u32;
+    table[4..8].copy_from_slice(&length.to_le_bytes());
+    table[9] = 0u8.wrapping_sub(table.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)));
+    assert!(acpi::checksum(&table));
+
+    let madt = Madt::parse(&table);
+    assert_eq!(madt.local_apic_address, 0xfee0_0000);
+    assert!(madt.has_legacy_pics);
+    assert_eq!(madt.usable_processors().count(), 1);
+    assert_eq!(madt.io_apics[0].address, 0xfec0_0000);
+    assert_eq!(madt.isa_irq(0).gsi, 2);
+    assert_eq!((madt.isa_irq(0).polarity, madt.isa_irq(0).trigger), (Polarity::ActiveLow, TriggerMode::Level));
+    assert_eq!(madt.isa_irq(1).gsi, 1);
+}
+
+#[test_case]
+fn find_s5_sleep_type() {
+    use hugo4os::kernel::acpi::aml::{self, SleepType};
+
+    // Name (\_S5, Package (4) { 5, Zero, Zero, Zero }), after a reference to it
+    let aml = [0x70, b'_', b'S', b'5', b'_', 0x60, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x07, 0x04, 0x0a, 0x05, 0x00, 0x00, 0x00];
+    assert_eq!(aml::sleep_type(&aml, 5), Some(SleepType { pm1a: 5, pm1b: 0 }));
+    assert_eq!(aml::sleep_type(&aml, 3), None);
+}
+
+// Syscalls
+
+#[test_case]
+fn syscall_error_round_trip() {
+    use hugo4os_syscall::error::SyscallError;
+
+    let encoded = SyscallError::encode(Err(SyscallError::InvalidStream));
+    assert_eq!(SyscallError::decode(encoded), Err(SyscallError::InvalidStream));
+    assert_eq!(SyscallError::decode(SyscallError::encode(Ok(42))), Ok(42));
+    assert_eq!(SyscallError::from_code(SyscallError::PermissionDenied.code()), SyscallError::PermissionDenied);
+}
+
+#[test_case]
+fn syscall_buffer_in_kernel_heap() {
+    use hugo4os::{constants::HEAP_START, kernel::{memory::Access, syscall}};
+    use hugo4os_syscall::error::SyscallError;
+
+    let result = syscall::check_buffer(HEAP_START as u64, 16, Access::Read);
+    assert_eq!(result, Err(SyscallError::InvalidPointer));
+}
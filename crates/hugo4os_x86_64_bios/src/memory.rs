@@ -1,22 +1,826 @@
-use core::{alloc::{GlobalAlloc, Layout}, ptr::{self, NonNull}};
+use core::{alloc::{GlobalAlloc, Layout}, fmt, mem, ops::{Deref, DerefMut}, ptr::{self, NonNull}, sync::atomic::{AtomicU32, Ordering}};
 
-use bootloader::boot_info::{MemoryRegions, MemoryRegionKind};
-use x86_64::{structures::paging::{PageTable, OffsetPageTable, FrameAllocator, Size4KiB, PhysFrame, mapper::MapToError, Mapper, Page, PageTableFlags}, VirtAddr, PhysAddr};
+use alloc::vec::Vec;
 
-use hugo4os::{constants::{HEAP_SIZE, HEAP_START, BLOCK_SIZES}, kernel::memory::MemoryManager};
-use super::{ALLOCATOR, X86_64, Locked};
+use bootloader::{boot_info::{MemoryRegions, MemoryRegionKind}, binary::{load_kernel::{self, ElfLoadConfig}, level_4_entries::UsedLevel4Entries}};
+use spin::Once;
+use x86_64::{structures::{idt::PageFaultErrorCode, paging::{PageTable, page_table::PageTableEntry, OffsetPageTable, FrameAllocator, Size4KiB, PhysFrame, mapper::{MapToError, MappedFrame, MapperFlush, TranslateResult, UnmapError, FlagUpdateError}, Mapper, Page, PageTableFlags, Translate, PageSize, FrameDeallocator}}, registers::control::{Cr0, Cr0Flags, Cr3}, instructions::tlb, VirtAddr, PhysAddr};
+
+use hugo4os::{constants::{HEAP_INITIAL_SIZE, HEAP_MAX_SIZE, HEAP_GROWTH_MIN, HEAP_START, BLOCK_SIZES, MMIO_SIZE, MMIO_START}, kernel::{lock::{IrqMutex, IrqMutexGuard}, memory::{MemoryManager, Access, AddressSpace, MemoryError, Protection, ExecutableImage, ExecutableError, TlsTemplate}}};
+use super::{interrupts, ALLOCATOR, X86_64, Locked};
+#[cfg(feature = "heap-debug")] use super::heap_debug;
+
+/// Where the bootloader mapped all of physical memory
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+/// Frame allocator shared by everything that maps memory after boot. Also
+/// serializes changes to page tables, every [`MemoryManager`] method holds it.
+static FRAME_ALLOCATOR: Once<FrameAllocatorLock> = Once::new();
+
+/// The page table the kernel booted with, new address spaces copy its entries
+static KERNEL_LEVEL_4_FRAME: Once<PhysFrame> = Once::new();
+
+/// Memory set aside with [`MemoryManager::reserve`] that isn't mapped yet, of
+/// every address space. Always locked after [`FRAME_ALLOCATOR`].
+static RESERVATIONS: IrqMutex<Vec<Reservation>> = IrqMutex::new(Vec::new());
+
+/// Physical memory mapped into the device memory window, each range once.
+/// Always locked after [`FRAME_ALLOCATOR`].
+static MMIO_MAPPINGS: IrqMutex<Vec<MmioMapping>> = IrqMutex::new(Vec::new());
+
+/// First level 4 entry of the higher half, user memory stays below it
+const HIGHER_HALF_P4_INDEX: usize = 256;
+
+/// Marks a read-only page whose frame may be shared with another address
+/// space, writing to it copies the frame first (the bootloader uses BIT_9)
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Page tables leading to user memory allow everything, the pages themselves
+/// decide (so a table can be shared by pages with different protections)
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE).union(PageTableFlags::USER_ACCESSIBLE);
+
+#[derive(Debug, Clone, Copy)]
+struct Reservation {
+    space: AddressSpace,
+    start: usize,
+    end: usize,
+    flags: PageTableFlags,
+}
+
+/// [`FRAME_ALLOCATOR`] and the processor holding it. The heap grows with it,
+/// and it has to know when that processor is the one allocating.
+struct FrameAllocatorLock {
+    allocator: IrqMutex<BuddyFrameAllocator>,
+    owner: AtomicU32, // Local APIC ID, `NO_OWNER` when free
+}
+
+struct FrameAllocatorGuard<'a> {
+    guard: IrqMutexGuard<'a, BuddyFrameAllocator>,
+    owner: &'a AtomicU32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MmioMapping {
+    start: usize, // Physical, page aligned
+    end: usize,
+    virt: usize,
+}
+
+// Address spaces only share the level 4 entries that existed when they were
+// created, so the heap can't grow into a new one. The device memory window
+// uses the heap's.
+const _: () = assert!(HEAP_START >> 39 == (HEAP_START + HEAP_MAX_SIZE - 1) >> 39, "The heap has to fit in one level 4 entry");
+const _: () = assert!(HEAP_START >> 39 == (MMIO_START + MMIO_SIZE - 1) >> 39, "The device memory window has to share the heap's level 4 entry");
 
 impl MemoryManager for X86_64 {
-    
+    fn kernel_address_space() -> AddressSpace {
+        AddressSpace(kernel_level_4_frame().start_address().as_u64() as usize)
+    }
+
+    fn create_address_space() -> Result<AddressSpace, MemoryError> {
+        let mut frame_allocator = frame_allocator().lock();
+        let frame = frame_allocator.allocate_frame().ok_or(MemoryError::OutOfMemory)?;
+
+        // Level 4 entries are shared, so the kernel stays mapped the same way everywhere
+        let kernel_table = unsafe { level_4_table(kernel_level_4_frame()) };
+        let table = unsafe { level_4_table(frame) };
+        *table = PageTable::new();
+        for (entry, kernel_entry) in table.iter_mut().zip(kernel_table.iter()) {
+            *entry = kernel_entry.clone();
+        }
+
+        Ok(AddressSpace(frame.start_address().as_u64() as usize))
+    }
+
+    fn clone_address_space(space: AddressSpace) -> Result<AddressSpace, MemoryError> {
+        let clone = X86_64::create_address_space()?;
+
+        let result = share_user_memory(space, clone);
+        if result.is_err() {
+            X86_64::destroy_address_space(clone).expect("Failed to destroy a new address space");
+        }
+
+        result.map(|_| clone)
+    }
+
+    fn destroy_address_space(space: AddressSpace) -> Result<(), MemoryError> {
+        if is_active(space) || space == X86_64::kernel_address_space() {
+            return Err(MemoryError::AddressInUse);
+        }
+
+        let mut frame_allocator = frame_allocator().lock();
+        RESERVATIONS.lock().retain(|reservation| reservation.space != space);
+
+        let frame = address_space_frame(space);
+        let kernel_table = unsafe { level_4_table(kernel_level_4_frame()) };
+        let table = unsafe { level_4_table(frame) };
+
+        for (entry, kernel_entry) in table.iter().zip(kernel_table.iter()) {
+            if kernel_entry.is_unused() && !entry.is_unused() {
+                unsafe { free_page_table(entry.addr(), 3, &mut *frame_allocator) };
+            }
+        }
+
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        Ok(())
+    }
+
+    unsafe fn switch_address_space(space: AddressSpace) {
+        // Writing CR3 flushes the TLB, even when nothing changes
+        if !is_active(space) {
+            let (_, flags) = Cr3::read();
+            Cr3::write(address_space_frame(space), flags);
+        }
+    }
+
+    fn load_executable(space: AddressSpace, data: &[u8]) -> Result<ExecutableImage, ExecutableError> {
+        let mut frame_allocator = frame_allocator().lock();
+        let mut page_table = unsafe { address_space_page_table(space) };
+        let mut used_entries = UsedLevel4Entries::from_page_table(page_table.level_4_table());
+
+        let config = ElfLoadConfig {
+            physical_memory_offset: physical_memory_offset(),
+            user: true,
+        };
+        let loaded = load_kernel::load_elf(data, &mut page_table, &mut *frame_allocator, &mut used_entries, config)
+            .map_err(ExecutableError::Invalid)?;
+
+        Ok(ExecutableImage {
+            entry: loaded.entry_point.as_u64() as usize,
+            base: loaded.virtual_address_offset as usize,
+            program_headers: loaded.program_headers.map(|address| address.as_u64() as usize),
+            program_header_size: loaded.program_header_size as usize,
+            program_header_count: loaded.program_header_count as usize,
+            tls: loaded.tls_template.map(|tls| TlsTemplate {
+                start: tls.start_addr as usize,
+                file_size: tls.file_size as usize,
+                mem_size: tls.mem_size as usize,
+            }),
+        })
+    }
+
+    fn map(space: AddressSpace, address: usize, size: usize, protection: Protection) -> Result<(), MemoryError> {
+        let pages = user_pages(address, size)?;
+
+        let mut frame_allocator = frame_allocator().lock();
+        let mut page_table = unsafe { address_space_page_table(space) };
+        let flags = protection_flags(protection);
+
+        for page in pages {
+            // Mapping into an entry shared with the kernel would change every address space
+            if is_kernel_entry(page) {
+                return Err(MemoryError::AddressInUse);
+            }
+
+            let flush = map_zeroed(&mut page_table, page, flags, &mut *frame_allocator)?;
+            if is_active(space) {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn reserve(space: AddressSpace, address: usize, size: usize, protection: Protection) -> Result<(), MemoryError> {
+        let mut pages = user_pages(address, size)?;
+
+        let _frame_allocator = frame_allocator().lock();
+        if pages.any(is_kernel_entry) {
+            return Err(MemoryError::AddressInUse);
+        }
+
+        let mut reservations = RESERVATIONS.lock();
+        let end = address + size;
+        if reservations.iter().any(|reservation| reservation.space == space && reservation.start < end && address < reservation.end) {
+            return Err(MemoryError::AddressInUse);
+        }
+
+        reservations.push(Reservation {
+            space,
+            start: address,
+            end,
+            flags: protection_flags(protection),
+        });
+
+        Ok(())
+    }
+
+    fn unmap(space: AddressSpace, address: usize, size: usize) -> Result<(), MemoryError> {
+        let pages = user_pages(address, size)?;
+
+        let mut frame_allocator = frame_allocator().lock();
+        let mut page_table = unsafe { address_space_page_table(space) };
+        unreserve(space, address, address + size);
+
+        for page in pages {
+            if is_kernel_entry(page) {
+                return Err(MemoryError::AddressInUse);
+            }
+
+            match page_table.unmap(page) {
+                Ok((frame, flush)) => {
+                    unsafe { frame_allocator.release(frame) };
+                    if is_active(space) {
+                        flush.flush();
+                    } else {
+                        flush.ignore();
+                    }
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(UnmapError::ParentEntryHugePage) => return Err(MemoryError::AddressInUse),
+                Err(UnmapError::InvalidFrameAddress(_)) => return Err(MemoryError::InvalidAddress),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn protect(space: AddressSpace, address: usize, size: usize, protection: Protection) -> Result<(), MemoryError> {
+        let pages = user_pages(address, size)?;
+
+        let frame_allocator = frame_allocator().lock();
+        let mut page_table = unsafe { address_space_page_table(space) };
+        let flags = protection_flags(protection);
+
+        for page in pages {
+            if is_kernel_entry(page) {
+                return Err(MemoryError::AddressInUse);
+            }
+
+            // A shared frame only becomes writable after it is copied
+            let flags = match page_table.translate_page(page) {
+                Ok(frame) if flags.contains(PageTableFlags::WRITABLE) && frame_allocator.is_shared(frame) => {
+                    (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+                }
+                _ => flags,
+            };
+
+            let flush = unsafe { page_table.update_flags(page, flags) }.map_err(|error| match error {
+                FlagUpdateError::PageNotMapped => MemoryError::NotMapped,
+                FlagUpdateError::ParentEntryHugePage => MemoryError::AddressInUse,
+            })?;
+
+            if is_active(space) {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write(space: AddressSpace, address: usize, data: &[u8]) -> Result<(), MemoryError> {
+        let mut frame_allocator = frame_allocator().lock();
+        let mut page_table = unsafe { address_space_page_table(space) };
+
+        let mut written = 0;
+        while written < data.len() {
+            let virt = VirtAddr::try_new((address + written) as u64).map_err(|_| MemoryError::InvalidAddress)?;
+
+            // Other address spaces must not see the write
+            if let Some(flush) = unsafe { copy_on_write(&mut page_table, Page::containing_address(virt), &mut *frame_allocator)? } {
+                if is_active(space) {
+                    flush.flush();
+                } else {
+                    flush.ignore();
+                }
+            }
+
+            let phys = match page_table.translate(virt) {
+                TranslateResult::Mapped { frame, offset, flags } if flags.contains(PageTableFlags::USER_ACCESSIBLE) => {
+                    frame.start_address() + offset
+                }
+                _ => return Err(MemoryError::NotMapped),
+            };
+
+            // Stop at the end of the page, the next one may be anywhere in physical memory
+            let chunk = (Size4KiB::SIZE - u64::from(virt.page_offset())) as usize;
+            let chunk = chunk.min(data.len() - written);
+            unsafe {
+                let destination: *mut u8 = (physical_memory_offset() + phys.as_u64()).as_mut_ptr();
+                ptr::copy_nonoverlapping(data[written..].as_ptr(), destination, chunk);
+            }
+
+            written += chunk;
+        }
+
+        Ok(())
+    }
+
+    fn check_user_access(address: usize, size: usize, access: Access) -> bool {
+        let end = match address.checked_add(size) {
+            Some(end) if end <= HIGHER_HALF_P4_INDEX << 39 => end,
+            _ => return false,
+        };
+        if size == 0 {
+            return true;
+        }
+
+        let mut frame_allocator = frame_allocator().lock();
+        let space = active_address_space();
+        let mut page_table = unsafe { address_space_page_table(space) };
+        let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(address as u64));
+        let end_page = Page::<Size4KiB>::containing_address(VirtAddr::new((end - 1) as u64));
+
+        Page::range_inclusive(start_page, end_page).all(|page| {
+            if is_kernel_entry(page) {
+                return false;
+            }
+            unsafe { fault_in(&mut page_table, space, page, access == Access::Write, &mut *frame_allocator) };
+
+            let flags = match page_table.translate(page.start_address()) {
+                TranslateResult::Mapped { flags, .. } => flags,
+                _ => return false,
+            };
+            flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) && match access {
+                Access::Read => true,
+                Access::Write => flags.contains(PageTableFlags::WRITABLE),
+                Access::Execute => !flags.contains(PageTableFlags::NO_EXECUTE),
+            }
+        })
+    }
+
+    fn map_physical(address: usize, size: usize) -> Result<usize, MemoryError> {
+        let end = address.checked_add(size.max(1)).ok_or(MemoryError::InvalidAddress)?;
+        let start = address & !0xfff;
+        let mut frame_allocator = frame_allocator().lock();
+        let mut page_table = unsafe { address_space_page_table(X86_64::kernel_address_space()) };
+
+        // The bootloader maps the memory in its memory map (with huge pages),
+        // firmware tables are usually in there
+        let offset = physical_memory_offset().as_u64();
+        let mapped = |physical: usize| {
+            let virt = offset.checked_add(physical as u64).and_then(|virt| VirtAddr::try_new(virt).ok());
+            virt.and_then(|virt| page_table.translate_addr(virt)) == Some(PhysAddr::new(physical as u64))
+        };
+        if (start..end).step_by(4096).all(mapped) {
+            return Ok(offset as usize + address);
+        }
+
+        // Devices aren't, they get their own uncached mapping
+        let mut mappings = MMIO_MAPPINGS.lock();
+        if let Some(mapping) = mappings.iter().find(|mapping| mapping.start <= start && end <= mapping.end) {
+            return Ok(mapping.virt + (address - mapping.start));
+        }
+
+        let size = (end - start + 0xfff) & !0xfff;
+        let virt = mappings.last().map_or(MMIO_START, |last| last.virt + (last.end - last.start));
+        if virt + size > MMIO_START + MMIO_SIZE {
+            return Err(MemoryError::OutOfMemory);
+        }
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | PageTableFlags::NO_CACHE;
+        for offset in (0..size).step_by(4096) {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new((virt + offset) as u64));
+            let frame = PhysFrame::containing_address(PhysAddr::new((start + offset) as u64));
+            unsafe { page_table.map_to(page, frame, flags, &mut *frame_allocator) }.map_err(map_error)?.flush();
+        }
+
+        mappings.push(MmioMapping { start, end: start + size, virt });
+        Ok(virt + (address - start))
+    }
 }
 
 pub fn init(physical_memory_offset: u64, memory_regions: &'static MemoryRegions) {
     let phys_mem_offset = VirtAddr::new(physical_memory_offset);
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::new(memory_regions) };
+    let mut frame_allocator = unsafe { BuddyFrameAllocator::new(memory_regions, phys_mem_offset) };
     let mut mapper = unsafe { new_page_table(phys_mem_offset) };
     
     // Initialize dynamic managed memory
     init_heap(&mut mapper, &mut frame_allocator).unwrap();
+
+    PHYSICAL_MEMORY_OFFSET.call_once(|| phys_mem_offset);
+    KERNEL_LEVEL_4_FRAME.call_once(|| Cr3::read().0);
+    FRAME_ALLOCATOR.call_once(|| FrameAllocatorLock::new(frame_allocator));
+
+    // Otherwise the kernel could write to copy-on-write pages without faulting
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+}
+
+/// Resolve a page fault at `address` in the active address space, by mapping
+/// reserved memory or copying a copy-on-write page. Returns false when the
+/// access really isn't allowed.
+///
+/// With `wait` this blocks until the page tables are free, which is only
+/// safe when the faulting code can't be holding them (user mode, or a
+/// syscall accessing user memory) and interrupts are enabled.
+pub fn handle_page_fault(address: VirtAddr, error: PageFaultErrorCode, wait: bool) -> bool {
+    let page = Page::<Size4KiB>::containing_address(address);
+    if usize::from(page.p4_index()) >= HIGHER_HALF_P4_INDEX || is_kernel_entry(page) {
+        return false;
+    }
+
+    let mut frame_allocator = match wait {
+        true => frame_allocator().lock(),
+        false => match frame_allocator().try_lock() {
+            Some(frame_allocator) => frame_allocator,
+            None => return false,
+        },
+    };
+
+    let space = active_address_space();
+    let mut page_table = unsafe { address_space_page_table(space) };
+    unsafe { fault_in(&mut page_table, space, page, error.contains(PageFaultErrorCode::CAUSED_BY_WRITE), &mut *frame_allocator) }
+}
+
+/// Do what a fault on `page` of the active address space `space` would:
+/// map it when it is reserved, or copy it when it is copy-on-write and this
+/// is a `write`. Returns false when nothing changed.
+///
+/// Unsafe because nothing may hold a reference to the frame `page` maps to.
+unsafe fn fault_in(page_table: &mut OffsetPageTable, space: AddressSpace, page: Page, write: bool, frame_allocator: &mut BuddyFrameAllocator) -> bool {
+    if page_table.translate_page(page).is_ok() {
+        if !write {
+            return false;
+        }
+
+        match copy_on_write(page_table, page, frame_allocator) {
+            Ok(Some(flush)) => {
+                flush.flush();
+                true
+            }
+            _ => false,
+        }
+    } else {
+        let start = page.start_address().as_u64() as usize;
+        let flags = RESERVATIONS.lock().iter()
+            .find(|reservation| reservation.space == space && (reservation.start..reservation.end).contains(&start))
+            .map(|reservation| reservation.flags);
+
+        match flags.map(|flags| map_zeroed(page_table, page, flags, frame_allocator)) {
+            Some(Ok(flush)) => {
+                flush.flush();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET.get().expect("memory::init wasn't called")
+}
+
+fn frame_allocator() -> &'static FrameAllocatorLock {
+    FRAME_ALLOCATOR.get().expect("memory::init wasn't called")
+}
+
+const NO_OWNER: u32 = u32::MAX;
+
+/// Identifies the processor for [`FrameAllocatorLock`]. Interrupt handlers
+/// allocate too and don't swap GS, so this can't use `cpu::current`. Only the
+/// boot processor runs before there is a local APIC.
+fn processor_id() -> u32 {
+    interrupts::local_apic().map_or(0, |local_apic| local_apic.id())
+}
+
+impl FrameAllocatorLock {
+    fn new(allocator: BuddyFrameAllocator) -> FrameAllocatorLock {
+        FrameAllocatorLock {
+            allocator: IrqMutex::new(allocator),
+            owner: AtomicU32::new(NO_OWNER),
+        }
+    }
+
+    fn lock(&self) -> FrameAllocatorGuard<'_> {
+        self.guard(self.allocator.lock())
+    }
+
+    fn try_lock(&self) -> Option<FrameAllocatorGuard<'_>> {
+        self.allocator.try_lock().map(|guard| self.guard(guard))
+    }
+
+    fn guard<'a>(&'a self, guard: IrqMutexGuard<'a, BuddyFrameAllocator>) -> FrameAllocatorGuard<'a> {
+        self.owner.store(processor_id(), Ordering::Relaxed);
+        FrameAllocatorGuard { guard, owner: &self.owner }
+    }
+
+    /// Whether the processor this runs on holds the lock, locking it again
+    /// would spin forever.
+    fn is_held_here(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == processor_id()
+    }
+}
+
+impl Deref for FrameAllocatorGuard<'_> {
+    type Target = BuddyFrameAllocator;
+
+    fn deref(&self) -> &BuddyFrameAllocator {
+        &self.guard
+    }
+}
+
+impl DerefMut for FrameAllocatorGuard<'_> {
+    fn deref_mut(&mut self) -> &mut BuddyFrameAllocator {
+        &mut self.guard
+    }
+}
+
+impl Drop for FrameAllocatorGuard<'_> {
+    fn drop(&mut self) {
+        // Before `guard` unlocks
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+    }
+}
+
+/// Usage of the kernel heap, for diagnostics.
+pub fn allocator_stats() -> AllocatorStats {
+    ALLOCATOR.lock().stats()
+}
+
+/// A free frame below physical address `limit`, for hardware (or startup
+/// code) that can't reach higher.
+pub fn allocate_frame_below(limit: u64) -> Option<PhysFrame> {
+    frame_allocator().lock().allocate_below(limit)
+}
+
+/// Map `frame` at its own physical address in `space`, for code that runs
+/// while paging is turned on (see `smp.rs`). Only the kernel can access it,
+/// [`MemoryManager::destroy_address_space`] frees it with the rest.
+pub fn identity_map(space: AddressSpace, frame: PhysFrame) -> Result<(), MemoryError> {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    if is_active(space) || is_kernel_entry(page) {
+        return Err(MemoryError::AddressInUse);
+    }
+
+    let mut frame_allocator = frame_allocator().lock();
+    let mut page_table = unsafe { address_space_page_table(space) };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { page_table.map_to(page, frame, flags, &mut *frame_allocator) }.map_err(map_error)?.ignore();
+    Ok(())
+}
+
+/// How many physical frames are in use, for diagnostics.
+pub fn frame_counts() -> FrameCounts {
+    frame_allocator().lock().counts()
+}
+
+/// Whether `address` can be read without faulting. Looks at the active page
+/// tables without locking them, for crash reports.
+pub fn is_readable(address: VirtAddr) -> bool {
+    match PHYSICAL_MEMORY_OFFSET.get() {
+        Some(&offset) => unsafe { new_page_table(offset) }.translate_addr(address).is_some(),
+        None => false,
+    }
+}
+
+fn kernel_level_4_frame() -> PhysFrame {
+    *KERNEL_LEVEL_4_FRAME.get().expect("memory::init wasn't called")
+}
+
+fn address_space_frame(space: AddressSpace) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(space.0 as u64))
+}
+
+/// Whether `page` falls in a level 4 entry shared with the kernel.
+fn is_kernel_entry(page: Page) -> bool {
+    let kernel_table = unsafe { level_4_table(kernel_level_4_frame()) };
+    !kernel_table[page.p4_index()].is_unused()
+}
+
+fn protection_flags(protection: Protection) -> PageTableFlags {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    match protection {
+        Protection::Read => flags | PageTableFlags::NO_EXECUTE,
+        Protection::ReadWrite => flags | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        Protection::ReadExecute => flags,
+    }
+}
+
+fn map_error(error: MapToError<Size4KiB>) -> MemoryError {
+    match error {
+        MapToError::FrameAllocationFailed => MemoryError::OutOfMemory,
+        MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => MemoryError::AddressInUse,
+    }
+}
+
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+/// Map a new, zeroed frame at `page`.
+fn map_zeroed(page_table: &mut OffsetPageTable, page: Page, flags: PageTableFlags, frame_allocator: &mut BuddyFrameAllocator) -> Result<MapperFlush<Size4KiB>, MemoryError> {
+    let frame = frame_allocator.allocate_frame().ok_or(MemoryError::OutOfMemory)?;
+    unsafe {
+        ptr::write_bytes(frame_ptr(frame), 0, Size4KiB::SIZE as usize);
+
+        page_table.map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, frame_allocator).map_err(|error| {
+            frame_allocator.deallocate_frame(frame);
+            map_error(error)
+        })
+    }
+}
+
+/// Give `page` a frame of its own if it is copy-on-write, and make it
+/// writable. Returns `None` when it isn't copy-on-write.
+///
+/// Unsafe because nothing may hold a reference to the frame `page` maps to.
+unsafe fn copy_on_write(page_table: &mut OffsetPageTable, page: Page, frame_allocator: &mut BuddyFrameAllocator) -> Result<Option<MapperFlush<Size4KiB>>, MemoryError> {
+    let (frame, flags) = match page_table.translate(page.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } if flags.contains(COPY_ON_WRITE) => (frame, flags),
+        _ => return Ok(None),
+    };
+    let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+    // The last address space using the frame can keep it
+    if !frame_allocator.is_shared(frame) {
+        let flush = page_table.update_flags(page, flags).map_err(|_| MemoryError::NotMapped)?;
+        return Ok(Some(flush));
+    }
+
+    // Switch the entry over to the copy, so the page is never unmapped and
+    // nothing can fail once the copy is made
+    let entry = level_1_entry(page_table, page).ok_or(MemoryError::NotMapped)?;
+    let copy = frame_allocator.allocate_frame().ok_or(MemoryError::OutOfMemory)?;
+    ptr::copy_nonoverlapping(frame_ptr(frame), frame_ptr(copy), Size4KiB::SIZE as usize);
+
+    entry.set_addr(copy.start_address(), flags);
+    frame_allocator.release(frame);
+
+    Ok(Some(MapperFlush::new(page)))
+}
+
+/// The entry of the level 1 table that maps `page`, `None` when there is no
+/// such table.
+///
+/// Unsafe because the caller has to hold the [`FRAME_ALLOCATOR`] lock.
+unsafe fn level_1_entry<'a>(page_table: &'a mut OffsetPageTable, page: Page) -> Option<&'a mut PageTableEntry> {
+    let mut table = page_table.level_4_table();
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let entry = &table[index];
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = level_4_table(PhysFrame::containing_address(entry.addr())); // Every level has the same layout
+    }
+
+    Some(&mut table[page.p1_index()])
+}
+
+/// Share all user memory of `space` with `clone`, which has none yet.
+fn share_user_memory(space: AddressSpace, clone: AddressSpace) -> Result<(), MemoryError> {
+    let mut frame_allocator = frame_allocator().lock();
+    let mut clone_table = unsafe { address_space_page_table(clone) };
+    let kernel_table = unsafe { level_4_table(kernel_level_4_frame()) };
+    let table = unsafe { level_4_table(address_space_frame(space)) };
+
+    let mut result = Ok(());
+    for (index, (entry, kernel_entry)) in table.iter().zip(kernel_table.iter()).enumerate() {
+        if kernel_entry.is_unused() && !entry.is_unused() {
+            let start = (index as u64) << 39;
+            result = unsafe { share_page_table(entry.addr(), 3, start, &mut clone_table, &mut *frame_allocator) };
+            if result.is_err() {
+                break;
+            }
+        }
+    }
+
+    // Pages of `space` that were writable aren't anymore
+    if is_active(space) {
+        tlb::flush_all();
+    }
+
+    let mut reservations = RESERVATIONS.lock();
+    let cloned: Vec<Reservation> = reservations.iter()
+        .filter(|reservation| reservation.space == space)
+        .map(|reservation| Reservation { space: clone, ..*reservation })
+        .collect();
+    reservations.extend(cloned);
+
+    result
+}
+
+/// Map everything mapped through the page table at `table_address` of the
+/// given `level` (1 to 3), which starts at virtual address `start`, into
+/// `clone` as well. Writable pages become copy-on-write in both.
+///
+/// Unsafe because the table must belong to the lower half of an address space.
+unsafe fn share_page_table(table_address: PhysAddr, level: u8, start: u64, clone: &mut OffsetPageTable, frame_allocator: &mut BuddyFrameAllocator) -> Result<(), MemoryError> {
+    let table = level_4_table(PhysFrame::containing_address(table_address)); // Every level has the same layout
+
+    for (index, entry) in table.iter_mut().enumerate().filter(|(_, entry)| !entry.is_unused()) {
+        let address = start + ((index as u64) << (12 + 9 * (level as u64 - 1)));
+
+        if level > 1 {
+            // User memory is only ever mapped with 4 KiB pages
+            if !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                share_page_table(entry.addr(), level - 1, address, clone, frame_allocator)?;
+            }
+            continue;
+        }
+
+        let frame = PhysFrame::containing_address(entry.addr());
+        let mut flags = entry.flags();
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            entry.set_flags(flags);
+        }
+
+        if !frame_allocator.share(frame) {
+            return Err(MemoryError::OutOfMemory);
+        }
+
+        let page = Page::containing_address(VirtAddr::new(address));
+        match clone.map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, frame_allocator) {
+            Ok(flush) => flush.ignore(),
+            Err(error) => {
+                frame_allocator.release(frame);
+                return Err(map_error(error));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Forget the reservations of `space` in `[start, end)`, splitting the ones
+/// that only partially overlap. The caller holds the [`FRAME_ALLOCATOR`] lock.
+fn unreserve(space: AddressSpace, start: usize, end: usize) {
+    let mut reservations = RESERVATIONS.lock();
+    let mut kept = Vec::with_capacity(reservations.len() + 1);
+
+    for reservation in reservations.drain(..) {
+        if reservation.space != space || reservation.end <= start || end <= reservation.start {
+            kept.push(reservation);
+            continue;
+        }
+
+        if reservation.start < start {
+            kept.push(Reservation { end: start, ..reservation });
+        }
+        if end < reservation.end {
+            kept.push(Reservation { start: end, ..reservation });
+        }
+    }
+
+    *reservations = kept;
+}
+
+/// Free the page table at `table_address` of the given `level` (1 to 3),
+/// everything mapped through it, and all tables below it.
+///
+/// Unsafe because nothing may use the table anymore.
+unsafe fn free_page_table(table_address: PhysAddr, level: u8, frame_allocator: &mut BuddyFrameAllocator) {
+    let table_frame = PhysFrame::containing_address(table_address);
+    let table = level_4_table(table_frame); // Every level has the same layout
+
+    for entry in table.iter().filter(|entry| !entry.is_unused()) {
+        if level == 1 {
+            frame_allocator.release(PhysFrame::containing_address(entry.addr()));
+        } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            // The kernel only maps 4 KiB pages itself, but whatever else
+            // ends up in user memory has to be freed as well
+            let first = PhysFrame::containing_address(entry.addr());
+            for frame in PhysFrame::range(first, first + (1 << (9 * (level as u64 - 1)))) {
+                frame_allocator.release(frame);
+            }
+        } else {
+            free_page_table(entry.addr(), level - 1, frame_allocator);
+        }
+    }
+
+    frame_allocator.deallocate_frame(table_frame);
+}
+
+/// Returns the level 4 table stored in `frame`.
+///
+/// Unsafe because the caller has to make sure no other reference to the same
+/// table exists, in practice by holding the [`FRAME_ALLOCATOR`] lock.
+unsafe fn level_4_table(frame: PhysFrame) -> &'static mut PageTable {
+    let virt = physical_memory_offset() + frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr()
+}
+
+/// Same as [`level_4_table`], but for an [`AddressSpace`] handle (which holds
+/// the physical address of its level 4 table).
+unsafe fn address_space_page_table(space: AddressSpace) -> OffsetPageTable<'static> {
+    OffsetPageTable::new(level_4_table(address_space_frame(space)), physical_memory_offset())
+}
+
+fn is_active(space: AddressSpace) -> bool {
+    active_address_space() == space
+}
+
+fn active_address_space() -> AddressSpace {
+    AddressSpace(Cr3::read().0.start_address().as_u64() as usize)
+}
+
+/// The pages covering `[address, address + size)`, which has to be page
+/// aligned and in the lower half.
+fn user_pages(address: usize, size: usize) -> Result<impl Iterator<Item = Page>, MemoryError> {
+    let page_size = Size4KiB::SIZE as usize;
+    let end = address.checked_add(size).ok_or(MemoryError::InvalidAddress)?;
+    let lower_half_end = HIGHER_HALF_P4_INDEX << 39;
+    if address % page_size != 0 || size % page_size != 0 || size == 0 || end > lower_half_end {
+        return Err(MemoryError::InvalidAddress);
+    }
+
+    let start = Page::<Size4KiB>::containing_address(VirtAddr::new(address as u64));
+    let end = Page::<Size4KiB>::containing_address(VirtAddr::new((end - 1) as u64));
+    Ok(Page::range_inclusive(start, end))
 }
 
 /// Initialize a new OffsetPageTable.
@@ -61,49 +865,301 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryRegions,
-    next: usize,
+/// Largest block handed out at once is 2^MAX_ORDER frames (4 MiB)
+const MAX_ORDER: usize = 10;
+
+/// Memory below this is never handed out, even when the memory map says it
+/// is usable: the real mode IVT and BIOS data area are in the first page,
+/// and firmware tends to leave things up to where boot sectors are loaded.
+const LOW_MEMORY_END: u64 = 0x7000;
+
+/// Entry in [`BuddyFrameAllocator::orders`] of frames that don't start a free block
+const NOT_FREE: u8 = u8::MAX;
+
+/// Links between free blocks of the same order, kept in their first frame.
+struct FreeBlock {
+    previous: Option<usize>,
+    next: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCounts {
+    pub free: usize,
+    pub used: usize,
+}
+
+/// Buddy allocator for the usable frames in the bootloader's memory map.
+///
+/// Free memory is split into blocks of 2^order frames, one list per order.
+/// Allocating splits a larger block when needed, freeing merges a block with
+/// its buddy (the other half of the block they were split from) whenever
+/// that one is free too. Both take at most [`MAX_ORDER`] steps.
+///
+/// Frames are referred to by their number (address / 4096), the lists are
+/// stored in the free frames themselves (through the physical memory mapping).
+pub struct BuddyFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    orders: &'static mut [u8], // Per frame: order of the free block it starts, or NOT_FREE
+    shared: &'static mut [u8], // Per frame: how many more address spaces map it (copy-on-write)
+    free_lists: [Option<usize>; MAX_ORDER + 1],
+    total: usize,
+    free: usize,
 }
 
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
+impl BuddyFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map, its bookkeeping
+    /// (two bytes per frame) is put in the first usable region that fits it.
+    /// Skips everything below [`LOW_MEMORY_END`].
     ///
     /// This function is unsafe because the caller must guarantee that the passed
     /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn new(memory_map: &'static MemoryRegions) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+    /// as `USABLE` in it are really unused, and mapped at `physical_memory_offset`.
+    pub unsafe fn new(memory_map: &'static MemoryRegions, physical_memory_offset: VirtAddr) -> Self {
+        let usable_frames = memory_map.iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(|region| (frame_number_up(region.start.max(LOW_MEMORY_END)), region.end / Size4KiB::SIZE));
+
+        Self::from_frames(usable_frames, physical_memory_offset)
+    }
+
+    /// Same as [`BuddyFrameAllocator::new`], with the usable memory given as
+    /// `(start, end)` frame numbers.
+    pub unsafe fn from_frames(usable_frames: impl Iterator<Item = (u64, u64)> + Clone, physical_memory_offset: VirtAddr) -> Self {
+        let usable_frames = || usable_frames.clone().filter(|(start, end)| start < end);
+
+        let frame_count = usable_frames().map(|(_, end)| end).max().unwrap_or(0) as usize;
+        let bookkeeping_frames = (2 * frame_count as u64 + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+        let (bookkeeping_start, _) = usable_frames()
+            .find(|(start, end)| end - start >= bookkeeping_frames)
+            .expect("No room for the frame allocator");
+
+        let bookkeeping = (physical_memory_offset + bookkeeping_start * Size4KiB::SIZE).as_mut_ptr::<u8>();
+        let orders = core::slice::from_raw_parts_mut(bookkeeping, frame_count);
+        let shared = core::slice::from_raw_parts_mut(bookkeeping.add(frame_count), frame_count);
+        orders.fill(NOT_FREE);
+        shared.fill(0);
+
+        let mut allocator = BuddyFrameAllocator {
+            physical_memory_offset,
+            orders,
+            shared,
+            free_lists: [None; MAX_ORDER + 1],
+            total: 0,
+            free: 0,
+        };
+
+        let bookkeeping_end = bookkeeping_start + bookkeeping_frames;
+        for (start, end) in usable_frames() {
+            let (start, end) = (start as usize, end as usize);
+            if start == bookkeeping_start as usize {
+                allocator.free_range(bookkeeping_end as usize, end);
+            } else {
+                allocator.free_range(start, end);
+            }
+        }
+        allocator.total = allocator.free;
+
+        allocator
+    }
+
+    pub fn counts(&self) -> FrameCounts {
+        FrameCounts {
+            free: self.free,
+            used: self.total - self.free,
+        }
+    }
+
+    /// `count` physically contiguous frames (for DMA), the first one is
+    /// aligned to `count` rounded up to a power of two.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let order = count.checked_next_power_of_two()?.trailing_zeros() as usize;
+        if count == 0 || order > MAX_ORDER {
+            return None;
+        }
+
+        let frame = self.allocate_block(order)?;
+        self.free_range(frame + count, frame + (1 << order)); // Give back what wasn't asked for
+
+        Some(frame_at(frame))
+    }
+
+    /// Free frames allocated by [`BuddyFrameAllocator::allocate_contiguous`].
+    ///
+    /// Unsafe because the frames must not be used anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let start = number_of(start);
+        self.free_range(start, start + count);
+    }
+
+    /// The lowest free frame below physical address `limit`, what remains of
+    /// the block it is taken from is freed again.
+    fn allocate_below(&mut self, limit: u64) -> Option<PhysFrame> {
+        let limit = (limit / Size4KiB::SIZE) as usize;
+        let (frame, order) = (0..=MAX_ORDER)
+            .flat_map(|order| self.free_blocks(order).map(move |frame| (frame, order)))
+            .filter(|&(frame, _)| frame < limit)
+            .min()?;
+
+        self.remove(frame, order);
+        self.free -= 1 << order;
+        self.free_range(frame + 1, frame + (1 << order));
+        Some(frame_at(frame))
+    }
+
+    /// The first frames of the free blocks of `order`.
+    fn free_blocks(&self, order: usize) -> impl Iterator<Item = usize> + '_ {
+        let mut next = self.free_lists[order];
+        core::iter::from_fn(move || {
+            let frame = next?;
+            next = unsafe { (*self.block(frame)).next };
+            Some(frame)
+        })
+    }
+
+    /// Count another mapping of `frame`, false when it has too many already.
+    fn share(&mut self, frame: PhysFrame) -> bool {
+        let shared = &mut self.shared[number_of(frame)];
+        match shared.checked_add(1) {
+            Some(count) => {
+                *shared = count;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn is_shared(&self, frame: PhysFrame) -> bool {
+        self.shared[number_of(frame)] > 0
+    }
+
+    /// Drop a mapping of `frame`, it is freed when that was the last one.
+    ///
+    /// Unsafe because the mapping must not be used anymore.
+    unsafe fn release(&mut self, frame: PhysFrame) {
+        let number = number_of(frame);
+        if self.shared[number] > 0 {
+            self.shared[number] -= 1;
+        } else {
+            self.deallocate_frame(frame);
         }
     }
 
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions
-            .filter(|r| r.kind == MemoryRegionKind::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions
-            .map(|r| r.start..r.end);
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    fn allocate_block(&mut self, order: usize) -> Option<usize> {
+        let found = (order..=MAX_ORDER).find(|&order| self.free_lists[order].is_some())?;
+        let frame = self.free_lists[found]?;
+        self.remove(frame, found);
+
+        // Put the halves that aren't needed back, from large to small
+        for order in (order..found).rev() {
+            self.push(frame + (1 << order), order);
+        }
+
+        self.free -= 1 << order;
+        Some(frame)
+    }
+
+    /// Free `[start, end)` as the largest aligned blocks that fit.
+    fn free_range(&mut self, start: usize, end: usize) {
+        let mut frame = start;
+        while frame < end {
+            let mut order = (frame.trailing_zeros() as usize).min(MAX_ORDER);
+            while frame + (1 << order) > end {
+                order -= 1;
+            }
+
+            self.free_block(frame, order);
+            frame += 1 << order;
+        }
+    }
+
+    fn free_block(&mut self, mut frame: usize, mut order: usize) {
+        // Freeing it again would put it in a list twice, and hand it out twice
+        assert!(!self.is_free(frame), "Frame {:#x} freed twice", frame);
+        self.free += 1 << order;
+
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if buddy >= self.orders.len() || self.orders[buddy] != order as u8 {
+                break;
+            }
+
+            self.remove(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
+        }
+
+        self.push(frame, order);
+    }
+
+    /// Whether `frame` is part of a free block, of any order.
+    fn is_free(&self, frame: usize) -> bool {
+        (0..=MAX_ORDER).any(|order| {
+            let start = frame & !((1 << order) - 1);
+            self.orders.get(start) == Some(&(order as u8))
+        })
+    }
+
+    fn push(&mut self, frame: usize, order: usize) {
+        let next = self.free_lists[order];
+        unsafe {
+            self.block(frame).write(FreeBlock { previous: None, next });
+            if let Some(next) = next {
+                (*self.block(next)).previous = Some(frame);
+            }
+        }
+
+        self.orders[frame] = order as u8;
+        self.free_lists[order] = Some(frame);
+    }
+
+    fn remove(&mut self, frame: usize, order: usize) {
+        unsafe {
+            let FreeBlock { previous, next } = self.block(frame).read();
+            match previous {
+                Some(previous) => (*self.block(previous)).next = next,
+                None => self.free_lists[order] = next,
+            }
+            if let Some(next) = next {
+                (*self.block(next)).previous = previous;
+            }
+        }
+
+        self.orders[frame] = NOT_FREE;
+    }
+
+    /// The links stored in free `frame`.
+    fn block(&self, frame: usize) -> *mut FreeBlock {
+        (self.physical_memory_offset + frame as u64 * Size4KiB::SIZE).as_mut_ptr()
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+// The bookkeeping is only reachable through the allocator
+unsafe impl Send for BuddyFrameAllocator {}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        self.allocate_block(0).map(frame_at)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.free_block(number_of(frame), 0);
     }
 }
 
+fn frame_number_up(address: u64) -> u64 {
+    (address + Size4KiB::SIZE - 1) / Size4KiB::SIZE
+}
+
+fn frame_at(number: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(number as u64 * Size4KiB::SIZE))
+}
+
+fn number_of(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / Size4KiB::SIZE) as usize
+}
+
 pub fn init_heap<M, A>(mapper: &mut M, frame_allocator: &mut A) -> Result<(), MapToError<Size4KiB>>
 where
     M: Mapper<Size4KiB>,
@@ -111,7 +1167,7 @@ where
 {
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_end = heap_start + HEAP_INITIAL_SIZE - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
@@ -126,7 +1182,7 @@ where
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(HEAP_START, HEAP_INITIAL_SIZE);
     }
 
     Ok(())
@@ -134,29 +1190,100 @@ where
 
 
 ////////////////////////////////////////////////////////////////////////////////
-// FixedSizeBlockAllocator                                                    //
+// SlabAllocator                                                              //
 ////////////////////////////////////////////////////////////////////////////////
 
-fn list_index(layout: &Layout) -> Option<usize> {
+/// Index into [`BLOCK_SIZES`] of the smallest block `layout` fits in.
+pub(crate) fn size_class(layout: &Layout) -> Option<usize> {
     let required_block_size = layout.size().max(layout.align());
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
-struct ListNode {
-    next: Option<&'static mut ListNode>,
+/// Memory taken by a slab of `block_size` blocks. Slabs are aligned to their
+/// size, so rounding down the address of a block gives its slab.
+const fn slab_size(block_size: usize) -> usize {
+    let size = block_size * 8;
+    if size > 4096 { size } else { 4096 }
+}
+
+/// Slabs of size class `index` are aligned to their size.
+fn slab_layout(index: usize) -> Layout {
+    let size = slab_size(BLOCK_SIZES[index]);
+    Layout::from_size_align(size, size).unwrap()
+}
+
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+/// Start of every slab, the blocks come after it.
+struct Slab {
+    previous: Option<NonNull<Slab>>, // Only linked while there are free blocks
+    next: Option<NonNull<Slab>>,
+    free: Option<NonNull<FreeSlot>>,
+    used: usize,
+}
+
+#[derive(Clone, Copy)]
+struct SizeClass {
+    partial: Option<NonNull<Slab>>, // Slabs with at least one free block
+    blocks_in_use: usize,
+    slabs: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    pub bytes_in_use: usize,
+    pub slabs: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AllocatorStats {
+    pub size_classes: [SizeClassStats; BLOCK_SIZES.len()],
+    pub fallback_bytes_in_use: usize, // Allocations too large for any size class
+    pub heap_size: usize,
+    pub heap_used: usize,             // Slabs included
+    pub high_water_mark: usize,       // Most bytes ever in use at once
+    pub failed_allocations: usize,
+}
+
+impl fmt::Display for AllocatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Heap: {} of {} bytes used, {} failed allocations", self.heap_used, self.heap_size, self.failed_allocations)?;
+        writeln!(f, "Most in use at once: {} bytes", self.high_water_mark)?;
+        for class in self.size_classes.iter() {
+            writeln!(f, "  {:>5} bytes: {} bytes in {} slabs", class.block_size, class.bytes_in_use, class.slabs)?;
+        }
+        write!(f, "  Fallback: {} bytes", self.fallback_bytes_in_use)
+    }
 }
 
-pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+/// Small allocations are served from slabs, pages of equally sized blocks
+/// that go back to the fallback allocator as soon as they are empty. Anything
+/// larger than the biggest [`BLOCK_SIZES`] entry goes to the fallback directly.
+pub struct SlabAllocator {
+    classes: [SizeClass; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    fallback_in_use: usize,
+    in_use: usize,
+    high_water_mark: usize,
+    failed_allocations: usize,
 }
 
-impl FixedSizeBlockAllocator {
-    pub const fn new() -> FixedSizeBlockAllocator {
-        const EMPTY: Option<&'static mut ListNode> = None;
-        FixedSizeBlockAllocator {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
+// The slabs are only reachable through the allocator
+unsafe impl Send for SlabAllocator {}
+
+impl SlabAllocator {
+    pub const fn new() -> SlabAllocator {
+        const EMPTY: SizeClass = SizeClass { partial: None, blocks_in_use: 0, slabs: 0 };
+        SlabAllocator {
+            classes: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            fallback_in_use: 0,
+            in_use: 0,
+            high_water_mark: 0,
+            failed_allocations: 0,
         }
     }
 
@@ -164,6 +1291,156 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
+    pub fn stats(&self) -> AllocatorStats {
+        let mut size_classes = [SizeClassStats { block_size: 0, bytes_in_use: 0, slabs: 0 }; BLOCK_SIZES.len()];
+        for ((stats, class), &block_size) in size_classes.iter_mut().zip(self.classes.iter()).zip(BLOCK_SIZES) {
+            *stats = SizeClassStats {
+                block_size,
+                bytes_in_use: class.blocks_in_use * block_size,
+                slabs: class.slabs,
+            };
+        }
+
+        AllocatorStats {
+            size_classes,
+            fallback_bytes_in_use: self.fallback_in_use,
+            heap_size: self.fallback_allocator.size(),
+            heap_used: self.fallback_allocator.used(),
+            high_water_mark: self.high_water_mark,
+            failed_allocations: self.failed_allocations,
+        }
+    }
+
+    /// Null when the heap is full, [`grow_heap`] can't run with the heap
+    /// locked.
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (ptr, size) = match size_class(&layout) {
+            Some(index) => (self.allocate_block(index), BLOCK_SIZES[index]),
+            None => (self.fallback_alloc(layout), layout.size()),
+        };
+
+        if !ptr.is_null() {
+            if size_class(&layout).is_none() {
+                self.fallback_in_use += size;
+            }
+            self.in_use += size;
+            self.high_water_mark = self.high_water_mark.max(self.in_use);
+        }
+
+        ptr
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match size_class(&layout) {
+            Some(index) => {
+                self.deallocate_block(ptr, index);
+                self.in_use -= BLOCK_SIZES[index];
+            }
+            None => {
+                self.fallback_allocator.deallocate(NonNull::new_unchecked(ptr), layout);
+                self.fallback_in_use -= layout.size();
+                self.in_use -= layout.size();
+            }
+        }
+    }
+
+    fn allocate_block(&mut self, index: usize) -> *mut u8 {
+        let slab = match self.classes[index].partial {
+            Some(slab) => slab,
+            None => match self.new_slab(index) {
+                Some(slab) => slab,
+                None => return ptr::null_mut(),
+            },
+        };
+
+        unsafe {
+            let slab_ref = &mut *slab.as_ptr();
+            let block = slab_ref.free.expect("Partial slab without free blocks");
+            slab_ref.free = block.as_ref().next;
+            slab_ref.used += 1;
+
+            if slab_ref.free.is_none() {
+                self.unlink(index, slab);
+            }
+
+            self.classes[index].blocks_in_use += 1;
+            block.as_ptr() as *mut u8
+        }
+    }
+
+    unsafe fn deallocate_block(&mut self, ptr: *mut u8, index: usize) {
+        let size = slab_size(BLOCK_SIZES[index]);
+        let slab = NonNull::new_unchecked((ptr as usize & !(size - 1)) as *mut Slab);
+        let slab_ref = &mut *slab.as_ptr();
+        let was_full = slab_ref.free.is_none();
+
+        let block = ptr as *mut FreeSlot;
+        block.write(FreeSlot { next: slab_ref.free });
+        slab_ref.free = NonNull::new(block);
+        slab_ref.used -= 1;
+        self.classes[index].blocks_in_use -= 1;
+
+        if slab_ref.used == 0 {
+            // Empty, give the memory back so any size can use it again
+            if !was_full {
+                self.unlink(index, slab);
+            }
+            self.fallback_allocator.deallocate(slab.cast(), Layout::from_size_align_unchecked(size, size));
+            self.classes[index].slabs -= 1;
+        } else if was_full {
+            self.link(index, slab);
+        }
+    }
+
+    /// Carve a slab for size class `index` out of the fallback allocator, and
+    /// add it to the partial list.
+    fn new_slab(&mut self, index: usize) -> Option<NonNull<Slab>> {
+        let block_size = BLOCK_SIZES[index];
+        let size = slab_size(block_size);
+        let slab = NonNull::new(self.fallback_alloc(slab_layout(index)) as *mut Slab)?;
+
+        // Blocks are aligned to their size, the first ones may be taken by the header
+        let first_block = (mem::size_of::<Slab>() + block_size - 1) & !(block_size - 1);
+        let mut free = None;
+        for offset in (first_block..size).step_by(block_size).rev() {
+            let block = (slab.as_ptr() as usize + offset) as *mut FreeSlot;
+            unsafe { block.write(FreeSlot { next: free }) };
+            free = NonNull::new(block);
+        }
+
+        unsafe { slab.as_ptr().write(Slab { previous: None, next: None, free, used: 0 }) };
+        self.classes[index].slabs += 1;
+        self.link(index, slab);
+
+        Some(slab)
+    }
+
+    fn link(&mut self, index: usize, slab: NonNull<Slab>) {
+        let next = self.classes[index].partial;
+        unsafe {
+            let slab_ref = &mut *slab.as_ptr();
+            slab_ref.previous = None;
+            slab_ref.next = next;
+            if let Some(next) = next {
+                (*next.as_ptr()).previous = Some(slab);
+            }
+        }
+        self.classes[index].partial = Some(slab);
+    }
+
+    fn unlink(&mut self, index: usize, slab: NonNull<Slab>) {
+        unsafe {
+            let Slab { previous, next, .. } = *slab.as_ptr();
+            match previous {
+                Some(previous) => (*previous.as_ptr()).next = next,
+                None => self.classes[index].partial = next,
+            }
+            if let Some(next) = next {
+                (*next.as_ptr()).previous = previous;
+            }
+        }
+    }
+
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
@@ -172,46 +1449,111 @@ impl FixedSizeBlockAllocator {
     }
 }
 
-unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                match allocator.list_heads[index].take() {
-                    Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    }
-                    None => { // If no block of the requested size exists, create it
-                        let block_size = BLOCK_SIZES[index];
-                        let block_align = block_size; // Only works for powers of two
-                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        allocator.fallback_alloc(layout)
-                    }
-                }
-            }
-            None => {
-                allocator.fallback_alloc(layout)
+impl Locked<SlabAllocator> {
+    /// Allocate, mapping more memory for the heap when it is full.
+    fn allocate_or_grow(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.lock().allocate(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+
+        // What the fallback allocator has to fit for this
+        let needed = size_class(&layout).map_or(layout, slab_layout);
+        if grow_heap(self, needed).is_ok() {
+            let ptr = self.lock().allocate(layout);
+            if !ptr.is_null() {
+                return ptr;
             }
         }
+
+        self.lock().failed_allocations += 1;
+        ptr::null_mut()
     }
+}
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
+/// Map enough memory at the top of `heap` to fit `layout`, and at least
+/// [`HEAP_GROWTH_MIN`], without going past [`HEAP_MAX_SIZE`].
+///
+/// Runs without holding the heap lock, but locks the frame allocator first:
+/// page tables are changed with the frame allocator locked, and that may
+/// allocate.
+fn grow_heap(heap: &Locked<SlabAllocator>, layout: Layout) -> Result<(), MemoryError> {
+    let size = (layout.size() + layout.align())
+        .max(HEAP_GROWTH_MIN)
+        .checked_add(Size4KiB::SIZE as usize - 1)
+        .ok_or(MemoryError::OutOfMemory)?
+        & !(Size4KiB::SIZE as usize - 1);
 
-                let new_node_ptr = ptr as *mut ListNode;
-                new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
-            }
+    // Not there yet during boot. When this processor holds it, it is changing
+    // page tables and allocated while doing so.
+    let frame_allocator = FRAME_ALLOCATOR.get().ok_or(MemoryError::OutOfMemory)?;
+    if frame_allocator.is_held_here() {
+        return Err(MemoryError::OutOfMemory);
+    }
+    let mut frame_allocator = frame_allocator.lock();
+
+    // Only ever grows with the frame allocator locked, so it stays there
+    let top = heap.lock().fallback_allocator.top();
+    let end = top.checked_add(size).ok_or(MemoryError::OutOfMemory)?;
+    if end > HEAP_START + HEAP_MAX_SIZE {
+        return Err(MemoryError::OutOfMemory);
+    }
+
+    // Kernel page tables are shared, mapping through the current level 4
+    // table shows up in every address space
+    let mut page_table = unsafe { new_page_table(physical_memory_offset()) };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let mut mapped = 0;
+    let mut result = Ok(());
+    for page in Page::<Size4KiB>::range(Page::containing_address(VirtAddr::new(top as u64)), Page::containing_address(VirtAddr::new(end as u64))) {
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
             None => {
-                let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_allocator.deallocate(ptr, layout);
+                result = Err(MemoryError::OutOfMemory);
+                break;
+            }
+        };
+
+        match unsafe { page_table.map_to(page, frame, flags, &mut *frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                result = Err(MemoryError::OutOfMemory);
+                break;
             }
         }
+
+        mapped += Size4KiB::SIZE as usize;
+    }
+
+    // Whatever did get mapped is still usable
+    unsafe { heap.lock().fallback_allocator.extend(mapped) };
+    result
+}
+
+unsafe impl GlobalAlloc for Locked<SlabAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "heap-debug")]
+        {
+            let (inner, front) = match heap_debug::inner_layout(layout) {
+                Some(inner) => inner,
+                None => return ptr::null_mut(),
+            };
+
+            let ptr = self.allocate_or_grow(inner);
+            return if ptr.is_null() { ptr } else { heap_debug::guard(ptr, layout, front) };
+        }
+
+        #[cfg(not(feature = "heap-debug"))]
+        self.allocate_or_grow(layout)
     }
-}
\ No newline at end of file
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Checked before locking, reporting a problem panics
+        #[cfg(feature = "heap-debug")]
+        let (ptr, layout) = heap_debug::check(ptr, layout);
+
+        self.lock().deallocate(ptr, layout)
+    }
+}
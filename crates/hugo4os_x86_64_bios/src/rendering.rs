@@ -1,7 +1,12 @@
 use bootloader::boot_info;
 
 use hugo4os::kernel::abstractions::rendering::{self as abstractions, PixelFormat};
+use spin::Once;
 
+/// The framebuffer given to the kernel, for the panic handler to take over.
+pub static PANIC_FRAMEBUFFER: Once<FrameBuffer> = Once::new();
+
+#[derive(Clone, Copy)]
 pub struct FrameBuffer {
     info: abstractions::FrameBufferInfo,
     start_address: u64,
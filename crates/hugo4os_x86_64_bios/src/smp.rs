@@ -0,0 +1,211 @@
+//! Starting the other processors (application processors) the MADT lists.
+//!
+//! A processor starts in real mode after an INIT and a startup IPI, at the
+//! start of a page below 1 MiB. `asm/ap_trampoline.s` is copied to such a
+//! page and switches straight to long mode with a temporary address space,
+//! which is the kernel's plus that page at its own physical address. Then
+//! [`ap_entry`] moves to the kernel's address space and sets up everything a
+//! processor has its own copy of. Processors are started one at a time, they
+//! share the page.
+
+use core::arch::global_asm;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+use alloc::boxed::Box;
+use hugo4os::constants::MAXIMUM_CPUS;
+use hugo4os::kernel::{acpi, cpu::{CpuLocal, Cpus}, memory::{AddressSpace, MemoryManager}};
+use spin::Once;
+use x86_64::registers::control::{Cr0, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+
+use crate::{apic, cpu::{self, Cpu}, gdt, interrupts, memory, println, syscall, X86_64};
+
+global_asm!(include_str!("asm/ap_trampoline.s"));
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_parameters: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Filled in at `ap_trampoline_parameters` in the copy of the trampoline.
+#[repr(C)]
+struct Parameters {
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    stack: u64,
+    entry: u64,
+    argument: u64,
+}
+
+/// How long a processor gets to reach [`ap_entry`], in milliseconds.
+const STARTUP_TIMEOUT_MS: u32 = 100;
+
+/// Local APIC id of each started processor, by index.
+static APIC_IDS: [AtomicU32; MAXIMUM_CPUS] = {
+    const UNKNOWN: AtomicU32 = AtomicU32::new(0);
+    [UNKNOWN; MAXIMUM_CPUS]
+};
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Set by the processor being started once it no longer needs the trampoline.
+static STARTED: AtomicBool = AtomicBool::new(false);
+static ENTRY: Once<fn() -> !> = Once::new();
+
+/// Set by [`Cpus::stop_others`], the processors halt on the NMI it sends.
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+impl Cpus for X86_64 {
+    fn start_others(entry: fn() -> !) -> usize {
+        ENTRY.call_once(|| entry);
+
+        let (local_apic, madt) = match (interrupts::local_apic(), acpi::get().and_then(|acpi| acpi.madt.as_ref())) {
+            (Some(local_apic), Some(madt)) => (local_apic, madt),
+            _ => return 1,
+        };
+
+        let own_id = local_apic.id();
+        APIC_IDS[0].store(own_id, Ordering::Relaxed);
+
+        let others = madt.usable_processors().filter(|processor| processor.apic_id != own_id);
+        if let Err(error) = start(local_apic, others.map(|processor| processor.apic_id)) {
+            println!("Not starting the other processors: {}", error);
+        }
+
+        CPU_COUNT.load(Ordering::Acquire)
+    }
+
+    fn current() -> usize {
+        cpu::current().index
+    }
+
+    fn local() -> &'static CpuLocal {
+        &cpu::current().local
+    }
+
+    fn stop_others() {
+        // Whoever calls this second is stopped by the first
+        if STOPPING.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if let Some(local_apic) = interrupts::local_apic() {
+            let own_id = local_apic.id();
+            let count = CPU_COUNT.load(Ordering::Acquire);
+            for apic_id in APIC_IDS[..count].iter().map(|id| id.load(Ordering::Relaxed)) {
+                if apic_id != own_id {
+                    local_apic.send_nmi(apic_id);
+                }
+            }
+        }
+    }
+
+    fn wake(index: usize) {
+        // Interrupt handlers wake tasks without swapping GS, so this can't
+        // use `cpu::current`
+        if let Some(local_apic) = interrupts::local_apic() {
+            let apic_id = APIC_IDS[index].load(Ordering::Relaxed);
+            if apic_id != local_apic.id() {
+                local_apic.send_ipi(apic_id, interrupts::InterruptIndex::Wakeup as u8);
+            }
+        }
+    }
+}
+
+fn start(local_apic: &apic::LocalApic, apic_ids: impl Iterator<Item = u32>) -> Result<(), &'static str> {
+    let frame = memory::allocate_frame_below(0x100000).ok_or("no free page below 1 MiB")?;
+    let space = X86_64::create_address_space().map_err(|_| "out of memory")?;
+
+    // The trampoline only loads the lower half of CR3
+    if space.0 > u32::MAX as usize || memory::identity_map(space, frame).is_err() {
+        let _ = X86_64::destroy_address_space(space);
+        return Err("can't map the trampoline");
+    }
+
+    let page = X86_64::map_physical(frame.start_address().as_u64() as usize, 4096).map_err(|_| "can't map the trampoline")?;
+    unsafe { copy_trampoline(page, space) };
+
+    for apic_id in apic_ids {
+        let index = CPU_COUNT.load(Ordering::Relaxed);
+        if index == MAXIMUM_CPUS {
+            break;
+        }
+
+        APIC_IDS[index].store(apic_id, Ordering::Relaxed);
+        STARTED.store(false, Ordering::Relaxed);
+        unsafe {
+            let parameters = parameters(page);
+            (*parameters).stack = gdt::allocate_stack().as_u64();
+            (*parameters).argument = index as u64;
+        }
+
+        local_apic.start_processor(apic_id, (frame.start_address().as_u64() >> 12) as u8);
+
+        let mut waited = 0;
+        while !STARTED.load(Ordering::Acquire) && waited < STARTUP_TIMEOUT_MS {
+            unsafe { apic::pit_wait(1) };
+            waited += 1;
+        }
+
+        if !STARTED.load(Ordering::Acquire) {
+            // It may still get to the trampoline later, and would share the
+            // next one's stack
+            local_apic.reset_processor(apic_id);
+            println!("Processor with APIC id {} didn't start, skipping it", apic_id);
+            continue;
+        }
+        CPU_COUNT.store(index + 1, Ordering::Release);
+    }
+
+    // Frees the trampoline page too
+    let _ = X86_64::destroy_address_space(space);
+    Ok(())
+}
+
+/// Copy the trampoline to `page` and fill in the parameters every processor
+/// shares.
+unsafe fn copy_trampoline(page: usize, space: AddressSpace) {
+    let start = &ap_trampoline_start as *const u8;
+    let length = &ap_trampoline_end as *const u8 as usize - start as usize;
+    ptr::copy_nonoverlapping(start, page as *mut u8, length);
+
+    // Everything the processor that booted has turned on, except what only
+    // works once long mode is active
+    let parameters = parameters(page);
+    (*parameters).cr0 = Cr0::read_raw();
+    (*parameters).cr3 = space.0 as u64;
+    (*parameters).cr4 = (Cr4::read() - Cr4Flags::PCID).bits();
+    (*parameters).efer = (Efer::read() - EferFlags::LONG_MODE_ACTIVE).bits();
+    (*parameters).entry = ap_entry as usize as u64;
+}
+
+/// Whether an NMI is [`Cpus::stop_others`] halting this processor.
+pub fn is_stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+unsafe fn parameters(page: usize) -> *mut Parameters {
+    let offset = &ap_trampoline_parameters as *const u8 as usize - &ap_trampoline_start as *const u8 as usize;
+    (page + offset) as *mut Parameters
+}
+
+/// Called by the trampoline on its own stack, with interrupts disabled.
+extern "C" fn ap_entry(index: usize) -> ! {
+    unsafe { X86_64::switch_address_space(X86_64::kernel_address_space()) };
+
+    let tss = gdt::init_other();
+    let cpu = Box::leak(Box::new(Cpu::new(index, tss)));
+    unsafe { cpu::init(cpu) };
+    syscall::init();
+
+    // Checked by `start_others` before starting this one
+    let madt = acpi::get().and_then(|acpi| acpi.madt.as_ref()).unwrap();
+    interrupts::init_other(madt).expect("Enabling the local APIC failed");
+
+    STARTED.store(true, Ordering::Release);
+    interrupts::enable();
+    (ENTRY.wait())()
+}
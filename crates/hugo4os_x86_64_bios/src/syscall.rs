@@ -0,0 +1,245 @@
+//! x86_64 syscall entry points.
+//!
+//! There are two ways into the kernel, both use the same register convention
+//! (see [`hugo4os_syscall::syscall!`]) and end up in [`syscall_handler`]:
+//!
+//! - `syscall`, the fast path. The CPU jumps to the address in LSTAR without
+//!   touching the stack, so [`syscall_entry`] switches to the kernel stack
+//!   (the same one the TSS provides for ring 3 interrupts, kept in the
+//!   per-CPU data, see `cpu.rs`) and returns with `sysretq`.
+//! - `int 0x80`, kept for compatibility. Goes through the IDT like any other
+//!   interrupt and returns with `iretq`.
+
+use core::arch::asm;
+
+use hugo4os::kernel::{self, abstractions};
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+
+use crate::gdt;
+
+/// Register frame pushed by both trampolines, the fields are in the order
+/// they end up on the stack (lowest address first).
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallRegs {
+    pub rax: u64, // Syscall id on entry, result on return
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rcx: u64, // Return address when entered through `syscall`
+    pub r11: u64, // RFLAGS when entered through `syscall`
+}
+
+impl Into<abstractions::interrupts::InputSyscall> for SyscallRegs {
+    fn into(self) -> abstractions::interrupts::InputSyscall {
+        abstractions::interrupts::InputSyscall {
+            id: self.rax,
+            args: [
+                self.rdi,
+                self.rsi,
+                self.rdx,
+                self.r10,
+                self.r8,
+                self.r9,
+                self.r12,
+                self.r13,
+                self.r14,
+                self.r15,
+            ],
+        }
+    }
+}
+
+/// Enable `syscall`/`sysret` and point LSTAR at [`syscall_entry`] on this
+/// processor, must run after [`crate::cpu::init`].
+pub fn init() {
+    let selectors = gdt::selectors();
+
+    unsafe {
+        Star::write(
+            selectors.user_code_selector,
+            selectors.user_data_selector,
+            selectors.code_selector,
+            selectors.data_selector,
+        ).expect("Invalid GDT layout for syscall/sysret");
+
+        Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+    }
+
+    LStar::write(VirtAddr::new(syscall_entry as u64));
+
+    // Enter with interrupts disabled, like the `int 0x80` gate does, they are
+    // enabled again once the handler runs on the kernel stack
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG | RFlags::ALIGNMENT_CHECK);
+}
+
+/// Address of [`int80_entry`], for the IDT.
+pub fn int80_entry_addr() -> VirtAddr {
+    VirtAddr::new(int80_entry as u64)
+}
+
+/// Pushes a [`SyscallRegs`], calls [`syscall_handler`] with a pointer to it
+/// and the caller's RFLAGS (read from `$rflags` after pushing) and pops the
+/// (updated) registers again.
+macro_rules! call_handler {
+    ($rflags:literal) => {
+        concat!(
+            "push rcx\n",
+            "push r15\n",
+            "push r14\n",
+            "push r13\n",
+            "push r12\n",
+            "push r9\n",
+            "push r8\n",
+            "push r10\n",
+            "push rdx\n",
+            "push rsi\n",
+            "push rdi\n",
+            "push rax\n",
+            "mov rsi, ", $rflags, "\n",
+            "mov rdi, rsp\n",
+            "call {handler}\n",
+            "pop rax\n",
+            "pop rdi\n",
+            "pop rsi\n",
+            "pop rdx\n",
+            "pop r10\n",
+            "pop r8\n",
+            "pop r9\n",
+            "pop r12\n",
+            "pop r13\n",
+            "pop r14\n",
+            "pop r15\n",
+            "pop rcx\n",
+        )
+    };
+}
+
+/// Target of `syscall`, `rcx` holds the return address and `r11` the caller's
+/// RFLAGS. `r11` is pushed first so it ends up as [`SyscallRegs::r11`].
+///
+/// `gs:16` is scratch space of this processor only, and is free again once
+/// the caller's `rsp` is pushed. Nothing else can use it in between: SFMask
+/// keeps interrupts off, and exceptions there would be kernel bugs.
+#[naked]
+unsafe extern "C" fn syscall_entry() {
+    asm!(
+        "swapgs",
+        "mov gs:[16], rsp",     // Caller's rsp
+        "mov rsp, gs:[8]",      // Kernel stack top
+        "push qword ptr gs:[16]",
+        "push r11",
+        call_handler!("r11"),
+        "pop r11",
+        "pop rsp",
+        "swapgs",
+        "sysretq",
+        handler = sym syscall_handler,
+        options(noreturn)
+    );
+}
+
+/// Target of `int 0x80`. `rcx` and `r11` are saved too, so the frame has the
+/// same layout as the one built by [`syscall_entry`] (and the stack stays
+/// 16-byte aligned). Swaps GS when called from ring 3, like every entry.
+#[naked]
+unsafe extern "C" fn int80_entry() {
+    asm!(
+        "test byte ptr [rsp + 8], 3", // CS
+        "jz 2f",
+        "swapgs",
+        "2:",
+        "push r11",
+        call_handler!("[rsp + 120]"), // Interrupt frame RFLAGS, past 13 pushed registers, RIP and CS
+        "pop r11",
+        "test byte ptr [rsp + 8], 3",
+        "jz 3f",
+        "swapgs",
+        "3:",
+        "iretq",
+        handler = sym syscall_handler,
+        options(noreturn)
+    );
+}
+
+/// Runs [`kernel::interrupts::syscall`] (abstraction) and stores the result in
+/// `rax`, which the trampolines restore last.
+///
+/// The process can be preempted while the syscall runs, its context is saved
+/// on the same kernel stack then. Never while it holds a lock it shares with
+/// the rest of the kernel, those are `IrqMutex`es which keep interrupts
+/// disabled. Interrupts stay off if they were off for the caller, which only
+/// happens for callers in the kernel.
+extern "C" fn syscall_handler(regs: &mut SyscallRegs, rflags: u64) {
+    if RFlags::from_bits_truncate(rflags).contains(RFlags::INTERRUPT_FLAG) {
+        crate::interrupts::enable();
+    }
+
+    regs.rax = kernel::interrupts::syscall((*regs).into());
+    crate::interrupts::disable();
+}
+
+/// Same as [`syscall_entry`], but returns to ring 0. `sysretq` always
+/// returns to ring 3, so this is what LSTAR points to while benchmarking from
+/// the kernel (which has its GS base already).
+#[cfg(feature = "bench")]
+#[naked]
+unsafe extern "C" fn syscall_entry_kernel() {
+    asm!(
+        "mov gs:[16], rsp",
+        "mov rsp, gs:[8]",
+        "push qword ptr gs:[16]",
+        "push r11",
+        call_handler!("r11"),
+        "pop r11",
+        "mov rsp, [rsp]",
+        "push r11",
+        "popfq",
+        "jmp rcx",
+        handler = sym syscall_handler,
+        options(noreturn)
+    );
+}
+
+/// Compare the cost of a round trip through both entry points, prints the
+/// average amount of cycles per call.
+#[cfg(feature = "bench")]
+pub fn benchmark() {
+    use core::arch::x86_64::_rdtsc;
+    use hugo4os_syscall::ids::SyscallId;
+
+    const ITERATIONS: u64 = 100_000;
+
+    // Cheapest possible syscall, rejected by the dispatcher right away
+    let id = SyscallId::COUNT as u64;
+
+    crate::interrupts::with_disabled(|| {
+        LStar::write(VirtAddr::new(syscall_entry_kernel as u64));
+
+        let start = unsafe { _rdtsc() };
+        for _ in 0..ITERATIONS {
+            unsafe { hugo4os_syscall::syscall!(id) };
+        }
+        let fast = (unsafe { _rdtsc() } - start) / ITERATIONS;
+
+        LStar::write(VirtAddr::new(syscall_entry as u64));
+
+        let start = unsafe { _rdtsc() };
+        for _ in 0..ITERATIONS {
+            unsafe { hugo4os_syscall::syscall_compat!(id) };
+        }
+        let compat = (unsafe { _rdtsc() } - start) / ITERATIONS;
+
+        crate::println!("[bench] syscall/sysret: {} cycles per call", fast);
+        crate::println!("[bench] int 0x80/iretq:  {} cycles per call", compat);
+    });
+}
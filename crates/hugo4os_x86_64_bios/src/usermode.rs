@@ -0,0 +1,56 @@
+use core::arch::asm;
+
+use x86_64::VirtAddr;
+use x86_64::registers::rflags::RFlags;
+
+use crate::gdt;
+
+/// Drop to ring 3 and continue at `entry` with `stack` as stack pointer.
+///
+/// Builds the frame an interrupt from ring 3 would have pushed and `iretq`s
+/// into it, interrupts are enabled in the process. The kernel is entered again
+/// through interrupts and syscalls, which run on the kernel stack of this
+/// processor (see [`crate::cpu::Cpu::kernel_stack_top`]).
+///
+/// Unsafe because `entry` and `stack` must be mapped as user accessible.
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack: VirtAddr) -> ! {
+    let selectors = gdt::selectors();
+    let code = selectors.user_code_selector.0 as u64;
+    let data = selectors.user_data_selector.0 as u64;
+    let rflags = RFlags::INTERRUPT_FLAG.bits();
+
+    asm!(
+        "mov ds, {data:x}",
+        "mov es, {data:x}",
+        "push {data}",  // SS
+        "push {stack}", // RSP
+        "push {rflags}",
+        "push {code}",  // CS
+        "push {entry}", // RIP
+
+        // Don't leak kernel values to the process
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "swapgs",       // See `cpu.rs`
+        "iretq",
+        data = in(reg) data,
+        stack = in(reg) stack.as_u64(),
+        rflags = in(reg) rflags,
+        code = in(reg) code,
+        entry = in(reg) entry.as_u64(),
+        options(noreturn)
+    );
+}
@@ -14,14 +14,65 @@ pub const MAXIMUM_CONCURRENT_TASKS: usize = 100;
 #[allow(non_upper_case_globals)] pub const GiB: usize = 1024 * MiB;
 #[allow(non_upper_case_globals)] pub const TiB: usize = 1024 * GiB;
 
-/// Block sizes to use for FixedSizeBlockAllocator. These **must** be powers
+/// Block sizes to use for SlabAllocator. These **must** be powers
 /// of 2, because of how the allocator works.
 pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
 /// Start address for Dynamic Memory
 pub const HEAP_START: usize = 0x4444_4444_0000;
-/// Size of Dynamic Memory
-pub const HEAP_SIZE: usize = 30 * MiB;
+/// Size of Dynamic Memory at boot, it grows when it runs out
+pub const HEAP_INITIAL_SIZE: usize = 8 * MiB;
+/// Dynamic Memory never grows beyond this
+pub const HEAP_MAX_SIZE: usize = 1 * GiB;
+/// Least amount of memory added to Dynamic Memory at once
+pub const HEAP_GROWTH_MIN: usize = 1 * MiB;
+
+/// Where device memory gets mapped, right after Dynamic Memory
+pub const MMIO_START: usize = HEAP_START + HEAP_MAX_SIZE;
+/// Size of the device memory window
+pub const MMIO_SIZE: usize = 1 * GiB;
+
+/// End (exclusive) of the stack every process starts with
+pub const USER_STACK_TOP: usize = 0x0000_7fff_ffff_f000;
+/// Size the stack of every process can grow to, it is mapped on first use
+pub const USER_STACK_SIZE: usize = 8 * MiB;
+/// Unmapped memory below the stack, so an overflow faults instead of
+/// overwriting something else
+pub const USER_STACK_GUARD_SIZE: usize = 64 * KiB;
+/// How much of the initial stack argv, envp and auxv may take up
+pub const USER_STACK_ARGUMENTS_MAX: usize = 16 * KiB;
+
+
+////////////////////////////////////////////////////////////////////////////////
+// Scheduling                                                                 //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Frequency of the timer interrupt that drives the scheduler, in Hz
+pub const SCHEDULER_FREQUENCY: u32 = 100;
+/// Frequency of the periodic RTC interrupt counted by `time::ticks`, in Hz
+pub const TICK_FREQUENCY: u32 = 1024;
+/// Most processors the kernel runs on, the rest are left halted
+pub const MAXIMUM_CPUS: usize = 64;
+/// Stack every process gets for interrupts and syscalls
+pub const PROCESS_KERNEL_STACK_SIZE: usize = 16 * KiB;
+
+/// How long a process gets to exit after a close request, in milliseconds
+pub const CLOSE_REQUEST_TIMEOUT: u32 = 5000;
+/// How long the close handler of a process may run, in milliseconds
+pub const GRACEFUL_CLOSE_TIMEOUT: u32 = 3000;
+/// How long a forced close waits for a syscall to finish, in milliseconds
+pub const FORCE_CLOSE_TIMEOUT: u32 = 1000;
+
+
+////////////////////////////////////////////////////////////////////////////////
+// Syscalls                                                                   //
+////////////////////////////////////////////////////////////////////////////////
+
+/// Largest buffer a single syscall is allowed to read from or write to
+pub const SYSCALL_MAX_BUFFER_SIZE: usize = 1 * MiB;
+
+/// Capacity of the ring buffer behind every stream
+pub const STREAM_BUFFER_SIZE: usize = 4 * KiB;
 
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -37,8 +88,11 @@ pub static mut COLORS: &mut [u32] = &mut [
     0xffd3d3d3,
 ];
 
-pub static FONT_REGULAR: &[u8] = include_bytes!("../res/fonts/Roboto/Roboto-Regular.ttf");
-pub static FONT_NERD_MONO: &[u8] = include_bytes!("../res/fonts/JetBrainsMono/JetBrains Mono Regular Nerd Font Complete Mono.ttf");
+/// Fonts in the initrd, loaded in this order
+pub const FONTS: &[&str] = &[
+    "fonts/Roboto-Regular.ttf",
+    "fonts/JetBrainsMono-Regular-Nerd-Mono.ttf",
+];
 
 pub const COLOR_DIV_LOOKUP_TABLE: &[u8; 131072] = include_bytes!("../res/generated/color-div-lookup-table.bin");
 pub const COLOR_MULT_LOOKUP_TABLE: &[u8; 131072] = include_bytes!("../res/generated/color-mult-lookup-table.bin");
\ No newline at end of file
@@ -24,7 +24,7 @@ pub mod rendering {
 pub mod interrupts {
     #[derive(Debug, Clone, Copy)]
     pub struct InputSyscall {
-        pub target: *mut u64, // *mut, as it also functions as the return value
-        pub args: [u64; 11],
+        pub id: u64, // `SyscallId`, not validated yet
+        pub args: [u64; 10],
     }
 }
\ No newline at end of file
@@ -0,0 +1,74 @@
+//! Just enough AML to find the sleep types in the DSDT, not an interpreter.
+//!
+//! Firmware declares each sleep state as a named package at the root of the
+//! namespace, the first two elements are the `SLP_TYP` values for the PM1a
+//! and PM1b control blocks:
+//!
+//! ```text
+//! 08 [5C] "_S5_"   Name (\_S5, ...)
+//! 12 len count     Package (count) {
+//! 0A 07            BytePrefix 7, or 00 (Zero), 01 (One), a word, ...
+//! ```
+
+/// The values written to the `SLP_TYP` bits of the PM1 control blocks to
+/// enter a sleep state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub pm1a: u8,
+    pub pm1b: u8,
+}
+
+const NAME_OP: u8 = 0x08;
+const ROOT_PREFIX: u8 = b'\\';
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+
+/// Find `\_S<state>` in the AML `code` (a table without its header).
+pub fn sleep_type(code: &[u8], state: u8) -> Option<SleepType> {
+    let name = [b'_', b'S', b'0' + state, b'_'];
+
+    (1..code.len().saturating_sub(3))
+        .filter(|&start| code[start..start + 4] == name)
+        .filter(|&start| {
+            // A reference to the name looks the same, only a declaration
+            // starts with NameOp
+            code[start - 1] == NAME_OP || (start >= 2 && code[start - 1] == ROOT_PREFIX && code[start - 2] == NAME_OP)
+        })
+        .find_map(|start| parse_package(&code[start + 4..]))
+}
+
+fn parse_package(code: &[u8]) -> Option<SleepType> {
+    if *code.first()? != PACKAGE_OP {
+        return None;
+    }
+
+    // The top two bits of the first byte of PkgLength say how many bytes follow
+    let length_bytes = (*code.get(1)? >> 6) as usize;
+    let mut offset = 2 + length_bytes;
+    let count = *code.get(offset)?;
+    offset += 1;
+    if count < 2 {
+        return None;
+    }
+
+    let (pm1a, length) = parse_integer(code.get(offset..)?)?;
+    let (pm1b, _) = parse_integer(code.get(offset + length..)?)?;
+    Some(SleepType { pm1a, pm1b })
+}
+
+/// The low byte of an integer constant and the length of the constant,
+/// `SLP_TYP` is only 3 bits.
+fn parse_integer(code: &[u8]) -> Option<(u8, usize)> {
+    match *code.first()? {
+        ZERO_OP => Some((0, 1)),
+        ONE_OP => Some((1, 1)),
+        BYTE_PREFIX => Some((*code.get(1)?, 2)),
+        WORD_PREFIX => Some((*code.get(1)?, 3)),
+        DWORD_PREFIX => Some((*code.get(1)?, 5)),
+        _ => None,
+    }
+}
@@ -0,0 +1,121 @@
+//! The FADT ("FACP"), fixed hardware registers used for power management.
+//!
+//! It grew with every ACPI version, fields past the length of the table are
+//! missing. The 64-bit `X_` fields (generic addresses) replace the older I/O
+//! port fields when they are set.
+//!
+//! ```text
+//! 40   DSDT (u32)             89   PM1 control length
+//! 46   SCI interrupt (u16)    91   PM timer length
+//! 48   SMI command (u32)      108  century (RTC register)
+//! 52   ACPI enable            109  IA-PC boot architecture flags (u16)
+//! 53   ACPI disable           112  flags (u32)
+//! 56   PM1a event (u32)       116  reset register (generic address)
+//! 60   PM1b event (u32)       128  reset value
+//! 64   PM1a control (u32)     140  X_DSDT (u64)
+//! 68   PM1b control (u32)     148  X_PM1a event, X_PM1b event, X_PM1a control,
+//! 76   PM timer (u32)              X_PM1b control, X_PM2 control, X_PM timer
+//! 88   PM1 event length            (generic addresses, 12 bytes each)
+//! ```
+
+use super::{read_u8, read_u16, read_u32, read_u64, GenericAddress};
+
+/// Frequency of the ACPI power management timer, in Hz.
+pub const PM_TIMER_FREQUENCY: u64 = 3_579_545;
+
+const TMR_VAL_EXT: u32 = 1 << 8;
+const RESET_REG_SUP: u32 = 1 << 10;
+const HW_REDUCED_ACPI: u32 = 1 << 20;
+
+const LEGACY_DEVICES: u16 = 1 << 0;
+const HAS_8042: u16 = 1 << 1;
+const VGA_NOT_PRESENT: u16 = 1 << 2;
+const CMOS_RTC_NOT_PRESENT: u16 = 1 << 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// Physical address of the DSDT, which holds the AML of the system
+    pub dsdt_address: u64,
+    pub sci_interrupt: u16,
+    /// Port to write `acpi_enable` to, to switch from legacy to ACPI mode.
+    /// `None` when the system is always in ACPI mode.
+    pub smi_command: Option<u32>,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: Option<GenericAddress>,
+    pub pm1b_event_block: Option<GenericAddress>,
+    pub pm1a_control_block: Option<GenericAddress>,
+    pub pm1b_control_block: Option<GenericAddress>,
+    pub pm_timer: Option<PmTimer>,
+    /// Index of the CMOS RTC register holding the century
+    pub century_register: Option<u8>,
+    /// Writing `reset_value` to it resets the system
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+    pub boot_architecture: BootArchitecture,
+    /// There are no fixed hardware registers, everything goes through AML
+    pub hardware_reduced: bool,
+}
+
+/// The power management timer, counts up at [`PM_TIMER_FREQUENCY`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmTimer {
+    pub block: GenericAddress,
+    /// The counter is 32 bits wide instead of 24
+    pub extended: bool,
+}
+
+/// What legacy PC hardware there is, zero (everything is there) before
+/// ACPI 2.0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootArchitecture {
+    pub legacy_devices: bool,
+    pub has_8042: bool,
+    pub no_vga: bool,
+    pub no_cmos_rtc: bool,
+}
+
+impl Fadt {
+    pub fn parse(table: &[u8]) -> Fadt {
+        let u8_at = |offset| read_u8(table, offset).unwrap_or(0);
+        let u32_at = |offset| read_u32(table, offset).unwrap_or(0);
+        let block = |x_offset, offset, length| {
+            GenericAddress::read(table, x_offset).or_else(|| GenericAddress::io_port(u32_at(offset), u8_at(length)))
+        };
+
+        let flags = u32_at(112);
+        let boot_flags = read_u16(table, 109).unwrap_or(0);
+
+        let dsdt_address = match read_u64(table, 140) {
+            Some(address) if address != 0 => address,
+            _ => u32_at(40) as u64,
+        };
+
+        let smi_command = u32_at(48);
+        let century = u8_at(108);
+        let reset_register = GenericAddress::read(table, 116).filter(|_| flags & RESET_REG_SUP != 0);
+
+        Fadt {
+            dsdt_address,
+            sci_interrupt: read_u16(table, 46).unwrap_or(0),
+            smi_command: if smi_command != 0 { Some(smi_command) } else { None },
+            acpi_enable: u8_at(52),
+            acpi_disable: u8_at(53),
+            pm1a_event_block: block(148, 56, 88),
+            pm1b_event_block: block(160, 60, 88),
+            pm1a_control_block: block(172, 64, 89),
+            pm1b_control_block: block(184, 68, 89),
+            pm_timer: block(208, 76, 91).map(|block| PmTimer { block, extended: flags & TMR_VAL_EXT != 0 }),
+            century_register: if century != 0 { Some(century) } else { None },
+            reset_register,
+            reset_value: u8_at(128),
+            boot_architecture: BootArchitecture {
+                legacy_devices: boot_flags & LEGACY_DEVICES != 0,
+                has_8042: boot_flags & HAS_8042 != 0,
+                no_vga: boot_flags & VGA_NOT_PRESENT != 0,
+                no_cmos_rtc: boot_flags & CMOS_RTC_NOT_PRESENT != 0,
+            },
+            hardware_reduced: flags & HW_REDUCED_ACPI != 0,
+        }
+    }
+}
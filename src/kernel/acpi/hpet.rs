@@ -0,0 +1,48 @@
+//! The HPET table, describes the High Precision Event Timer.
+//!
+//! ```text
+//! 36  event timer block id (u32)
+//! 40  base address (generic address)
+//! 52  HPET number
+//! 53  minimum clock tick in periodic mode (u16)
+//! ```
+
+use super::{read_u8, read_u16, read_u32, AddressSpaceId, GenericAddress, HEADER_SIZE};
+
+const COMPARATORS_SHIFT: u32 = 8;
+const COUNTER_64BIT: u32 = 1 << 13;
+const LEGACY_REPLACEMENT: u32 = 1 << 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    /// Physical address of the registers
+    pub address: u64,
+    pub number: u8,
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    /// It can take over the interrupts of the PIT and RTC
+    pub legacy_replacement: bool,
+    pub vendor_id: u16,
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    /// `None` when the registers are not in memory, which the spec requires.
+    pub fn parse(table: &[u8]) -> Option<Hpet> {
+        let id = read_u32(table, HEADER_SIZE)?;
+        let address = GenericAddress::read(table, HEADER_SIZE + 4)?;
+        if address.space != AddressSpaceId::SystemMemory {
+            return None;
+        }
+
+        Some(Hpet {
+            address: address.address,
+            number: read_u8(table, HEADER_SIZE + 16)?,
+            comparators: ((id >> COMPARATORS_SHIFT) & 0x1f) as u8 + 1,
+            counter_64bit: id & COUNTER_64BIT != 0,
+            legacy_replacement: id & LEGACY_REPLACEMENT != 0,
+            vendor_id: (id >> 16) as u16,
+            minimum_tick: read_u16(table, HEADER_SIZE + 17)?,
+        })
+    }
+}
@@ -0,0 +1,206 @@
+//! The MADT ("APIC"), lists the processors and interrupt controllers.
+//!
+//! After the header come the local APIC address (u32) and flags (u32), then
+//! a list of entries that each start with their type and length (u8, u8).
+
+use alloc::vec::Vec;
+
+use super::{read_u8, read_u16, read_u32, read_u64, HEADER_SIZE};
+
+const PCAT_COMPAT: u32 = 1 << 0;
+
+const PROCESSOR_LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const NMI_SOURCE: u8 = 3;
+const LOCAL_APIC_NMI: u8 = 4;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const PROCESSOR_LOCAL_X2APIC: u8 = 9;
+
+const ENABLED: u32 = 1 << 0;
+const ONLINE_CAPABLE: u32 = 1 << 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    /// Physical address of every processor's local APIC
+    pub local_apic_address: u64,
+    /// There are 8259 PICs, which have to be masked before using the APICs
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmi_sources: Vec<NmiSource>,
+    pub local_apic_nmis: Vec<LocalApicNmi>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    /// Id used by the ACPI namespace
+    pub uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+    /// Disabled, but can be enabled while the system runs
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    /// First global system interrupt it handles
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    BusDefault,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    BusDefault,
+    Edge,
+    Level,
+}
+
+/// An ISA interrupt that isn't connected to the global system interrupt with
+/// the same number, or not in the default way (active high, edge triggered).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// A global system interrupt that is a non-maskable interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NmiSource {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// A LINT pin of a local APIC that is connected to NMI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    /// Processor uid, `None` for all of them
+    pub processor: Option<u32>,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+impl Madt {
+    pub fn parse(table: &[u8]) -> Madt {
+        let mut madt = Madt {
+            local_apic_address: read_u32(table, HEADER_SIZE).unwrap_or(0) as u64,
+            has_legacy_pics: read_u32(table, HEADER_SIZE + 4).unwrap_or(0) & PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmi_sources: Vec::new(),
+            local_apic_nmis: Vec::new(),
+        };
+
+        let mut rest = table.get(HEADER_SIZE + 8..).unwrap_or(&[]);
+        while rest.len() >= 2 {
+            let length = rest[1] as usize;
+            if length < 2 || length > rest.len() {
+                break;
+            }
+
+            let (entry, next) = rest.split_at(length);
+            rest = next;
+            madt.parse_entry(entry);
+        }
+
+        madt
+    }
+
+    /// Entries that are too short are skipped.
+    fn parse_entry(&mut self, entry: &[u8]) -> Option<()> {
+        match entry[0] {
+            PROCESSOR_LOCAL_APIC => {
+                let flags = read_u32(entry, 4)?;
+                self.processors.push(Processor {
+                    uid: read_u8(entry, 2)? as u32,
+                    apic_id: read_u8(entry, 3)? as u32,
+                    enabled: flags & ENABLED != 0,
+                    online_capable: flags & ONLINE_CAPABLE != 0,
+                });
+            }
+            PROCESSOR_LOCAL_X2APIC => {
+                let flags = read_u32(entry, 8)?;
+                self.processors.push(Processor {
+                    uid: read_u32(entry, 12)?,
+                    apic_id: read_u32(entry, 4)?,
+                    enabled: flags & ENABLED != 0,
+                    online_capable: flags & ONLINE_CAPABLE != 0,
+                });
+            }
+            IO_APIC => self.io_apics.push(IoApic {
+                id: read_u8(entry, 2)?,
+                address: read_u32(entry, 4)?,
+                gsi_base: read_u32(entry, 8)?,
+            }),
+            INTERRUPT_SOURCE_OVERRIDE => {
+                let (polarity, trigger) = flags(read_u16(entry, 8)?);
+                self.overrides.push(InterruptOverride {
+                    irq: read_u8(entry, 3)?,
+                    gsi: read_u32(entry, 4)?,
+                    polarity,
+                    trigger,
+                });
+            }
+            NMI_SOURCE => {
+                let (polarity, trigger) = flags(read_u16(entry, 2)?);
+                self.nmi_sources.push(NmiSource { gsi: read_u32(entry, 4)?, polarity, trigger });
+            }
+            LOCAL_APIC_NMI => {
+                let (polarity, trigger) = flags(read_u16(entry, 3)?);
+                let processor = read_u8(entry, 2)?;
+                self.local_apic_nmis.push(LocalApicNmi {
+                    processor: if processor == 0xff { None } else { Some(processor as u32) },
+                    lint: read_u8(entry, 5)?,
+                    polarity,
+                    trigger,
+                });
+            }
+            LOCAL_APIC_ADDRESS_OVERRIDE => self.local_apic_address = read_u64(entry, 4)?,
+            _ => {}
+        }
+
+        Some(())
+    }
+
+    /// The global system interrupt ISA interrupt `irq` is connected to, and how.
+    pub fn isa_irq(&self, irq: u8) -> InterruptOverride {
+        self.overrides.iter()
+            .find(|o| o.irq == irq)
+            .copied()
+            .unwrap_or(InterruptOverride { irq, gsi: irq as u32, polarity: Polarity::BusDefault, trigger: TriggerMode::BusDefault })
+    }
+
+    /// The processors that are running or can be started.
+    pub fn usable_processors(&self) -> impl Iterator<Item = &Processor> {
+        self.processors.iter().filter(|processor| processor.enabled || processor.online_capable)
+    }
+}
+
+/// The MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3.
+fn flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::BusDefault,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::BusDefault,
+    };
+    (polarity, trigger)
+}
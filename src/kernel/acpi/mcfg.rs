@@ -0,0 +1,48 @@
+//! The MCFG table, where the memory mapped PCI Express configuration space is.
+//!
+//! After the header and 8 reserved bytes, it is a list of 16-byte entries:
+//! base address (u64), segment group (u16), start bus, end bus, reserved (u32).
+
+use alloc::vec::Vec;
+
+use super::{read_u8, read_u16, read_u64, HEADER_SIZE};
+
+const ENTRY_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    /// Physical address of the configuration space of bus 0 (even if it
+    /// starts at a later bus)
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl Mcfg {
+    pub fn parse(table: &[u8]) -> Mcfg {
+        let entries = table.get(HEADER_SIZE + 8..).unwrap_or(&[])
+            .chunks_exact(ENTRY_SIZE)
+            .filter_map(|entry| Some(McfgEntry {
+                base_address: read_u64(entry, 0)?,
+                segment_group: read_u16(entry, 8)?,
+                start_bus: read_u8(entry, 10)?,
+                end_bus: read_u8(entry, 11)?,
+            }))
+            .collect();
+
+        Mcfg { entries }
+    }
+
+    /// Physical address of the configuration space of a PCI function.
+    pub fn config_address(&self, segment_group: u16, bus: u8, device: u8, function: u8) -> Option<u64> {
+        let entry = self.entries.iter()
+            .find(|entry| entry.segment_group == segment_group && (entry.start_bus..=entry.end_bus).contains(&bus))?;
+        Some(entry.base_address + ((bus as u64) << 20 | (device as u64) << 15 | (function as u64) << 12))
+    }
+}
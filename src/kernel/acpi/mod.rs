@@ -0,0 +1,325 @@
+//! ACPI tables, found through the RSDP the bootloader passes on.
+//!
+//! The RSDP points to the RSDT (32-bit addresses) or, since ACPI 2.0, the
+//! XSDT (64-bit addresses), which list every other table. Each table starts
+//! with the same header:
+//!
+//! ```text
+//! 0   signature ("APIC", "FACP", ...)
+//! 4   length, including the header (u32)
+//! 8   revision
+//! 9   checksum, all bytes of the table add up to 0
+//! 10  OEM id, OEM table id, OEM revision, creator id and revision
+//! 36  contents
+//! ```
+//!
+//! Only the tables the kernel uses are parsed, the AML in the DSDT is only
+//! searched for the sleep type that turns the machine off (see [`aml`]).
+
+use core::{fmt, ptr};
+
+use spin::Once;
+
+use super::{memory::{MemoryError, MemoryManager}, ports::Ports};
+
+pub mod aml;
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+use aml::SleepType;
+use fadt::Fadt;
+use hpet::Hpet;
+use madt::Madt;
+use mcfg::Mcfg;
+
+static ACPI: Once<Acpi> = Once::new();
+
+const HEADER_SIZE: usize = 36;
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    InvalidRsdp,                // Wrong signature or checksum
+    InvalidTable([u8; 4]),      // Signature of the table with a wrong checksum or length
+    Memory(MemoryError),
+}
+
+impl From<MemoryError> for AcpiError {
+    fn from(error: MemoryError) -> AcpiError {
+        AcpiError::Memory(error)
+    }
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcpiError::InvalidRsdp => write!(f, "Invalid RSDP"),
+            AcpiError::InvalidTable(signature) => write!(f, "Invalid {} table", signature_str(signature)),
+            AcpiError::Memory(error) => write!(f, "Could not map ACPI tables: {:?}", error),
+        }
+    }
+}
+
+/// The tables the kernel knows about, `None` when the firmware doesn't
+/// provide them (or they are invalid).
+#[derive(Debug, Clone)]
+pub struct Acpi {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Option<Mcfg>,
+    /// `\_S5` from the DSDT, soft off
+    pub s5_sleep_type: Option<SleepType>,
+}
+
+/// Parse the tables behind the RSDP at physical address `rsdp_address`. Only
+/// the first call does anything, later calls return the same tables.
+pub fn init<Arch: MemoryManager>(rsdp_address: usize) -> Result<&'static Acpi, AcpiError> {
+    if let Some(acpi) = ACPI.get() {
+        return Ok(acpi);
+    }
+
+    let acpi = parse::<Arch>(rsdp_address)?;
+    Ok(ACPI.call_once(|| acpi))
+}
+
+/// The tables found by [`init`], `None` before it or when it failed.
+pub fn get() -> Option<&'static Acpi> {
+    ACPI.get()
+}
+
+fn parse<Arch: MemoryManager>(rsdp_address: usize) -> Result<Acpi, AcpiError> {
+    let rsdp = map::<Arch>(rsdp_address, RSDP_V1_SIZE)?;
+    if &rsdp[..8] != RSDP_SIGNATURE || !checksum(rsdp) {
+        return Err(AcpiError::InvalidRsdp);
+    }
+
+    let mut oem_id = [0; 6];
+    oem_id.copy_from_slice(&rsdp[9..15]);
+    let revision = rsdp[15];
+
+    // Revision 2 and up have an XSDT, which has to be used when it is there
+    let (root, entry_size) = if revision >= 2 {
+        let rsdp = map::<Arch>(rsdp_address, RSDP_V2_SIZE)?;
+        let length = read_u32(rsdp, 20).unwrap_or(0) as usize;
+        let rsdp = map::<Arch>(rsdp_address, length.max(RSDP_V2_SIZE))?;
+        if !checksum(rsdp) {
+            return Err(AcpiError::InvalidRsdp);
+        }
+        (read_u64(rsdp, 24).unwrap_or(0) as usize, 8)
+    } else {
+        (read_u32(rsdp, 16).unwrap_or(0) as usize, 4)
+    };
+
+    let root = table::<Arch>(root)?;
+    let mut acpi = Acpi { revision, oem_id, madt: None, fadt: None, hpet: None, mcfg: None, s5_sleep_type: None };
+
+    for entry in root[HEADER_SIZE..].chunks_exact(entry_size) {
+        let address = match entry_size {
+            8 => read_u64(entry, 0),
+            _ => read_u32(entry, 0).map(u64::from),
+        };
+
+        // A broken table shouldn't hide the others
+        let table = match address.map(|address| table::<Arch>(address as usize)) {
+            Some(Ok(table)) => table,
+            _ => continue,
+        };
+
+        match &table[..4] {
+            b"APIC" => acpi.madt = Some(Madt::parse(table)),
+            b"FACP" => acpi.fadt = Some(Fadt::parse(table)),
+            b"HPET" => acpi.hpet = Hpet::parse(table),
+            b"MCFG" => acpi.mcfg = Some(Mcfg::parse(table)),
+            _ => {}
+        }
+    }
+
+    if let Some(Ok(dsdt)) = acpi.fadt.map(|fadt| table::<Arch>(fadt.dsdt_address as usize)) {
+        acpi.s5_sleep_type = aml::sleep_type(&dsdt[HEADER_SIZE..], 5);
+    }
+
+    Ok(acpi)
+}
+
+/// The table with its header at physical address `address`, after checking
+/// its length and checksum.
+fn table<Arch: MemoryManager>(address: usize) -> Result<&'static [u8], AcpiError> {
+    let header = map::<Arch>(address, HEADER_SIZE)?;
+    let mut signature = [0; 4];
+    signature.copy_from_slice(&header[..4]);
+
+    let length = read_u32(header, 4).unwrap_or(0) as usize;
+    if length < HEADER_SIZE {
+        return Err(AcpiError::InvalidTable(signature));
+    }
+
+    let table = map::<Arch>(address, length)?;
+    if !checksum(table) {
+        return Err(AcpiError::InvalidTable(signature));
+    }
+    Ok(table)
+}
+
+fn map<Arch: MemoryManager>(address: usize, size: usize) -> Result<&'static [u8], AcpiError> {
+    let start = Arch::map_physical(address, size)?;
+
+    // Safety: the firmware tables are never reused, and stay mapped
+    Ok(unsafe { core::slice::from_raw_parts(start as *const u8, size) })
+}
+
+/// Whether all bytes of `data` add up to 0.
+pub fn checksum(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn signature_str(signature: &[u8; 4]) -> &str {
+    core::str::from_utf8(signature).unwrap_or("????")
+}
+
+/// Where a register lives, used by the FADT and HPET tables:
+///
+/// ```text
+/// 0  address space
+/// 1  register width in bits
+/// 2  register offset in bits
+/// 3  access size (0 undefined, 1 byte, 2 word, 3 dword, 4 qword)
+/// 4  address (u64)
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpaceId,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpaceId {
+    SystemMemory,
+    SystemIo,
+    PciConfiguration,
+    Other(u8),
+}
+
+impl GenericAddress {
+    pub const SIZE: usize = 12;
+
+    /// The structure at `offset` in `table`, `None` when the table is too
+    /// short or the address is 0 (the register doesn't exist).
+    pub fn read(table: &[u8], offset: usize) -> Option<GenericAddress> {
+        let address = read_u64(table, offset + 4)?;
+        if address == 0 {
+            return None;
+        }
+
+        Some(GenericAddress {
+            space: match table[offset] {
+                0 => AddressSpaceId::SystemMemory,
+                1 => AddressSpaceId::SystemIo,
+                2 => AddressSpaceId::PciConfiguration,
+                other => AddressSpaceId::Other(other),
+            },
+            bit_width: table[offset + 1],
+            bit_offset: table[offset + 2],
+            access_size: table[offset + 3],
+            address,
+        })
+    }
+
+    /// An I/O port of `length` bytes, for the old fields that are only a port
+    /// number. `None` when the port is 0.
+    pub fn io_port(port: u32, length: u8) -> Option<GenericAddress> {
+        if port == 0 {
+            return None;
+        }
+
+        Some(GenericAddress {
+            space: AddressSpaceId::SystemIo,
+            bit_width: length.saturating_mul(8),
+            bit_offset: 0,
+            access_size: 0,
+            address: port as u64,
+        })
+    }
+
+    /// Size of one access in bytes, from the access size or else the width.
+    pub fn access_bytes(&self) -> u8 {
+        match self.access_size {
+            1..=4 => 1 << (self.access_size - 1),
+            _ => (self.bit_width / 8).clamp(1, 8),
+        }
+    }
+
+    /// Read the register, `None` when it is in an address space the kernel
+    /// can't reach.
+    ///
+    /// Unsafe because reading a device register can change its state.
+    pub unsafe fn read_register<Arch: MemoryManager + Ports>(&self) -> Option<u64> {
+        let size = self.access_bytes();
+        match self.space {
+            AddressSpaceId::SystemIo if size <= 4 => Some(Arch::read_port(self.address as u16, size) as u64),
+            AddressSpaceId::SystemMemory => {
+                let address = Arch::map_physical(self.address as usize, size as usize).ok()?;
+                Some(match size {
+                    1 => ptr::read_volatile(address as *const u8) as u64,
+                    2 => ptr::read_volatile(address as *const u16) as u64,
+                    4 => ptr::read_volatile(address as *const u32) as u64,
+                    _ => ptr::read_volatile(address as *const u64),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Write the register, returns false when it is in an address space the
+    /// kernel can't reach.
+    ///
+    /// Unsafe because it controls hardware directly.
+    pub unsafe fn write_register<Arch: MemoryManager + Ports>(&self, value: u64) -> bool {
+        let size = self.access_bytes();
+        match self.space {
+            AddressSpaceId::SystemIo if size <= 4 => Arch::write_port(self.address as u16, size, value as u32),
+            AddressSpaceId::SystemMemory => {
+                let address = match Arch::map_physical(self.address as usize, size as usize) {
+                    Ok(address) => address,
+                    Err(_) => return false,
+                };
+                match size {
+                    1 => ptr::write_volatile(address as *mut u8, value as u8),
+                    2 => ptr::write_volatile(address as *mut u16, value as u16),
+                    4 => ptr::write_volatile(address as *mut u32, value as u32),
+                    _ => ptr::write_volatile(address as *mut u64, value),
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+// Tables are little endian, and can be shorter than the current spec when
+// the firmware implements an older version.
+
+pub(crate) fn read_u8(data: &[u8], offset: usize) -> Option<u8> {
+    data.get(offset).copied()
+}
+
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+pub(crate) fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
@@ -1,5 +1,28 @@
-use super::{memory::MemoryManager, abstractions::rendering::FrameBuffer, interrupts::Interrupts};
+use super::{memory::MemoryManager, abstractions::rendering::FrameBuffer, cpu::Cpus, interrupts::Interrupts, ports::Ports, time::Clock};
 
-pub trait Architecture: MemoryManager + Interrupts {
+pub trait Architecture: MemoryManager + Interrupts + Cpus + Ports + Clock + 'static {
     type FrameBuffer: FrameBuffer;
+
+    /// Continue unprivileged at `entry`, with `stack` as stack pointer. The
+    /// kernel is only entered again through interrupts and syscalls.
+    ///
+    /// Unsafe because both addresses must be mapped as user accessible.
+    unsafe fn enter_user_mode(entry: usize, stack: usize) -> !;
+
+    /// Build a context on the kernel stack ending at `kernel_stack_top` that
+    /// continues unprivileged at `entry` with `stack` as stack pointer once
+    /// the scheduler switches to it, returns its address.
+    ///
+    /// Unsafe because the kernel stack must be valid and unused.
+    unsafe fn initial_context(kernel_stack_top: usize, entry: usize, stack: usize) -> usize;
+
+    /// Make the saved `context` continue at `entry` (on the same stack)
+    /// instead, returns false when it wasn't interrupted in user mode.
+    ///
+    /// Unsafe because `context` must have been saved by the scheduler.
+    unsafe fn redirect_context(context: usize, entry: usize) -> bool;
+
+    /// Reset the processor, and with it the machine. The last resort of
+    /// [`power::reboot`](super::power::reboot), so it must not fail.
+    fn reset() -> !;
 }
\ No newline at end of file
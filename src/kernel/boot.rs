@@ -0,0 +1,60 @@
+//! What the bootloader hands to the kernel.
+//!
+//! Every architecture (and bootloader it supports) translates whatever it was
+//! started with into a [`BootInfo`] before calling [`kernel_main`](crate::kernel_main),
+//! so nothing past that point has to know how the machine was booted.
+
+use alloc::vec::Vec;
+
+use super::architecture::Architecture;
+
+/// Everything the kernel is started with.
+pub struct BootInfo<Arch: Architecture> {
+    /// Screen to draw to, if the bootloader set one up
+    pub framebuffer: Option<Arch::FrameBuffer>,
+    /// Physical memory, sorted by address
+    pub memory_map: Vec<MemoryRegion>,
+    /// Physical address of the ACPI root system description pointer
+    pub rsdp_address: Option<usize>,
+    /// Arguments for the kernel itself, empty if there are none
+    pub command_line: &'static str,
+    /// Initial ramdisk, already mapped by the architecture
+    pub initrd: Option<&'static [u8]>,
+    /// Thread local storage of the kernel executable
+    pub tls_template: Option<TlsTemplate>,
+}
+
+/// A range of physical memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub end: usize, // Exclusive
+    pub kind: MemoryRegionKind,
+}
+
+impl MemoryRegion {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryRegionKind {
+    Usable,
+    Bootloader,     // The kernel, its page tables and the boot info, in use until shutdown
+    Initrd,         // See `BootInfo::initrd`
+    Reserved,       // Firmware, memory mapped devices and anything else that may not be touched
+}
+
+/// Where the initial contents of thread local variables are, every thread
+/// gets a copy of `file_size` bytes followed by zeroes up to `memory_size`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsTemplate {
+    pub start: usize,
+    pub file_size: usize,
+    pub memory_size: usize,
+}
@@ -0,0 +1,153 @@
+//! Options passed to the kernel by its bootloader.
+//!
+//! The command line is a list of options separated by whitespace, each either
+//! `name=value` or just `name`:
+//!
+//! ```text
+//! log=debug serial=com2 test=memory resolution=1280x720
+//! ```
+//!
+//! The architecture parses it with [`init`] as early as possible, because the
+//! serial port and log level are needed before anything else runs.
+
+use core::fmt;
+
+use spin::Once;
+
+static OPTIONS: Once<Options> = Once::new();
+
+/// How much the kernel prints, every level includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,  // What the `verbose` feature enables
+    Trace,
+}
+
+/// Screen size, the mode is chosen by the bootloader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution {
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    pub log_level: LogLevel,
+    /// I/O port of the serial port to print to, `None` to not print at all
+    pub serial_port: Option<u16>,
+    /// Only run the tests with this in their name
+    pub test_filter: Option<&'static str>,
+    /// Resolution that was asked for, it is up to the bootloader whether it is used
+    pub resolution: Option<Resolution>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionError<'a> {
+    Unknown(&'a str),                           // Name of the option
+    InvalidValue(&'a str, &'a str),             // Name and value
+    MissingValue(&'a str),
+}
+
+impl fmt::Display for OptionError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionError::Unknown(name) => write!(f, "Unknown option `{}`", name),
+            OptionError::InvalidValue(name, value) => write!(f, "Invalid value `{}` for option `{}`", value, name),
+            OptionError::MissingValue(name) => write!(f, "Option `{}` needs a value", name),
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            log_level: if cfg!(feature = "verbose") { LogLevel::Debug } else { LogLevel::Info },
+            serial_port: Some(COM1),
+            test_filter: None,
+            resolution: None,
+        }
+    }
+}
+
+const COM1: u16 = 0x3f8;
+const COM2: u16 = 0x2f8;
+const COM3: u16 = 0x3e8;
+const COM4: u16 = 0x2e8;
+
+impl Options {
+    /// Parse `command_line`, options that are invalid keep their default.
+    pub fn parse(command_line: &'static str) -> Options {
+        let mut options = Options::default();
+        for option in command_line.split_whitespace() {
+            let _ = options.set(option);
+        }
+        options
+    }
+
+    /// Apply a single `name=value` or `name` option.
+    pub fn set(&mut self, option: &'static str) -> Result<(), OptionError<'static>> {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+        let invalid = |value| OptionError::InvalidValue(name, value);
+
+        match (name, value) {
+            ("log", Some(value)) => {
+                self.log_level = match value {
+                    "error" => LogLevel::Error,
+                    "warn" => LogLevel::Warn,
+                    "info" => LogLevel::Info,
+                    "debug" => LogLevel::Debug,
+                    "trace" => LogLevel::Trace,
+                    _ => return Err(invalid(value)),
+                };
+            }
+            ("serial", Some(value)) => {
+                self.serial_port = match value {
+                    "none" => None,
+                    "com1" => Some(COM1),
+                    "com2" => Some(COM2),
+                    "com3" => Some(COM3),
+                    "com4" => Some(COM4),
+                    _ => Some(parse_port(value).ok_or_else(|| invalid(value))?),
+                };
+            }
+            ("test", Some(value)) => self.test_filter = Some(value),
+            ("resolution", Some(value)) => {
+                let (width, height) = value.split_once('x').ok_or_else(|| invalid(value))?;
+                self.resolution = Some(Resolution {
+                    width: width.parse().map_err(|_| invalid(value))?,
+                    height: height.parse().map_err(|_| invalid(value))?,
+                });
+            }
+            ("log" | "serial" | "test" | "resolution", None) => return Err(OptionError::MissingValue(name)),
+            _ => return Err(OptionError::Unknown(name)),
+        }
+
+        Ok(())
+    }
+}
+
+/// I/O port number, in hexadecimal with a `0x` prefix or decimal.
+fn parse_port(value: &str) -> Option<u16> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Parse the command line the kernel was started with, returns the options
+/// it could not use. Only the first call changes [`options`].
+pub fn init(command_line: &'static str) -> impl Iterator<Item = OptionError<'static>> {
+    OPTIONS.call_once(|| Options::parse(command_line));
+    command_line.split_whitespace().filter_map(|option| Options::default().set(option).err())
+}
+
+/// The options the kernel was started with, the defaults before [`init`].
+pub fn options() -> Options {
+    OPTIONS.get().copied().unwrap_or_default()
+}
@@ -0,0 +1,40 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+
+/// The processors the kernel runs on, the one that booted is number 0.
+pub trait Cpus {
+    /// Start every other processor the firmware lists, each calls `entry`
+    /// once it can run kernel code. Returns how many processors there are
+    /// now, including this one (so 1 if none could be started).
+    fn start_others(entry: fn() -> !) -> usize;
+
+    /// Number of the processor this runs on, below what
+    /// [`Cpus::start_others`] returned.
+    fn current() -> usize;
+
+    /// Interrupt processor `index` when it is halted, so it looks for work
+    /// again. Must not block or allocate, wakers call it.
+    fn wake(index: usize);
+
+    /// Halt every other processor for good, without waiting for whatever they
+    /// are doing. Before panicking or turning the machine off.
+    fn stop_others();
+
+    /// The [`CpuLocal`] of the processor this runs on. Works in syscalls,
+    /// exceptions and the timer interrupt, whatever they interrupted.
+    fn local() -> &'static CpuLocal;
+}
+
+/// What the kernel keeps for every processor, the architecture stores them.
+pub struct CpuLocal {
+    pub(crate) current_process: AtomicUsize,
+    pub(crate) in_syscall: AtomicBool, // Whether the current process is inside a syscall
+}
+
+impl CpuLocal {
+    pub const fn new() -> CpuLocal {
+        CpuLocal {
+            current_process: AtomicUsize::new(0), // The kernel
+            in_syscall: AtomicBool::new(false),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! The initial ramdisk, a tar archive the bootloader loads next to the kernel.
+//!
+//! It holds what doesn't have to be built into the kernel (fonts, images,
+//! programs), so those can change without rebuilding it.
+
+use spin::Once;
+
+use crate::loaders::tar::{Archive, File, Files};
+
+static INITRD: Once<Archive<'static>> = Once::new();
+
+/// Called once by [`kernel_main`](crate::kernel_main) when the bootloader
+/// passed an initrd.
+pub fn init(data: &'static [u8]) {
+    INITRD.call_once(|| Archive::new(data));
+}
+
+/// Contents of the file at `path`, `None` if it or the initrd doesn't exist.
+pub fn get(path: &str) -> Option<&'static [u8]> {
+    INITRD.get()?.get(path)
+}
+
+/// Every file in the initrd, nothing if there is none.
+pub fn files() -> impl Iterator<Item = File<'static>> {
+    INITRD.get()
+        .map(Archive::files)
+        .into_iter()
+        .flat_map(|files: Files<'static>| files.filter_map(Result::ok))
+}
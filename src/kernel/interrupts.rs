@@ -1,17 +1,106 @@
-use crate::task;
+use core::fmt;
+
+use crate::task::{self, process_manager::{self, ContextSwitch, ProcessId, KERNEL_PROCESS_ID, KILLED_EXIT_CODE, PROCESS_MANAGER}};
 
 pub trait Interrupts {
     fn enable();
     fn disable();
+    fn are_enabled() -> bool;
     fn with_disabled(f: impl FnOnce());
     fn enable_and_halt();
+
+    /// Set up the interrupt controller, with the MADT when ACPI has one.
+    /// Called once, after `acpi::init` and with interrupts disabled.
+    fn init_controller(madt: Option<&Madt>);
 }
 
-use super::abstractions::interrupts::InputSyscall;
+use super::{abstractions::interrupts::InputSyscall, acpi::madt::Madt, memory::PageFault};
 
-/// PIC1 Timer IRQ
-pub fn timer() {
+/// What kind of CPU exception happened, however the architecture numbers them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionKind {
+    Breakpoint,
+    Debug,              // Single step or watchpoint
+    NonMaskable,        // Hardware event, like a watchdog, that can arrive in the middle of anything
+    Arithmetic,         // Division by zero, overflow or a floating point error
+    InvalidInstruction, // Undefined, or using a unit that isn't available
+    ProtectionFault,    // Privileged instruction, bad segment or non-canonical address
+    StackFault,
+    AlignmentCheck,
+    MachineCheck,       // Hardware error
+    DoubleFault,        // Exception while starting the handler of another one
+    Other,
+}
+
+impl ExceptionKind {
+    /// Whether the interrupted code can simply continue afterwards.
+    pub fn is_resumable(self) -> bool {
+        matches!(self, ExceptionKind::Breakpoint | ExceptionKind::Debug)
+    }
 
+    /// Whether it has nothing to do with the interrupted code. Those can even
+    /// interrupt code holding a lock the handler needs (to print, say), so
+    /// the architecture handles them without waiting for anything, and they
+    /// don't go through [`exception`].
+    pub fn is_asynchronous(self) -> bool {
+        matches!(self, ExceptionKind::NonMaskable)
+    }
+
+    /// Whether nothing can be trusted anymore, not even when a process caused it.
+    pub fn is_fatal(self) -> bool {
+        matches!(self, ExceptionKind::MachineCheck | ExceptionKind::DoubleFault)
+    }
+}
+
+/// Everything the architecture knows about an exception, formatted as a
+/// multi-line crash report.
+pub struct ExceptionReport<'a> {
+    pub kind: ExceptionKind,
+    pub name: &'static str,                     // As the architecture calls it
+    pub vector: u8,
+    pub error_code: Option<u64>,
+    pub decoded_error: Option<&'a dyn fmt::Display>,
+    pub instruction_pointer: usize,
+    pub stack_pointer: usize,
+    pub user: bool,                             // Caused by unprivileged code
+    pub registers: &'a [(&'static str, u64)],   // Including control registers
+}
+
+impl fmt::Display for ExceptionReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = if self.user { "user" } else { "kernel" };
+        writeln!(f, "EXCEPTION: {} (vector {}) in {} mode", self.name, self.vector, mode)?;
+
+        match (self.error_code, self.decoded_error) {
+            (Some(code), Some(decoded)) => writeln!(f, "  Error code: {:#x} ({})", code, decoded)?,
+            (Some(code), None) => writeln!(f, "  Error code: {:#x}", code)?,
+            _ => {}
+        }
+        write!(f, "  At {:#018x}, stack {:#018x}", self.instruction_pointer, self.stack_pointer)?;
+
+        for (index, (name, value)) in self.registers.iter().enumerate() {
+            if index % 4 == 0 {
+                write!(f, "\n ")?;
+            }
+            write!(f, " {:>6} {:#018x}", name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What the architecture does after [`exception`] returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionAction {
+    Continue,
+    Killed(ProcessId), // Wait for the scheduler to switch away, it won't run again
+}
+
+/// PIC1 Timer IRQ, `context` is the saved state of whatever was interrupted.
+/// Returns the process to switch to, if its turn is over.
+pub fn timer(context: usize) -> Option<ContextSwitch> {
+    // When the interrupted code holds the lock, switching waits for the next tick
+    PROCESS_MANAGER.try_lock()?.schedule(context)
 }
 
 /// PIC1 Keyboard IRQ
@@ -21,15 +110,60 @@ pub fn keyboard(scancode: u8) {
 
 /// PIC2 RealTimeClock IRQ
 pub fn rtc() {
-    // TODO: Handle RTC IRQ
+    super::time::tick();
 }
 
-pub fn syscall(args: InputSyscall) -> u64 {
-    let target = unsafe { *args.target };
+/// CPU exception other than a page fault. Breakpoints and the like continue,
+/// a process that faults exits. When the kernel faults (or the hardware
+/// failed) this panics with the report.
+pub fn exception(report: &ExceptionReport) -> ExceptionAction {
+    if report.kind.is_resumable() || report.kind.is_asynchronous() {
+        return ExceptionAction::Continue;
+    }
+
+    let process = process_manager::current_process_id();
+    if report.kind.is_fatal() || process == KERNEL_PROCESS_ID || !report.user || process_manager::in_syscall() {
+        panic!("{}", report);
+    }
+
+    exit_faulting_process(process, report);
+    ExceptionAction::Killed(process)
+}
+
+/// Page fault the architecture couldn't resolve (by mapping reserved memory or
+/// copying a copy-on-write page). The process responsible exits, and is
+/// returned; the architecture has to wait for the scheduler to switch away.
+///
+/// Faults in the kernel are bugs, this panics then. That includes syscalls:
+/// they check every pointer they are passed, and killing a process in the
+/// middle of one could leave kernel locks held forever.
+pub fn page_fault(fault: PageFault) -> ProcessId {
+    let process = process_manager::current_process_id();
+    if process == KERNEL_PROCESS_ID || !fault.user || process_manager::in_syscall() {
+        panic!("Kernel {}", fault);
+    }
 
+    exit_faulting_process(process, &fault);
+    process
+}
 
-    if target == 1 {
+fn exit_faulting_process(process: ProcessId, reason: &dyn fmt::Display) {
+    // The timer doesn't switch while the lock is held, so the faulting code holds it
+    match PROCESS_MANAGER.try_lock() {
+        Some(mut manager) => manager.exit(process, KILLED_EXIT_CODE),
+        None => panic!("Process {}: {} with the process manager locked", process, reason),
     }
+}
+
+/// Syscall entry, `args.id` holds the requested `SyscallId`
+pub fn syscall(args: InputSyscall) -> u64 {
+    process_manager::set_in_syscall(true);
+    let result = super::syscall::dispatch(args.id, &args);
+    process_manager::set_in_syscall(false);
 
-    return 1337;
+    // Terminated while it was in the kernel, it doesn't get to see the result
+    if PROCESS_MANAGER.lock().exit_if_terminated(process_manager::current_process_id()) {
+        process_manager::wait_for_switch();
+    }
+    result
 }
\ No newline at end of file
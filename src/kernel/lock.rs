@@ -0,0 +1,105 @@
+//! A spinlock for data that syscalls and kernel tasks share.
+//!
+//! The timer can switch to another process at any moment, if that happened
+//! while holding a plain spinlock, everything else on the processor that
+//! wants the lock spins until the holder gets another turn (or forever, with
+//! interrupts disabled). [`IrqMutex`] keeps interrupts disabled while it is
+//! held, so its holder is never switched away from.
+
+use core::{mem::ManuallyDrop, ops::{Deref, DerefMut}};
+
+use spin::{Mutex, MutexGuard, Once};
+
+use super::interrupts::Interrupts;
+
+static INTERRUPTS: Once<InterruptControl> = Once::new();
+
+/// What the architecture provides, locks can't be generic over it.
+struct InterruptControl {
+    are_enabled: fn() -> bool,
+    enable: fn(),
+    disable: fn(),
+}
+
+/// Hook up the architecture. Before this the locks don't touch interrupts,
+/// which is fine as long as they are disabled.
+pub fn init<Arch: Interrupts>() {
+    INTERRUPTS.call_once(|| InterruptControl {
+        are_enabled: Arch::are_enabled,
+        enable: Arch::enable,
+        disable: Arch::disable,
+    });
+}
+
+/// Disable interrupts, returns whether they have to be enabled again.
+fn disable() -> bool {
+    match INTERRUPTS.get() {
+        Some(control) if (control.are_enabled)() => {
+            (control.disable)();
+            true
+        }
+        _ => false,
+    }
+}
+
+fn restore(enable: bool) {
+    if let (true, Some(control)) = (enable, INTERRUPTS.get()) {
+        (control.enable)();
+    }
+}
+
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> IrqMutex<T> {
+        IrqMutex { inner: Mutex::new(value) }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let enable = disable();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            enable,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let enable = disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard { guard: ManuallyDrop::new(guard), enable }),
+            None => {
+                restore(enable);
+                None
+            }
+        }
+    }
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    enable: bool, // Whether interrupts were enabled before locking
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlock first, or an interrupt could arrive while it is still held
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        restore(self.enable);
+    }
+}
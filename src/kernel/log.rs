@@ -0,0 +1,31 @@
+//! Messages of the kernel itself, for code that doesn't know the
+//! architecture's `println`. Written to the serial sink (see `stream.rs`)
+//! when the `log` option includes their level.
+
+use core::fmt::{self, Write};
+
+use super::{command_line::{self, LogLevel}, stream::{self, StreamKind, StreamSink}};
+
+struct SinkWriter(StreamSink);
+
+impl Write for SinkWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        (self.0)(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Write one line, dropped when nothing is registered to print it yet.
+pub fn write(level: LogLevel, args: fmt::Arguments) {
+    if command_line::options().log_level < level {
+        return;
+    }
+
+    if let Some(sink) = stream::sink(StreamKind::Serial) {
+        let _ = writeln!(SinkWriter(sink), "[{:?}] {}", level, args);
+    }
+}
+
+pub fn warn(args: fmt::Arguments) {
+    write(LogLevel::Warn, args);
+}
@@ -1,3 +1,159 @@
+use core::fmt;
+
+use crate::constants::{USER_STACK_TOP, USER_STACK_SIZE, USER_STACK_GUARD_SIZE};
+
+/// Handle to a set of page tables, what it contains is up to the architecture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressSpace(pub usize);
+
+/// Access rights of user memory, it is always readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    Read,
+    ReadWrite,
+    ReadExecute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    OutOfMemory,
+    AddressInUse,       // Already mapped, or reserved for the kernel
+    InvalidAddress,     // Not page aligned, or outside of user memory
+    NotMapped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutableError {
+    Invalid(&'static str),
+    Memory(MemoryError),
+    ArgumentsTooLarge,  // argv, envp and auxv don't fit on the stack
+}
+
+impl From<MemoryError> for ExecutableError {
+    fn from(error: MemoryError) -> ExecutableError {
+        ExecutableError::Memory(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// A page fault the architecture couldn't resolve by itself, see
+/// [`crate::kernel::interrupts::page_fault`].
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    pub address: usize,
+    pub instruction_pointer: usize,
+    pub stack_pointer: usize,
+    pub access: Access,
+    pub present: bool, // The page is mapped, but doesn't allow `access`
+    pub user: bool,    // Caused by unprivileged code
+}
+
+impl PageFault {
+    /// Whether the fault hit the guard below the stack of a process.
+    pub fn is_stack_overflow(&self) -> bool {
+        let guard_end = USER_STACK_TOP - USER_STACK_SIZE;
+        self.user && (guard_end - USER_STACK_GUARD_SIZE..guard_end).contains(&self.address)
+    }
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.access {
+            Access::Read => "reading",
+            Access::Write => "writing",
+            Access::Execute => "executing",
+        };
+        let mode = if self.user { "user" } else { "kernel" };
+        let reason = if self.present { "not allowed" } else { "not mapped" };
+
+        write!(
+            f,
+            "page fault {} {:#x} in {} mode ({}), at {:#x} with stack {:#x}",
+            access, self.address, mode, reason, self.instruction_pointer, self.stack_pointer,
+        )?;
+
+        if self.is_stack_overflow() {
+            write!(f, ", stack overflow")?;
+        }
+        Ok(())
+    }
+}
+
+/// Thread local storage template of an executable, copied for every thread.
+#[derive(Debug, Clone, Copy)]
+pub struct TlsTemplate {
+    pub start: usize,
+    pub file_size: usize,
+    pub mem_size: usize,
+}
+
+/// Where an executable ended up after [`MemoryManager::load_executable`].
+#[derive(Debug, Clone, Copy)]
+pub struct ExecutableImage {
+    pub entry: usize,
+    pub base: usize,                    // Offset applied to a position independent executable, 0 otherwise
+    pub program_headers: Option<usize>, // Address of the program headers, if they were loaded
+    pub program_header_size: usize,
+    pub program_header_count: usize,
+    pub tls: Option<TlsTemplate>,
+}
+
 pub trait MemoryManager {
-    
-}
\ No newline at end of file
+    /// The address space the kernel booted in, it has no user memory.
+    fn kernel_address_space() -> AddressSpace;
+
+    /// Create an address space with only the kernel mapped in it.
+    fn create_address_space() -> Result<AddressSpace, MemoryError>;
+
+    /// Copy the user memory of `space` into a new address space. Nothing is
+    /// copied until either of them writes to a page (copy-on-write).
+    fn clone_address_space(space: AddressSpace) -> Result<AddressSpace, MemoryError>;
+
+    /// Free all user memory in `space`, and the page tables themselves. Fails
+    /// with [`MemoryError::AddressInUse`] when `space` is active or belongs to
+    /// the kernel.
+    fn destroy_address_space(space: AddressSpace) -> Result<(), MemoryError>;
+
+    /// Make `space` the active address space.
+    ///
+    /// Unsafe because `space` must come from [`MemoryManager::create_address_space`]
+    /// (or be the kernel's) and not be destroyed.
+    unsafe fn switch_address_space(space: AddressSpace);
+
+    /// Map the ELF executable `data` into `space`, it must not be active.
+    fn load_executable(space: AddressSpace, data: &[u8]) -> Result<ExecutableImage, ExecutableError>;
+
+    /// Map `size` bytes of zeroed, user accessible memory at `address`.
+    fn map(space: AddressSpace, address: usize, size: usize, protection: Protection) -> Result<(), MemoryError>;
+
+    /// Reserve `size` bytes at `address`, every page is mapped (zeroed) when it
+    /// is first used. For memory that may never be needed, like the end of a
+    /// stack or a growing heap.
+    fn reserve(space: AddressSpace, address: usize, size: usize, protection: Protection) -> Result<(), MemoryError>;
+
+    /// Unmap and free `size` bytes at `address`, pages that aren't mapped are skipped.
+    fn unmap(space: AddressSpace, address: usize, size: usize) -> Result<(), MemoryError>;
+
+    /// Change the access rights of `size` bytes at `address`, which must be mapped.
+    fn protect(space: AddressSpace, address: usize, size: usize, protection: Protection) -> Result<(), MemoryError>;
+
+    /// Copy `data` to `address` in `space`, the whole range has to be mapped.
+    fn write(space: AddressSpace, address: usize, data: &[u8]) -> Result<(), MemoryError>;
+
+    /// Whether user mode may `access` all `size` bytes at `address` in the
+    /// active address space. Reserved pages are mapped and copy-on-write pages
+    /// copied first, as a fault would, so the kernel can use the range without
+    /// faulting.
+    fn check_user_access(address: usize, size: usize, access: Access) -> bool;
+
+    /// Make `size` bytes of physical memory at `address` accessible to the
+    /// kernel, returns the virtual address they start at. For firmware tables
+    /// and memory mapped devices, it stays mapped. Device memory isn't cached.
+    fn map_physical(address: usize, size: usize) -> Result<usize, MemoryError>;
+}
@@ -1,5 +1,17 @@
 pub mod abstractions;
+pub mod acpi;
+pub mod boot;
+pub mod command_line;
+pub mod cpu;
 pub mod rendering;
+pub mod initrd;
 pub mod interrupts;
+pub mod lock;
+pub mod log;
 pub mod memory;
+pub mod ports;
+pub mod power;
+pub mod stream;
+pub mod syscall;
+pub mod time;
 pub mod architecture;
\ No newline at end of file
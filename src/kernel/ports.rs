@@ -0,0 +1,13 @@
+/// I/O ports, the separate address space x86 has for legacy devices and
+/// ACPI registers. Architectures without one read 0 and ignore writes.
+pub trait Ports {
+    /// Read `size` bytes (1, 2 or 4) from `port`.
+    ///
+    /// Unsafe because reading a device register can change its state.
+    unsafe fn read_port(port: u16, size: u8) -> u32;
+
+    /// Write the low `size` bytes (1, 2 or 4) of `value` to `port`.
+    ///
+    /// Unsafe because it controls hardware directly.
+    unsafe fn write_port(port: u16, size: u8, value: u32);
+}
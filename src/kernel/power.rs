@@ -0,0 +1,180 @@
+//! Turning the machine off and restarting it.
+//!
+//! Shutting down needs ACPI: writing the `\_S5` sleep type with `SLP_EN` to
+//! the PM1 control blocks. Rebooting tries the FADT reset register, then the
+//! keyboard controller and finally resets the processor in the architecture's
+//! own way, which always works.
+
+use core::fmt;
+
+use spin::Once;
+
+use super::{acpi::{self, GenericAddress}, architecture::Architecture, log};
+
+const SCI_EN: u64 = 1 << 0;
+const SLP_TYP_SHIFT: u64 = 10;
+const SLP_TYP_MASK: u64 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u64 = 1 << 13;
+
+const KEYBOARD_CONTROLLER_STATUS: u16 = 0x64;
+const KEYBOARD_CONTROLLER_BUSY: u32 = 1 << 1;
+const KEYBOARD_CONTROLLER_RESET: u32 = 0xfe;
+
+/// How many times a register is polled before giving up, reading one takes
+/// about a microsecond.
+const POLL_LIMIT: usize = 1_000_000;
+
+static POWER: Once<Power> = Once::new();
+
+/// What the architecture provides, so syscalls can turn the machine off
+/// without knowing which one it is.
+struct Power {
+    read: unsafe fn(&GenericAddress) -> Option<u64>,
+    write: unsafe fn(&GenericAddress, u64) -> bool,
+    read_port: unsafe fn(u16, u8) -> u32,
+    write_port: unsafe fn(u16, u8, u32),
+    reset: fn() -> !,
+    stop_others: fn(),
+    disable_interrupts: fn(),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    NoAcpi,             // No FADT, or `init` wasn't called
+    NoSleepType,        // No `\_S5` in the DSDT
+    HardwareReduced,    // Needs the sleep registers, which aren't supported
+    NoControlBlock,     // No PM1a control block the kernel can write
+    StillRunning,       // Everything was written, but nothing happened
+}
+
+impl fmt::Display for PowerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerError::NoAcpi => write!(f, "No ACPI tables to shut down with"),
+            PowerError::NoSleepType => write!(f, "The firmware doesn't declare how to shut down"),
+            PowerError::HardwareReduced => write!(f, "Hardware reduced ACPI isn't supported"),
+            PowerError::NoControlBlock => write!(f, "No usable PM1 control block"),
+            PowerError::StillRunning => write!(f, "The machine didn't turn off"),
+        }
+    }
+}
+
+/// Remember how to reach the hardware on `Arch`, call after `acpi::init`.
+pub fn init<Arch: Architecture>() {
+    POWER.call_once(|| Power {
+        read: GenericAddress::read_register::<Arch>,
+        write: GenericAddress::write_register::<Arch>,
+        read_port: Arch::read_port,
+        write_port: Arch::write_port,
+        reset: Arch::reset,
+        stop_others: Arch::stop_others,
+        disable_interrupts: Arch::disable,
+    });
+}
+
+/// Turn the machine off, only returns when the hardware can't be told to. The
+/// other processors are halted before it is, and can't be resumed, so when it
+/// didn't work this one halts as well.
+pub fn shutdown() -> PowerError {
+    let power = match POWER.get() {
+        Some(power) => power,
+        None => return PowerError::NoAcpi,
+    };
+    let (fadt, sleep_type) = match acpi::get().and_then(|acpi| Some((acpi.fadt?, acpi.s5_sleep_type))) {
+        Some((fadt, Some(sleep_type))) => (fadt, sleep_type),
+        Some((_, None)) => return PowerError::NoSleepType,
+        None => return PowerError::NoAcpi,
+    };
+    if fadt.hardware_reduced {
+        return PowerError::HardwareReduced;
+    }
+    let pm1a = match fadt.pm1a_control_block {
+        Some(block) => block,
+        None => return PowerError::NoControlBlock,
+    };
+
+    unsafe {
+        let control = match (power.read)(&pm1a) {
+            Some(control) => control,
+            None => return PowerError::NoControlBlock,
+        };
+
+        // The firmware may still be handling power events itself (legacy mode)
+        if control & SCI_EN == 0 {
+            if let Some(smi_command) = fadt.smi_command.filter(|_| fadt.acpi_enable != 0) {
+                (power.write_port)(smi_command as u16, 1, fadt.acpi_enable as u32);
+                poll(|| (power.read)(&pm1a).map_or(true, |control| control & SCI_EN != 0));
+            }
+        }
+
+        // Nothing should be halfway writing to a disk when the power goes
+        (power.stop_others)();
+
+        let write = |block: &GenericAddress, sleep_type: u8| {
+            let control = (power.read)(block).unwrap_or(0) & !(SLP_TYP_MASK | SLP_EN);
+            (power.write)(block, control | (sleep_type as u64) << SLP_TYP_SHIFT | SLP_EN)
+        };
+        if let Some(pm1b) = fadt.pm1b_control_block {
+            write(&pm1b, sleep_type.pm1b);
+        }
+        let error = if write(&pm1a, sleep_type.pm1a) {
+            // Turning off can take a moment
+            settle(power);
+            PowerError::StillRunning
+        } else {
+            PowerError::NoControlBlock
+        };
+        halt(power, error)
+    }
+}
+
+/// Restart the machine, after halting the other processors.
+pub fn reboot() -> ! {
+    let power = POWER.get().expect("power::init wasn't called");
+    (power.stop_others)();
+
+    unsafe {
+        if let Some(fadt) = acpi::get().and_then(|acpi| acpi.fadt) {
+            if let Some(reset_register) = fadt.reset_register {
+                if (power.write)(&reset_register, fadt.reset_value as u64) {
+                    settle(power);
+                }
+            }
+        }
+
+        // Pulses the reset line, on every PC that still has one. Writing to a
+        // port nothing listens to does nothing.
+        poll(|| (power.read_port)(KEYBOARD_CONTROLLER_STATUS, 1) & KEYBOARD_CONTROLLER_BUSY == 0);
+        (power.write_port)(KEYBOARD_CONTROLLER_STATUS, 1, KEYBOARD_CONTROLLER_RESET);
+        settle(power);
+    }
+
+    (power.reset)()
+}
+
+/// Stop this processor for good, after the others were. Whatever they held
+/// stays locked, so printing why might not get through either.
+fn halt(power: &Power, error: PowerError) -> ! {
+    (power.disable_interrupts)();
+    log::warn(format_args!("Couldn't shut down: {}", error));
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Call `done` until it returns true, at most [`POLL_LIMIT`] times.
+fn poll(mut done: impl FnMut() -> bool) {
+    for _ in 0..POLL_LIMIT {
+        if done() {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Give the hardware about a second to act on a write.
+unsafe fn settle(power: &Power) {
+    for _ in 0..POLL_LIMIT {
+        (power.read_port)(KEYBOARD_CONTROLLER_STATUS, 1);
+    }
+}
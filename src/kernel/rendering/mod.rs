@@ -1,6 +1,7 @@
 //! Generic renderer with a target framebuffer and a backend.
 
 pub mod backend;
+pub mod panic_screen;
 
 use alloc::vec::Vec;
 use fontdue::{Font, layout::{Layout, CoordinateSystem, TextStyle}};
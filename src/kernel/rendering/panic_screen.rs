@@ -0,0 +1,124 @@
+//! Text on a blue screen for kernel panics.
+//!
+//! [`Renderer`](super::Renderer) draws into a buffer on the heap and rasterizes
+//! its fonts there too, which can't be trusted anymore after a panic. This
+//! writes straight into the framebuffer with a bitmap font instead, without
+//! allocating or taking locks.
+
+use core::{fmt, ptr};
+
+use noto_sans_mono_bitmap::{get_bitmap, get_bitmap_width, BitmapHeight, FontWeight};
+
+use crate::kernel::abstractions::rendering::{FrameBuffer, FrameBufferInfo, PixelFormat};
+
+const BACKGROUND: [u8; 3] = [0x10, 0x3c, 0x9c]; // RGB
+const FOREGROUND: [u8; 3] = [0xff, 0xff, 0xff];
+
+/// Space around the text, in pixels
+const MARGIN: usize = 24;
+
+const CHAR_WIDTH: usize = get_bitmap_width(FontWeight::Regular, BitmapHeight::Size14);
+const LINE_HEIGHT: usize = 16;
+
+/// Draws whatever is written to it, line by line. Text that doesn't fit is
+/// wrapped, once the screen is full the rest is dropped.
+pub struct PanicScreen {
+    start: *mut u8,
+    info: FrameBufferInfo,
+    x: usize,
+    y: usize,
+}
+
+impl PanicScreen {
+    /// Clear the whole screen to start writing at the top.
+    ///
+    /// Unsafe because nothing else may draw to `framebuffer` anymore.
+    pub unsafe fn take_over<F: FrameBuffer>(framebuffer: &F) -> PanicScreen {
+        let mut screen = PanicScreen {
+            start: framebuffer.get_start_address(),
+            info: framebuffer.info(),
+            x: MARGIN,
+            y: MARGIN,
+        };
+
+        for y in 0..screen.info.height {
+            for x in 0..screen.info.width {
+                screen.set_pixel(x, y, BACKGROUND);
+            }
+        }
+
+        screen
+    }
+
+    fn newline(&mut self) {
+        self.x = MARGIN;
+        self.y += LINE_HEIGHT;
+    }
+
+    fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.x = MARGIN,
+            '\t' => {
+                for _ in 0..4 {
+                    self.draw_char(' ');
+                }
+            }
+            _ => self.draw_char(c),
+        }
+    }
+
+    fn draw_char(&mut self, c: char) {
+        if self.x + CHAR_WIDTH > self.info.width.saturating_sub(MARGIN) {
+            self.newline();
+        }
+        if self.y + LINE_HEIGHT > self.info.height.saturating_sub(MARGIN) {
+            return; // Full
+        }
+
+        let bitmap = get_bitmap(c, FontWeight::Regular, BitmapHeight::Size14)
+            .or_else(|| get_bitmap('?', FontWeight::Regular, BitmapHeight::Size14));
+
+        if let Some(bitmap) = bitmap {
+            for (y, row) in bitmap.bitmap().iter().enumerate() {
+                for (x, &intensity) in row.iter().enumerate() {
+                    self.set_pixel(self.x + x, self.y + y, blend(intensity));
+                }
+            }
+        }
+
+        self.x += CHAR_WIDTH;
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, [r, g, b]: [u8; 3]) {
+        let bytes_per_pixel = self.info.bytes_per_pixel.min(4);
+        let color = match self.info.pixel_format {
+            PixelFormat::RGB => [r, g, b, 0],
+            PixelFormat::BGR => [b, g, r, 0],
+            PixelFormat::U8 => [((r as u16 + g as u16 + b as u16) / 3) as u8, 0, 0, 0],
+        };
+
+        let offset = (y * self.info.stride + x) * self.info.bytes_per_pixel;
+        for (index, &byte) in color[..bytes_per_pixel].iter().enumerate() {
+            // Volatile, so the writes to the framebuffer happen before halting
+            unsafe { ptr::write_volatile(self.start.add(offset + index), byte) };
+        }
+    }
+}
+
+/// Mix of the foreground and background colors, for anti-aliased glyphs.
+fn blend(intensity: u8) -> [u8; 3] {
+    let mut color = [0; 3];
+    for (channel, (&foreground, &background)) in color.iter_mut().zip(FOREGROUND.iter().zip(BACKGROUND.iter())) {
+        let mixed = foreground as u16 * intensity as u16 + background as u16 * (255 - intensity as u16);
+        *channel = (mixed / 255) as u8;
+    }
+    color
+}
+
+impl fmt::Write for PanicScreen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.chars().for_each(|c| self.write_char(c));
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+//! Kernel side of `hugo4os_syscall::stream::Stream`.
+//!
+//! Every process owns a table of stream handles, each handle points at a
+//! shared [`StreamObject`]. Pipes keep written bytes in a bounded ring buffer
+//! until somebody reads them, console and serial streams buffer their output
+//! until it is flushed into the sink registered by the architecture.
+
+use core::{future::Future, pin::Pin, sync::atomic::{AtomicUsize, Ordering}, task::{Context, Poll}};
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use conquer_once::spin::OnceCell;
+use futures_util::task::AtomicWaker;
+use spin::Lazy;
+
+pub use hugo4os_syscall::stream::StreamKind;
+
+use crate::{constants::STREAM_BUFFER_SIZE, kernel::lock::IrqMutex, task::process_manager::ProcessId, util::ring_buffer::RingBuffer};
+
+/// Index into the handle table of a process
+pub type StreamHandle = u64;
+
+/// Receives the bytes flushed out of a console or serial stream.
+pub type StreamSink = fn(&[u8]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamError {
+    InvalidHandle,  // The handle is not open in this process
+}
+
+static CONSOLE_SINK: OnceCell<StreamSink> = OnceCell::uninit();
+static SERIAL_SINK: OnceCell<StreamSink> = OnceCell::uninit();
+
+static HANDLE_TABLES: Lazy<IrqMutex<BTreeMap<ProcessId, Vec<Option<Arc<StreamObject>>>>>> = Lazy::new(|| IrqMutex::new(BTreeMap::new()));
+
+/// Connect console or serial streams to the hardware, called once by the
+/// architecture during boot.
+pub fn register_sink(kind: StreamKind, sink: StreamSink) {
+    let cell = match kind {
+        StreamKind::Console => &CONSOLE_SINK,
+        StreamKind::Serial => &SERIAL_SINK,
+        StreamKind::Pipe => panic!("Pipes can't be connected to a sink"),
+    };
+
+    cell.try_init_once(|| sink).expect("A sink for this StreamKind is already registered!");
+}
+
+/// The sink registered for `kind`, if any.
+pub fn sink(kind: StreamKind) -> Option<StreamSink> {
+    match kind {
+        StreamKind::Console => CONSOLE_SINK.try_get().ok().copied(),
+        StreamKind::Serial => SERIAL_SINK.try_get().ok().copied(),
+        StreamKind::Pipe => None,
+    }
+}
+
+pub struct StreamObject {
+    kind: StreamKind,
+    buffer: IrqMutex<RingBuffer>,
+    reader: AtomicWaker,
+    handles: AtomicUsize, // Amount of handle table entries pointing at this object
+}
+
+impl StreamObject {
+    fn new(kind: StreamKind) -> StreamObject {
+        StreamObject {
+            kind,
+            buffer: IrqMutex::new(RingBuffer::new(STREAM_BUFFER_SIZE)),
+            reader: AtomicWaker::new(),
+            handles: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn kind(&self) -> StreamKind {
+        self.kind
+    }
+
+    /// Pipes accept as many bytes as fit in their buffer (so writes may be
+    /// short), console and serial streams flush to their sink whenever the
+    /// buffer fills up and therefore always accept everything.
+    pub fn write(&self, mut data: &[u8]) -> usize {
+        let mut buffer = self.buffer.lock();
+
+        match self.kind {
+            StreamKind::Pipe => {
+                let written = buffer.push(data);
+                drop(buffer);
+
+                if written > 0 {
+                    self.reader.wake();
+                }
+
+                written
+            }
+            StreamKind::Console | StreamKind::Serial => {
+                let total = data.len();
+                while !data.is_empty() {
+                    let written = buffer.push(data);
+                    data = &data[written..];
+
+                    if buffer.is_full() {
+                        self.flush_buffer(&mut buffer);
+                    }
+                }
+
+                total
+            }
+        }
+    }
+
+    /// Read whatever is available without waiting, console and serial streams
+    /// are output only and never return any bytes.
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        match self.kind {
+            StreamKind::Pipe => self.buffer.lock().pop(buffer),
+            StreamKind::Console | StreamKind::Serial => 0,
+        }
+    }
+
+    /// Wait for at least one byte, then read up to `buffer.len()` bytes.
+    ///
+    /// Resolves to `0` when nothing is left to read and no other handle to the
+    /// stream exists that could still write to it.
+    pub fn read_async(self: Arc<Self>, buffer: &mut [u8]) -> ReadFuture<'_> {
+        ReadFuture { stream: self, buffer }
+    }
+
+    pub fn flush(&self) {
+        if self.kind != StreamKind::Pipe {
+            self.flush_buffer(&mut self.buffer.lock());
+        }
+    }
+
+    fn flush_buffer(&self, buffer: &mut RingBuffer) {
+        match sink(self.kind) {
+            Some(sink) => buffer.drain(sink),
+            None => buffer.clear(), // Nowhere to send it
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.handles.load(Ordering::Acquire) <= 1
+    }
+}
+
+pub struct ReadFuture<'a> {
+    stream: Arc<StreamObject>,
+    buffer: &'a mut [u8],
+}
+
+impl<'a> Future for ReadFuture<'a> {
+    type Output = usize;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<usize> {
+        let this = self.get_mut();
+
+        let read = this.stream.read(this.buffer);
+        if read > 0 || this.stream.kind != StreamKind::Pipe || this.stream.is_closed() {
+            return Poll::Ready(read);
+        }
+
+        this.stream.reader.register(&cx.waker());
+        match this.stream.read(this.buffer) {
+            0 if !this.stream.is_closed() => Poll::Pending,
+            read => {
+                this.stream.reader.take();
+                Poll::Ready(read)
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Handle tables                                                              //
+////////////////////////////////////////////////////////////////////////////////
+
+fn insert(process: ProcessId, stream: Arc<StreamObject>) -> StreamHandle {
+    stream.handles.fetch_add(1, Ordering::AcqRel);
+
+    let mut tables = HANDLE_TABLES.lock();
+    let table = tables.entry(process).or_insert_with(Vec::new);
+
+    match table.iter().position(Option::is_none) {
+        Some(index) => {
+            table[index] = Some(stream);
+            index as StreamHandle
+        }
+        None => {
+            table.push(Some(stream));
+            table.len() as StreamHandle - 1
+        }
+    }
+}
+
+fn release(stream: Arc<StreamObject>) {
+    if stream.handles.fetch_sub(1, Ordering::AcqRel) == 1 {
+        stream.flush();
+    }
+
+    // Let a reader blocked on the stream notice it might have been closed
+    stream.reader.wake();
+}
+
+/// Open a new stream for `process`.
+pub fn open(process: ProcessId, kind: StreamKind) -> StreamHandle {
+    insert(process, Arc::new(StreamObject::new(kind)))
+}
+
+/// Look up the stream behind a handle, mostly useful for kernel tasks that
+/// want to [`StreamObject::read_async`].
+pub fn get(process: ProcessId, handle: StreamHandle) -> Result<Arc<StreamObject>, StreamError> {
+    HANDLE_TABLES.lock()
+        .get(&process)
+        .and_then(|table| table.get(handle as usize))
+        .and_then(|stream| stream.clone())
+        .ok_or(StreamError::InvalidHandle)
+}
+
+/// Give process `to` a handle to the same stream `from` has open as `handle`,
+/// this is how two processes get connected through a pipe.
+pub fn share(from: ProcessId, handle: StreamHandle, to: ProcessId) -> Result<StreamHandle, StreamError> {
+    let stream = get(from, handle)?;
+    Ok(insert(to, stream))
+}
+
+pub fn write(process: ProcessId, handle: StreamHandle, data: &[u8]) -> Result<usize, StreamError> {
+    Ok(get(process, handle)?.write(data))
+}
+
+pub fn read(process: ProcessId, handle: StreamHandle, buffer: &mut [u8]) -> Result<usize, StreamError> {
+    Ok(get(process, handle)?.read(buffer))
+}
+
+pub fn flush(process: ProcessId, handle: StreamHandle) -> Result<(), StreamError> {
+    get(process, handle)?.flush();
+    Ok(())
+}
+
+/// Remove a handle, the stream itself is flushed and freed once its last
+/// handle is closed.
+pub fn close(process: ProcessId, handle: StreamHandle) -> Result<(), StreamError> {
+    let stream = HANDLE_TABLES.lock()
+        .get_mut(&process)
+        .and_then(|table| table.get_mut(handle as usize))
+        .and_then(Option::take)
+        .ok_or(StreamError::InvalidHandle)?;
+
+    release(stream);
+    Ok(())
+}
+
+/// Close every handle owned by `process`, used when it exits.
+pub fn close_all(process: ProcessId) {
+    let table = HANDLE_TABLES.lock().remove(&process);
+
+    for stream in table.into_iter().flatten().flatten() {
+        release(stream);
+    }
+}
@@ -0,0 +1,104 @@
+//! Architecture independent syscall dispatching.
+//!
+//! The architecture specific handler collects the argument registers into an
+//! [`InputSyscall`], [`dispatch`] decodes the requested [`SyscallId`], checks
+//! the arguments and hands them to the matching handler.
+
+mod stream;
+mod process;
+mod system;
+mod time;
+
+use hugo4os_syscall::{ids::SyscallId, error::{SyscallError, SyscallResult}};
+use spin::Once;
+
+use crate::constants::SYSCALL_MAX_BUFFER_SIZE;
+
+use super::{abstractions::interrupts::InputSyscall, architecture::Architecture, memory::Access};
+
+/// [`MemoryManager::check_user_access`](super::memory::MemoryManager::check_user_access)
+/// of the architecture, every buffer is rejected before [`init`].
+static CHECK_USER_ACCESS: Once<fn(usize, usize, Access) -> bool> = Once::new();
+
+/// Receives exactly [`SyscallEntry::arguments`] argument registers.
+type SyscallHandler = fn(&[u64]) -> SyscallResult;
+
+struct SyscallEntry {
+    /// Amount of argument registers read by `handler`
+    arguments: usize,
+    handler: SyscallHandler,
+}
+
+/// Dispatch table, indexed by [`SyscallId`]
+static SYSCALL_TABLE: [SyscallEntry; SyscallId::COUNT] = [
+    SyscallEntry { arguments: 1, handler: stream::create }, // StreamCreate
+    SyscallEntry { arguments: 3, handler: stream::write },  // StreamWrite
+    SyscallEntry { arguments: 3, handler: stream::read },   // StreamRead
+    SyscallEntry { arguments: 1, handler: stream::flush },  // StreamFlush
+    SyscallEntry { arguments: 1, handler: stream::close },  // StreamClose
+    SyscallEntry { arguments: 1, handler: process::exit },              // ProcessExit
+    SyscallEntry { arguments: 0, handler: process::poll_signal },       // ProcessPollSignal
+    SyscallEntry { arguments: 0, handler: process::refuse_close },      // ProcessRefuseClose
+    SyscallEntry { arguments: 1, handler: process::set_close_handler }, // ProcessSetCloseHandler
+    SyscallEntry { arguments: 0, handler: system::shutdown },           // SystemShutdown
+    SyscallEntry { arguments: 0, handler: system::reboot },             // SystemReboot
+    SyscallEntry { arguments: 0, handler: time::monotonic },            // TimeMonotonic
+    SyscallEntry { arguments: 0, handler: time::wall_clock },           // TimeWallClock
+];
+
+/// Hook up the architecture, call before starting processes.
+pub fn init<Arch: Architecture>() {
+    CHECK_USER_ACCESS.call_once(|| Arch::check_user_access);
+}
+
+/// Run the syscall identified by `id`, the returned value (encoded with
+/// [`SyscallError::encode`]) is written back to the caller by the architecture.
+pub fn dispatch(id: u64, args: &InputSyscall) -> u64 {
+    let result = SyscallId::try_from(id)
+        .map_err(|_| SyscallError::UnknownSyscall)
+        .and_then(|id| {
+            let entry = &SYSCALL_TABLE[id as usize];
+            (entry.handler)(&args.args[..entry.arguments])
+        });
+
+    SyscallError::encode(result)
+}
+
+/// Check a `(pointer, length)` argument pair: the pointer must not be null,
+/// the buffer must not exceed [`SYSCALL_MAX_BUFFER_SIZE`] and every page of
+/// it has to be user memory the caller may `access`, according to its page
+/// tables.
+pub fn check_buffer(ptr: u64, len: u64, access: Access) -> SyscallResult<()> {
+    if len > SYSCALL_MAX_BUFFER_SIZE as u64 {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    if ptr == 0 {
+        return Err(SyscallError::InvalidPointer);
+    }
+
+    match CHECK_USER_ACCESS.get() {
+        Some(check_user_access) if check_user_access(ptr as usize, len as usize, access) => Ok(()),
+        _ => Err(SyscallError::InvalidPointer),
+    }
+}
+
+/// Turn a validated `(pointer, length)` argument pair into a slice.
+fn user_buffer(ptr: u64, len: u64) -> SyscallResult<&'static [u8]> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+
+    check_buffer(ptr, len, Access::Read)?;
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+/// Turn a validated `(pointer, length)` argument pair into a mutable slice.
+fn user_buffer_mut(ptr: u64, len: u64) -> SyscallResult<&'static mut [u8]> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+
+    check_buffer(ptr, len, Access::Write)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize) })
+}
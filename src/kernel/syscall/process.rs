@@ -0,0 +1,48 @@
+//! Handlers for the `Process*` syscalls, thin wrappers around [`crate::task::process_manager`].
+
+use hugo4os_syscall::error::{SyscallError, SyscallResult};
+
+use crate::{kernel::memory::Access, task::process_manager::{self, current_process_id, KERNEL_PROCESS_ID, PROCESS_MANAGER}};
+
+/// `ProcessExit(code) -> !`
+pub(super) fn exit(args: &[u64]) -> SyscallResult {
+    let id = current_process_id();
+    if id == KERNEL_PROCESS_ID {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    PROCESS_MANAGER.lock().exit(id, args[0] as i64);
+    process_manager::wait_for_switch()
+}
+
+/// `ProcessPollSignal() -> signal`, 0 when there is none
+pub(super) fn poll_signal(_args: &[u64]) -> SyscallResult {
+    let signal = PROCESS_MANAGER.lock().pending_signal(current_process_id());
+
+    Ok(signal.map_or(0, |signal| signal as u64))
+}
+
+/// `ProcessRefuseClose() -> 0`
+pub(super) fn refuse_close(_args: &[u64]) -> SyscallResult {
+    match PROCESS_MANAGER.lock().refuse_close(current_process_id()) {
+        true => Ok(0),
+        false => Err(SyscallError::InvalidArgument),
+    }
+}
+
+/// `ProcessSetCloseHandler(handler) -> 0`, 0 removes the handler
+pub(super) fn set_close_handler(args: &[u64]) -> SyscallResult {
+    let handler = match args[0] {
+        0 => None,
+        address => {
+            // Has to be code the process can run
+            super::check_buffer(address, 1, Access::Execute)?;
+            Some(address as usize)
+        }
+    };
+
+    match PROCESS_MANAGER.lock().set_close_handler(current_process_id(), handler) {
+        true => Ok(0),
+        false => Err(SyscallError::InvalidArgument),
+    }
+}
@@ -0,0 +1,53 @@
+//! Handlers for the `Stream*` syscalls, thin wrappers around [`crate::kernel::stream`].
+
+use hugo4os_syscall::error::{SyscallError, SyscallResult};
+
+use crate::{kernel::stream::{self, StreamError, StreamKind}, task::process_manager::current_process_id};
+
+fn convert_error(error: StreamError) -> SyscallError {
+    match error {
+        StreamError::InvalidHandle => SyscallError::InvalidStream,
+    }
+}
+
+/// `StreamCreate(kind) -> stream_id`
+pub(super) fn create(args: &[u64]) -> SyscallResult {
+    let kind = StreamKind::try_from(args[0]).map_err(|_| SyscallError::InvalidArgument)?;
+    if kind != StreamKind::Pipe && stream::sink(kind).is_none() {
+        return Err(SyscallError::Unsupported); // The architecture has nothing to connect it to
+    }
+
+    Ok(stream::open(current_process_id(), kind))
+}
+
+/// `StreamWrite(stream_id, buffer, len) -> bytes_written`
+pub(super) fn write(args: &[u64]) -> SyscallResult {
+    let data = super::user_buffer(args[1], args[2])?;
+
+    stream::write(current_process_id(), args[0], data)
+        .map(|written| written as u64)
+        .map_err(convert_error)
+}
+
+/// `StreamRead(stream_id, buffer, count) -> bytes_read`
+pub(super) fn read(args: &[u64]) -> SyscallResult {
+    let buffer = super::user_buffer_mut(args[1], args[2])?;
+
+    stream::read(current_process_id(), args[0], buffer)
+        .map(|read| read as u64)
+        .map_err(convert_error)
+}
+
+/// `StreamFlush(stream_id) -> 0`
+pub(super) fn flush(args: &[u64]) -> SyscallResult {
+    stream::flush(current_process_id(), args[0])
+        .map(|_| 0)
+        .map_err(convert_error)
+}
+
+/// `StreamClose(stream_id) -> 0`
+pub(super) fn close(args: &[u64]) -> SyscallResult {
+    stream::close(current_process_id(), args[0])
+        .map(|_| 0)
+        .map_err(convert_error)
+}
@@ -0,0 +1,29 @@
+//! Handlers for the `System*` syscalls, thin wrappers around [`crate::kernel::power`].
+
+use hugo4os_syscall::error::{SyscallError, SyscallResult};
+
+use crate::{kernel::{log, power}, task::process_manager::{current_process_id, INIT_PROCESS_ID, KERNEL_PROCESS_ID}};
+
+/// `SystemShutdown() -> !`, fails when the machine can't be turned off
+pub(super) fn shutdown(_args: &[u64]) -> SyscallResult {
+    check_permission()?;
+
+    // Every reason it returns comes down to the hardware not supporting it
+    let error = power::shutdown();
+    log::warn(format_args!("Process {} couldn't shut down: {}", current_process_id(), error));
+    Err(SyscallError::Unsupported)
+}
+
+/// `SystemReboot() -> !`
+pub(super) fn reboot(_args: &[u64]) -> SyscallResult {
+    check_permission()?;
+    power::reboot()
+}
+
+/// Only init decides when the machine goes off, not every program.
+fn check_permission() -> SyscallResult<()> {
+    match current_process_id() {
+        KERNEL_PROCESS_ID | INIT_PROCESS_ID => Ok(()),
+        _ => Err(SyscallError::PermissionDenied),
+    }
+}
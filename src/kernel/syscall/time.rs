@@ -0,0 +1,16 @@
+//! Handlers for the `Time*` syscalls, thin wrappers around [`crate::kernel::time`].
+
+use hugo4os_syscall::error::{SyscallError, SyscallResult};
+
+use crate::kernel::time;
+
+/// `TimeMonotonic() -> nanoseconds since boot`
+pub(super) fn monotonic(_args: &[u64]) -> SyscallResult {
+    Ok(time::monotonic().as_nanos() as u64)
+}
+
+/// `TimeWallClock() -> nanoseconds since the Unix epoch`
+pub(super) fn wall_clock(_args: &[u64]) -> SyscallResult {
+    let time = time::wall_clock().ok_or(SyscallError::Unsupported)?;
+    Ok(time.as_nanos() as u64)
+}
@@ -0,0 +1,209 @@
+//! Time since boot and the date.
+//!
+//! Three clocks, from coarse to fine: [`ticks`] counts the periodic RTC
+//! interrupt ([`TICK_FREQUENCY`]), [`monotonic`] reads the fastest counter
+//! the architecture has (calibrated once at boot) and [`wall_clock`] adds the
+//! date the battery backed clock had at boot to that.
+//!
+//! Kernel tasks wait with [`sleep`], which is woken by the ticks.
+
+use core::{fmt, future::Future, pin::Pin, sync::atomic::{AtomicU64, Ordering}, task::{Context, Poll, Waker}, time::Duration};
+
+use alloc::vec::Vec;
+use spin::Once;
+
+use crate::constants::TICK_FREQUENCY;
+
+use super::lock::IrqMutex;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static CLOCK: Once<ClockSource> = Once::new();
+
+/// Every pending [`Sleep`]: its id, the tick it ends at and who to wake.
+static SLEEPERS: IrqMutex<Vec<(u64, u64, Waker)>> = IrqMutex::new(Vec::new());
+static NEXT_SLEEP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The timers of the architecture.
+pub trait Clock {
+    /// A counter that never goes backwards, the fastest there is.
+    fn counter() -> u64;
+
+    /// How often [`Clock::counter`] increments per second, measured against
+    /// another timer. Slow, `None` when it can't be measured.
+    fn counter_frequency() -> Option<u64>;
+
+    /// The date and time (UTC) the battery backed clock says it is, `None`
+    /// when there isn't one.
+    fn read_wall_clock() -> Option<DateTime>;
+}
+
+struct ClockSource {
+    counter: fn() -> u64,
+    frequency: Option<u64>,
+    start: u64,
+    /// Since the Unix epoch, in seconds
+    boot_time: Option<u64>,
+}
+
+/// Calibrate the counter and read the date. Called before interrupts are
+/// enabled, so nothing disturbs the measurement.
+pub fn init<Arch: Clock>() {
+    CLOCK.call_once(|| {
+        let frequency = Arch::counter_frequency();
+        ClockSource {
+            counter: Arch::counter,
+            frequency,
+            start: Arch::counter(),
+            boot_time: Arch::read_wall_clock().map(|date| date.to_unix_seconds()),
+        }
+    });
+}
+
+/// Called on every periodic RTC interrupt, wakes the sleepers that are done.
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    let mut sleepers = SLEEPERS.lock();
+    sleepers.retain(|(_, deadline, waker)| {
+        if *deadline > now {
+            return true;
+        }
+        waker.wake_by_ref();
+        false
+    });
+}
+
+/// Periodic RTC interrupts since boot, [`TICK_FREQUENCY`] per second.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time since [`init`], to the nanosecond when the counter could be
+/// calibrated and to the tick otherwise.
+pub fn monotonic() -> Duration {
+    match CLOCK.get() {
+        Some(ClockSource { counter, frequency: Some(frequency), start, .. }) => {
+            let elapsed = counter().saturating_sub(*start) as u128;
+            Duration::from_nanos((elapsed * NANOS_PER_SECOND as u128 / *frequency as u128) as u64)
+        }
+        _ => Duration::from_nanos(ticks() * NANOS_PER_SECOND / TICK_FREQUENCY as u64),
+    }
+}
+
+/// Time since the Unix epoch, `None` without a battery backed clock.
+pub fn wall_clock() -> Option<Duration> {
+    let boot_time = CLOCK.get()?.boot_time?;
+    Some(Duration::from_secs(boot_time) + monotonic())
+}
+
+/// The current date and time (UTC).
+pub fn now() -> Option<DateTime> {
+    wall_clock().map(|time| DateTime::from_unix_seconds(time.as_secs()))
+}
+
+/// Wait at least `duration`, to the tick: up to a tick longer.
+pub fn sleep(duration: Duration) -> Sleep {
+    let length = (duration.as_nanos() * TICK_FREQUENCY as u128 + NANOS_PER_SECOND as u128 - 1) / NANOS_PER_SECOND as u128;
+    sleep_until(ticks().saturating_add(u64::try_from(length).unwrap_or(u64::MAX)).saturating_add(1))
+}
+
+/// Wait until [`ticks`] reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep {
+        id: NEXT_SLEEP_ID.fetch_add(1, Ordering::Relaxed),
+        deadline,
+    }
+}
+
+/// Future of [`sleep`] and [`sleep_until`].
+pub struct Sleep {
+    id: u64,
+    deadline: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+        // Checked with the lock held, or the last tick could come in between
+        let mut sleepers = SLEEPERS.lock();
+        if ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        let waker = context.waker().clone();
+        match sleepers.iter_mut().find(|(id, _, _)| *id == self.id) {
+            Some(sleeper) => sleeper.2 = waker,
+            None => sleepers.push((self.id, self.deadline, waker)),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        SLEEPERS.lock().retain(|(id, _, _)| *id != self.id);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,      // 1 to 12
+    pub day: u8,        // 1 to 31
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00, dates before it count as that.
+    pub fn to_unix_seconds(&self) -> u64 {
+        // Years start in March, so the leap day is the last day of a year
+        let (year, month) = match self.month {
+            1 | 2 => (self.year as i64 - 1, self.month as i64 + 9),
+            _ => (self.year as i64, self.month as i64 - 3),
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        let seconds = days * SECONDS_PER_DAY as i64
+            + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        seconds.max(0) as u64
+    }
+
+    pub fn from_unix_seconds(seconds: u64) -> DateTime {
+        let days = (seconds / SECONDS_PER_DAY) as i64 + 719_468;
+        let time = seconds % SECONDS_PER_DAY;
+
+        let era = days / 146_097;
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
@@ -4,11 +4,11 @@
 extern crate alloc;
 
 use fontdue::{Font, FontSettings};
+use spin::Once;
 
-use kernel::{rendering::{Renderer, backend::cpu::CPURenderer}, architecture::Architecture, interrupts::Interrupts};
-use task::{executor::Executor, Task};
+use kernel::{rendering::{Renderer, backend::cpu::CPURenderer}, architecture::Architecture, boot::BootInfo, interrupts::Interrupts};
+use task::{process_manager::{self, PROCESS_MANAGER}, shared_executor::SharedExecutor};
 
-#[cfg(test)] pub mod tests;
 #[rustfmt::skip] pub mod constants;
 
 pub mod loaders;
@@ -17,9 +17,16 @@ pub mod task;
 pub mod util;
 
 // TODO: Add aarch64 support
-// TODO: Make `kernel_main` architecture independent, with bootloaders starting it instead of the other way around
 
-pub fn kernel_main<Arch: Architecture>(framebuffer: Arch::FrameBuffer) -> ! {
+/// Runs the kernel tasks, on every processor.
+static EXECUTOR: Once<SharedExecutor> = Once::new();
+
+/// Entered by the architecture once it can allocate, with everything its
+/// bootloader passed on.
+pub fn kernel_main<Arch: Architecture>(boot_info: BootInfo<Arch>) -> ! {
+    kernel::lock::init::<Arch>();
+
+    let framebuffer = boot_info.framebuffer.expect("Booted without a framebuffer");
     let mut renderer = Renderer::new(framebuffer, CPURenderer::new());
 
     // Display splash screen
@@ -43,8 +50,27 @@ pub fn kernel_main<Arch: Architecture>(framebuffer: Arch::FrameBuffer) -> ! {
 
     renderer.present();
     
-    renderer.fonts.push(Font::from_bytes(constants::FONT_REGULAR, FontSettings::default()).unwrap());
-    renderer.fonts.push(Font::from_bytes(constants::FONT_NERD_MONO, FontSettings::default()).unwrap());
+    // Without ACPI the legacy PIC and PIT keep being used
+    if let Some(rsdp_address) = boot_info.rsdp_address {
+        if let Err(error) = kernel::acpi::init::<Arch>(rsdp_address) {
+            kernel::log::warn(format_args!("Ignoring ACPI: {}", error));
+        }
+    }
+    Arch::init_controller(kernel::acpi::get().and_then(|acpi| acpi.madt.as_ref()));
+    kernel::power::init::<Arch>();
+    kernel::syscall::init::<Arch>();
+    kernel::time::init::<Arch>();
+
+    if let Some(initrd) = boot_info.initrd {
+        kernel::initrd::init(initrd);
+    }
+
+    // Without an initrd there is just nothing to draw text with
+    for path in constants::FONTS {
+        if let Some(font) = kernel::initrd::get(path).and_then(|data| Font::from_bytes(data, FontSettings::default()).ok()) {
+            renderer.fonts.push(font);
+        }
+    }
 
     renderer.clear_screen();
     renderer.present();
@@ -57,7 +83,18 @@ pub fn kernel_main<Arch: Architecture>(framebuffer: Arch::FrameBuffer) -> ! {
         renderer.present();
     }
 
-    let mut executor = Executor::new();
-    executor.spawn(Task::new(task::keyboard::print_keypresses()));
+    PROCESS_MANAGER.lock().init::<Arch>();
+
+    let cpus = Arch::start_others(run_executor::<Arch>);
+    let executor = EXECUTOR.call_once(|| SharedExecutor::new::<Arch>(cpus));
+    executor.spawn(task::keyboard::print_keypresses());
+
+    // Processes only run on this processor, so their memory is freed here too
+    executor.spawn_pinned(0, process_manager::reap_exited::<Arch>());
     executor.run::<Arch>();
+}
+
+/// Entered by the other processors once they are started.
+fn run_executor<Arch: Architecture>() -> ! {
+    EXECUTOR.wait().run::<Arch>()
 }
\ No newline at end of file
@@ -0,0 +1,136 @@
+//! Loads ELF executables into a new address space.
+//!
+//! Mapping the segments is done by the architecture (see
+//! [`MemoryManager::load_executable`]), this sets up the initial stack the way
+//! the System V ABI expects it:
+//!
+//! ```text
+//! USER_STACK_TOP -> argv and envp strings
+//!                   padding (16-byte alignment)
+//!                   auxv (AT_NULL terminated)
+//!                   envp (NULL terminated)
+//!                   argv (NULL terminated)
+//! stack pointer  -> argc
+//!                   rest of the stack, mapped on first use
+//!                   guard (USER_STACK_GUARD_SIZE, never mapped)
+//! ```
+
+use core::marker::PhantomData;
+
+use alloc::vec::Vec;
+
+use crate::{kernel::memory::{MemoryManager, AddressSpace, ExecutableImage, ExecutableError, Protection}, constants::{USER_STACK_TOP, USER_STACK_SIZE, USER_STACK_ARGUMENTS_MAX}};
+
+// Auxiliary vector entry types
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+
+const PAGE_SIZE: usize = 4096;
+
+/// An executable that is ready to be entered.
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedExecutable {
+    pub address_space: AddressSpace,
+    pub entry: usize,
+    pub stack_pointer: usize,
+    pub image: ExecutableImage,
+}
+
+/// Map the executable `data` (static or position independent) into a fresh
+/// address space, with a stack holding `args` and `env`.
+pub fn load<Arch: MemoryManager>(data: &[u8], args: &[&str], env: &[&str]) -> Result<LoadedExecutable, ExecutableError> {
+    let guard = DestroyOnError::<Arch>::new(Arch::create_address_space()?);
+    let address_space = guard.space;
+    let image = Arch::load_executable(address_space, data)?;
+
+    // Only the pages holding the arguments are mapped right away, the rest of
+    // the stack (above its guard) is mapped when it is used
+    let (stack, stack_pointer) = initial_stack(&image, args, env)?;
+    let stack_mapped = stack_pointer & !(PAGE_SIZE - 1);
+    Arch::reserve(address_space, USER_STACK_TOP - USER_STACK_SIZE, USER_STACK_SIZE, Protection::ReadWrite)?;
+    Arch::map(address_space, stack_mapped, USER_STACK_TOP - stack_mapped, Protection::ReadWrite)?;
+    Arch::write(address_space, stack_pointer, &stack)?;
+
+    Ok(LoadedExecutable {
+        address_space: guard.keep(),
+        entry: image.entry,
+        stack_pointer,
+        image,
+    })
+}
+
+/// Destroys an address space that is still being set up when it is dropped,
+/// so returning early with an error frees everything mapped so far.
+struct DestroyOnError<Arch: MemoryManager> {
+    space: AddressSpace,
+    arch: PhantomData<Arch>,
+}
+
+impl<Arch: MemoryManager> DestroyOnError<Arch> {
+    fn new(space: AddressSpace) -> Self {
+        DestroyOnError { space, arch: PhantomData }
+    }
+
+    /// The address space is done, don't destroy it.
+    fn keep(self) -> AddressSpace {
+        let space = self.space;
+        core::mem::forget(self);
+        space
+    }
+}
+
+impl<Arch: MemoryManager> Drop for DestroyOnError<Arch> {
+    fn drop(&mut self) {
+        // It was never active, so this can't fail
+        let _ = Arch::destroy_address_space(self.space);
+    }
+}
+
+/// Build the contents of the initial stack, returns them together with the
+/// address they have to be written to (which is also the stack pointer).
+fn initial_stack(image: &ExecutableImage, args: &[&str], env: &[&str]) -> Result<(Vec<u8>, usize), ExecutableError> {
+    // Strings go at the very top, their addresses are known once the total size is
+    let strings_size: usize = args.iter().chain(env).map(|s| s.len() + 1).sum();
+    let strings_start = USER_STACK_TOP.checked_sub(strings_size).ok_or(ExecutableError::ArgumentsTooLarge)?;
+
+    let mut words: Vec<usize> = Vec::new();
+    let mut strings: Vec<u8> = Vec::with_capacity(strings_size);
+
+    words.push(args.len());
+    for list in [args, env] {
+        for string in list {
+            words.push(strings_start + strings.len());
+            strings.extend_from_slice(string.as_bytes());
+            strings.push(0);
+        }
+        words.push(0);
+    }
+
+    if let Some(program_headers) = image.program_headers {
+        words.extend([AT_PHDR, program_headers]);
+        words.extend([AT_PHENT, image.program_header_size]);
+        words.extend([AT_PHNUM, image.program_header_count]);
+    }
+    words.extend([AT_PAGESZ, PAGE_SIZE]);
+    words.extend([AT_ENTRY, image.entry]);
+    words.extend([AT_NULL, 0]);
+
+    let words_size = words.len() * core::mem::size_of::<usize>();
+    let stack_pointer = strings_start.checked_sub(words_size).ok_or(ExecutableError::ArgumentsTooLarge)? & !0xf;
+    if USER_STACK_TOP - stack_pointer > USER_STACK_ARGUMENTS_MAX {
+        return Err(ExecutableError::ArgumentsTooLarge);
+    }
+
+    let mut stack = Vec::with_capacity(USER_STACK_TOP - stack_pointer);
+    for word in words {
+        stack.extend_from_slice(&word.to_ne_bytes());
+    }
+    stack.resize(strings_start - stack_pointer, 0);
+    stack.extend_from_slice(&strings);
+
+    Ok((stack, stack_pointer))
+}
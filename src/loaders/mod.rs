@@ -1 +1,3 @@
-pub mod image;
\ No newline at end of file
+pub mod elf;
+pub mod image;
+pub mod tar;
\ No newline at end of file
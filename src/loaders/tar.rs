@@ -0,0 +1,162 @@
+//! Reads (ustar) tar archives in place, like the initrd.
+//!
+//! An archive is a list of 512-byte headers, each followed by the contents of
+//! its file padded to a multiple of 512 bytes, and ends with two empty headers:
+//!
+//! ```text
+//! 0    name (NUL terminated unless it is 100 bytes long)
+//! 124  size (octal, NUL or space terminated)
+//! 156  type ('0' or NUL for regular files)
+//! 257  magic ("ustar")
+//! 345  prefix, joined to the name with a '/' when it isn't empty
+//! ```
+
+const BLOCK_SIZE: usize = 512;
+
+const NAME: core::ops::Range<usize> = 0..100;
+const SIZE: core::ops::Range<usize> = 124..136;
+const TYPE: usize = 156;
+const MAGIC: core::ops::Range<usize> = 257..262;
+const PREFIX: core::ops::Range<usize> = 345..500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarError {
+    UnexpectedEOF,
+    InvalidHeader,  // Not a tar header, or the size is not a number
+    InvalidName,    // Not UTF-8
+}
+
+/// A tar archive in memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Archive<'a> {
+    data: &'a [u8],
+}
+
+/// A regular file in an [`Archive`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct File<'a> {
+    /// Directory of the file when the path didn't fit in `name`, without any
+    /// leading `./` or `/`. Usually empty.
+    pub prefix: &'a str,
+    pub name: &'a str,
+    pub data: &'a [u8],
+}
+
+impl<'a> File<'a> {
+    /// Whether this file is at `path`, which is relative to the archive root.
+    pub fn is_at(&self, path: &str) -> bool {
+        let path = normalize(path);
+        match path.strip_prefix(self.prefix) {
+            Some(rest) if !self.prefix.is_empty() => rest.strip_prefix('/') == Some(self.name),
+            _ => self.prefix.is_empty() && path == self.name,
+        }
+    }
+}
+
+impl<'a> Archive<'a> {
+    pub fn new(data: &'a [u8]) -> Archive<'a> {
+        Archive { data }
+    }
+
+    /// All regular files, directories and links are skipped.
+    pub fn files(&self) -> Files<'a> {
+        Files { rest: self.data }
+    }
+
+    /// Contents of the file at `path`, relative to the archive root.
+    pub fn get(&self, path: &str) -> Option<&'a [u8]> {
+        self.files()
+            .filter_map(Result::ok)
+            .find(|file| file.is_at(path))
+            .map(|file| file.data)
+    }
+}
+
+/// Iterator over the files of an [`Archive`], stops after the first error.
+pub struct Files<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Files<'a> {
+    type Item = Result<File<'a>, TarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let header = self.rest.get(..BLOCK_SIZE)?;
+            if header.iter().all(|&byte| byte == 0) {
+                self.rest = &[];
+                return None;  // End of the archive
+            }
+
+            let entry = parse_entry(self.rest);
+            match entry {
+                Ok((file, next)) => {
+                    self.rest = next;
+                    if let Some(file) = file {
+                        return Some(Ok(file));
+                    }
+                }
+                Err(error) => {
+                    self.rest = &[];
+                    return Some(Err(error));
+                }
+            }
+        }
+    }
+}
+
+/// The entry at the start of `data` if it is a regular file, and everything
+/// after it.
+fn parse_entry(data: &[u8]) -> Result<(Option<File<'_>>, &[u8]), TarError> {
+    let header = &data[..BLOCK_SIZE];
+    if &header[MAGIC] != b"ustar" {
+        return Err(TarError::InvalidHeader);
+    }
+
+    let size = parse_octal(&header[SIZE]).ok_or(TarError::InvalidHeader)?;
+    let padded_size = size.checked_add(BLOCK_SIZE - 1).ok_or(TarError::InvalidHeader)? / BLOCK_SIZE * BLOCK_SIZE;
+    let contents = data.get(BLOCK_SIZE..).ok_or(TarError::UnexpectedEOF)?;
+    let file_data = contents.get(..size).ok_or(TarError::UnexpectedEOF)?;
+    let next = contents.get(padded_size..).unwrap_or(&[]);
+
+    if !matches!(header[TYPE], b'0' | 0) {
+        return Ok((None, next));
+    }
+
+    let prefix = normalize(string(&header[PREFIX])?);
+    let name = string(&header[NAME])?;
+    let name = if prefix.is_empty() { normalize(name) } else { name };
+    Ok((Some(File { prefix, name, data: file_data }), next))
+}
+
+/// A NUL terminated (unless it fills the whole field) string.
+fn string(field: &[u8]) -> Result<&str, TarError> {
+    let length = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..length]).map_err(|_| TarError::InvalidName)
+}
+
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let digits = field.iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| byte != 0 && byte != b' ');
+
+    let mut value: usize = 0;
+    for &digit in digits {
+        if !(b'0'..=b'7').contains(&digit) {
+            return None;
+        }
+        value = value.checked_mul(8)?.checked_add((digit - b'0') as usize)?;
+    }
+    Some(value)
+}
+
+/// `path` without the `./` or `/` it may start with.
+fn normalize(path: &str) -> &str {
+    let mut path = path;
+    loop {
+        match path.strip_prefix("./").or_else(|| path.strip_prefix('/')) {
+            Some(rest) => path = rest,
+            None => return path,
+        }
+    }
+}
@@ -5,6 +5,7 @@ use alloc::boxed::Box;
 pub mod executor;
 pub mod keyboard;
 pub mod process_manager;
+pub mod shared_executor;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
@@ -1,80 +1,656 @@
-use alloc::vec::Vec;
+use core::{fmt, sync::atomic::Ordering, task::{Poll, Waker}, time::Duration};
 
-#[derive(Debug, Clone, Copy)]
+use alloc::{boxed::Box, vec, vec::Vec};
+use futures_util::{future::poll_fn, task::AtomicWaker};
+use hugo4os_syscall::process::ProcessSignal;
+use spin::Once;
+
+use crate::{kernel::{architecture::Architecture, cpu::CpuLocal, lock::IrqMutex, memory::{AddressSpace, ExecutableError, MemoryError}, stream}, loaders::elf, constants::{SCHEDULER_FREQUENCY, PROCESS_KERNEL_STACK_SIZE, CLOSE_REQUEST_TIMEOUT, GRACEFUL_CLOSE_TIMEOUT, FORCE_CLOSE_TIMEOUT}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessKillSignal {
     RequestClose,               // Ask the process to, uhh, commit sudoku (the program may refuse).
     GracefulForcedClose,        // Let the process know about its imminent death so it can save and stuff, then kill it as soon as it completes the current running task.
     ForceClose,                 // Kill the process without any sort of warning, as soon as it completes the current running task.
-    Terminate,                  // Instantly close the process, this can be dangerous
+    Terminate,                  // Close the process the next time it would return to user mode, whatever it is doing.
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessKillError {
+    ProcessNotFound,
     RecuestRefused,             // The process kindly refused the offer to commit sudoku (the process didn't close after ProcessKillSignal::RequestClose).
     NoGracefulCloseHandler,     // The kernel can't find a way to notify the process that it will be assasinated soon.
-    GracefulCloseHanderTimeout, // The process took too long to finish its GracefulCloseHandler
-    ForceCloseTimeout,          // The process took too long to finish its running task
+    GracefulCloseHanderTimeout, // The process took too long to finish its GracefulCloseHandler, it is terminated instead
+    ForceCloseTimeout,          // The process took too long to finish its running task, it is terminated instead
 }
 
-type ProcessId = usize;
+pub type ProcessId = usize;
 
-#[derive(Debug, Clone)]
+/// The kernel itself, kernel tasks own their resources under this id.
+pub const KERNEL_PROCESS_ID: ProcessId = 0;
+
+/// The first process the kernel starts, which manages the rest of the system.
+/// Ids aren't reused, so no other process ever gets it.
+pub const INIT_PROCESS_ID: ProcessId = KERNEL_PROCESS_ID + 1;
+
+/// `Cpus::local` of the architecture, where every processor keeps which
+/// process it runs and whether that is inside a syscall.
+static CPU_LOCAL: Once<fn() -> &'static CpuLocal> = Once::new();
+
+/// Stands in for it before [`ProcessManager::init`], only the processor
+/// that booted runs then.
+static BOOT_CPU_LOCAL: CpuLocal = CpuLocal::new();
+
+/// Wakes [`reap_exited`] when a process is done.
+static REAPER_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// `Interrupts::enable_and_halt` of the architecture, for [`wait_for_switch`].
+static ENABLE_AND_HALT: Once<fn()> = Once::new();
+
+/// The processes the timer interrupt switches between.
+pub static PROCESS_MANAGER: IrqMutex<ProcessManager> = IrqMutex::new(ProcessManager::new());
+
+fn cpu_local() -> &'static CpuLocal {
+    CPU_LOCAL.get().map_or(&BOOT_CPU_LOCAL, |local| local())
+}
+
+/// The process currently running on this CPU, the kernel on every processor
+/// but the one that runs processes.
+pub fn current_process_id() -> ProcessId {
+    cpu_local().current_process.load(Ordering::Relaxed)
+}
+
+/// Called around every syscall, a process inside one is never killed by
+/// [`ProcessKillSignal::ForceClose`] or interrupted by its close handler.
+pub(crate) fn set_in_syscall(in_syscall: bool) {
+    cpu_local().in_syscall.store(in_syscall, Ordering::Relaxed);
+}
+
+/// Whether the process running on this CPU is inside a syscall.
+pub fn in_syscall() -> bool {
+    cpu_local().in_syscall.load(Ordering::Relaxed)
+}
+
+/// Called by a process that exited (or was terminated) in the kernel, it
+/// won't be scheduled again. Sleeps until the timer switches away, the
+/// kernel stack it runs on is freed afterwards.
+pub fn wait_for_switch() -> ! {
+    loop {
+        match ENABLE_AND_HALT.get() {
+            Some(enable_and_halt) => enable_and_halt(),
+            None => core::hint::spin_loop(),
+        }
+    }
+}
+
+/// Send `signal` to process `id` and wait until it has been dealt with. The
+/// process is gone once this returns `Ok`, its resources are freed by
+/// [`reap_exited`].
+pub async fn kill(id: ProcessId, signal: ProcessKillSignal) -> Result<(), ProcessKillError> {
+    PROCESS_MANAGER.lock().kill(id, signal)?;
+
+    poll_fn(|context| PROCESS_MANAGER.lock().poll_kill(id, context.waker())).await
+}
+
+/// Kernel task that frees the resources of exited and killed processes.
+pub async fn reap_exited<Arch: Architecture>() {
+    poll_fn(|context| {
+        REAPER_WAKER.register(context.waker());
+        PROCESS_MANAGER.lock().reap::<Arch>();
+        Poll::<()>::Pending
+    }).await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Ready,
+    Exited(i64), // Waiting to be reaped, with its exit code
+}
+
+/// Exit code of processes that didn't exit by themselves.
+pub const KILLED_EXIT_CODE: i64 = -1;
+
+/// Decides how long a process runs before the next one gets a turn, every
+/// process still gets one turn per round so none of them can starve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProcessPriority {
+    Low,
+    Normal,
+    High,
+}
+
+impl ProcessPriority {
+    /// Length of a turn, in timer ticks.
+    pub fn time_slice(self) -> usize {
+        match self {
+            ProcessPriority::Low => 1,
+            ProcessPriority::Normal => 2,
+            ProcessPriority::High => 4,
+        }
+    }
+}
+
+/// What the architecture has to do to continue another process, returned by
+/// [`ProcessManager::schedule`].
+#[derive(Debug, Clone, Copy)]
+pub struct ContextSwitch {
+    pub context: usize,                     // Saved context to restore
+    pub address_space: Option<AddressSpace>, // None for the kernel, which is mapped in every address space
+    pub kernel_stack_top: Option<usize>,     // Stack for interrupts and syscalls, None for the kernel
+}
+
+/// State of something the scheduler switches between.
+#[derive(Debug, Clone, Copy)]
+struct Scheduling {
+    priority: ProcessPriority,
+    context: usize,   // Only valid while not running
+    in_syscall: bool, // Only valid while not running
+    ticks: u64,
+    switches: u64,
+}
+
+impl Scheduling {
+    const fn new(priority: ProcessPriority, context: usize) -> Scheduling {
+        Scheduling {
+            priority,
+            context,
+            in_syscall: false,
+            ticks: 0,
+            switches: 0,
+        }
+    }
+}
+
+/// A [`ProcessKillSignal`] that is being delivered.
+#[derive(Debug)]
+struct PendingKill {
+    signal: ProcessKillSignal,
+    deadline: u64, // In ticks
+    refused: bool,
+    handler_started: bool,
+    result: Option<Result<(), ProcessKillError>>,
+    waker: Option<Waker>,
+}
+
+/// Stack the process runs on while in the kernel, the architecture saves its
+/// context here when it is preempted.
+struct KernelStack(Box<[u8]>);
+
+impl KernelStack {
+    fn new() -> KernelStack {
+        KernelStack(vec![0; PROCESS_KERNEL_STACK_SIZE].into_boxed_slice())
+    }
+
+    /// 16-byte aligned end of the stack.
+    fn top(&self) -> usize {
+        (self.0.as_ptr() as usize + self.0.len()) & !0xf
+    }
+}
+
+impl fmt::Debug for KernelStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KernelStack({:#x})", self.top())
+    }
+}
+
+#[derive(Debug)]
 pub struct Process {
     id: ProcessId,
+    address_space: AddressSpace,
+    entry: usize,
+    stack_pointer: usize,
+    kernel_stack: KernelStack,
+    scheduling: Scheduling,
+    state: ProcessState,
+    close_handler: Option<usize>,
+    kill: Option<PendingKill>,
+    terminate: bool, // Exit the next time it would return to user mode
 }
 
-#[derive(Debug, Clone)]
+impl Process {
+    /// Load the ELF executable `data`, the process is given an id once it is
+    /// added to a [`ProcessManager`].
+    pub fn load<Arch: Architecture>(data: &[u8], args: &[&str], env: &[&str]) -> Result<Process, ExecutableError> {
+        let executable = elf::load::<Arch>(data, args, env)?;
+
+        let kernel_stack = KernelStack::new();
+        let context = unsafe { Arch::initial_context(kernel_stack.top(), executable.entry, executable.stack_pointer) };
+
+        Ok(Process {
+            id: KERNEL_PROCESS_ID,
+            address_space: executable.address_space,
+            entry: executable.entry,
+            stack_pointer: executable.stack_pointer,
+            kernel_stack,
+            scheduling: Scheduling::new(ProcessPriority::Normal, context),
+            state: ProcessState::Ready,
+            close_handler: None,
+            kill: None,
+            terminate: false,
+        })
+    }
+
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    pub fn address_space(&self) -> AddressSpace {
+        self.address_space
+    }
+
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    pub fn stack_pointer(&self) -> usize {
+        self.stack_pointer
+    }
+
+    pub fn priority(&self) -> ProcessPriority {
+        self.scheduling.priority
+    }
+
+    pub fn state(&self) -> ProcessState {
+        self.state
+    }
+
+    fn exit(&mut self, code: i64) {
+        if self.state == ProcessState::Ready {
+            self.state = ProcessState::Exited(code);
+            REAPER_WAKER.wake();
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct ProcessManager {
     queue: Vec<Process>,
     current_id: ProcessId,
+    running: ProcessId,
+    slice_left: usize,
+    ticks: u64,
+    kernel: Scheduling, // The kernel runs whenever no process does, it takes turns like one
+    redirect_context: Option<unsafe fn(usize, usize) -> bool>,
 }
 
 impl ProcessManager {
-    pub fn new() -> ProcessManager {
+    pub const fn new() -> ProcessManager {
         ProcessManager {
             queue: Vec::new(),
-            current_id: 0,
+            current_id: INIT_PROCESS_ID,
+            running: KERNEL_PROCESS_ID,
+            slice_left: 0,
+            ticks: 0,
+            kernel: Scheduling::new(ProcessPriority::Normal, 0),
+            redirect_context: None,
         }
     }
+
+    /// Hook up the architecture, close handlers can't be started before this.
+    pub fn init<Arch: Architecture>(&mut self) {
+        self.redirect_context = Some(Arch::redirect_context);
+        ENABLE_AND_HALT.call_once(|| Arch::enable_and_halt);
+        CPU_LOCAL.call_once(|| Arch::local);
+    }
 }
 
 
 // Managing the queue
 impl ProcessManager {
-    pub fn add_process(&mut self, process: Process) -> ProcessId {
+    pub fn add_process(&mut self, mut process: Process) -> ProcessId {
         let id = self.current_id;
         self.current_id += 1;
 
+        process.id = id;
+        self.queue.push(process);
+
         id
     }
 
-    pub fn load_process(&mut self, data: &[u8]) -> ProcessId {
-        self.add_process(todo!())
+    /// Load the ELF executable `data` (see [`Process::load`]) and queue it.
+    pub fn load_process<Arch: Architecture>(&mut self, data: &[u8], args: &[&str], env: &[&str]) -> Result<ProcessId, ExecutableError> {
+        let process = Process::load::<Arch>(data, args, env)?;
+
+        Ok(self.add_process(process))
+    }
+
+    /// Take process `id` out of the queue, and free its handles and memory.
+    /// Its address space must not be active anymore.
+    pub fn remove_process<Arch: Architecture>(&mut self, id: ProcessId) -> Result<Option<Process>, MemoryError> {
+        let index = match self.queue.iter().position(|process| process.id == id) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+
+        // The kernel keeps running in whatever address space was active last
+        let address_space = self.queue[index].address_space;
+        Arch::with_disabled(|| unsafe { Arch::switch_address_space(Arch::kernel_address_space()) });
+        Arch::destroy_address_space(address_space)?;
+        stream::close_all(id);
+
+        Ok(Some(self.queue.remove(index)))
+    }
+
+    /// Free everything held by processes that exited, except for the one
+    /// that is running (its kernel stack is still in use).
+    pub fn reap<Arch: Architecture>(&mut self) {
+        let exited: Vec<ProcessId> = self.queue.iter()
+            .filter(|process| process.id != self.running && process.state != ProcessState::Ready)
+            .map(|process| process.id)
+            .collect();
+
+        for id in exited {
+            self.remove_process::<Arch>(id).expect("Failed to free the memory of an exited process");
+        }
     }
 
+    /// Start delivering `signal` to process `id`, it is settled by the
+    /// scheduler (see [`kill`] to wait for the outcome). The process is never
+    /// stopped in the middle of a syscall, where it may be holding kernel
+    /// locks, [`ProcessKillSignal::Terminate`] takes effect when it returns.
     #[must_use]
-    pub fn kill(&self, id: ProcessId, signal: ProcessKillSignal) -> Result<(), ProcessKillError> {
-        match signal {
-            ProcessKillSignal::RequestClose => todo!(),
-            ProcessKillSignal::GracefulForcedClose => todo!(),
-            ProcessKillSignal::ForceClose => todo!(),
-            ProcessKillSignal::Terminate => Ok(()),
+    pub fn kill(&mut self, id: ProcessId, signal: ProcessKillSignal) -> Result<(), ProcessKillError> {
+        let process = self.queue.iter_mut()
+            .find(|process| process.id == id)
+            .ok_or(ProcessKillError::ProcessNotFound)?;
+
+        if process.state != ProcessState::Ready {
+            return Ok(());
+        }
+
+        let timeout = match signal {
+            ProcessKillSignal::RequestClose => CLOSE_REQUEST_TIMEOUT,
+            ProcessKillSignal::GracefulForcedClose if process.close_handler.is_none() => return Err(ProcessKillError::NoGracefulCloseHandler),
+            ProcessKillSignal::GracefulForcedClose => GRACEFUL_CLOSE_TIMEOUT,
+            ProcessKillSignal::ForceClose => FORCE_CLOSE_TIMEOUT,
+            ProcessKillSignal::Terminate => {
+                process.terminate = true;
+                0
+            }
+        };
+
+        process.kill = Some(PendingKill {
+            signal,
+            deadline: self.ticks + timeout as u64 * SCHEDULER_FREQUENCY as u64 / 1000,
+            refused: false,
+            handler_started: false,
+            result: None,
+            waker: None,
+        });
+
+        Ok(())
+    }
+
+    /// Outcome of the last [`ProcessManager::kill`] of process `id`, `waker`
+    /// is woken once it is known.
+    pub fn poll_kill(&mut self, id: ProcessId, waker: &Waker) -> Poll<Result<(), ProcessKillError>> {
+        let process = match self.queue.iter_mut().find(|process| process.id == id) {
+            Some(process) => process,
+            None => return Poll::Ready(Ok(())), // Reaped already
+        };
+
+        if process.state != ProcessState::Ready {
+            process.kill = None;
+            return Poll::Ready(Ok(()));
+        }
+
+        match process.kill.as_mut() {
+            Some(PendingKill { result: Some(result), .. }) => {
+                let result = *result;
+                process.kill = None;
+                Poll::Ready(result)
+            }
+            Some(pending) => {
+                pending.waker = Some(waker.clone());
+                Poll::Pending
+            }
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// Exit process `id` with `code`, it won't run again.
+    pub fn exit(&mut self, id: ProcessId, code: i64) {
+        if let Some(process) = self.queue.iter_mut().find(|process| process.id == id) {
+            process.exit(code);
+        }
+    }
+
+    /// Called when process `id` returns from a syscall, true when it was
+    /// terminated (or exited) in the meantime. It must not continue then, see
+    /// [`wait_for_switch`].
+    pub fn exit_if_terminated(&mut self, id: ProcessId) -> bool {
+        match self.queue.iter_mut().find(|process| process.id == id) {
+            Some(process) if process.terminate || process.state != ProcessState::Ready => {
+                process.exit(KILLED_EXIT_CODE);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The signal waiting for process `id` to respond to, if any.
+    pub fn pending_signal(&self, id: ProcessId) -> Option<ProcessSignal> {
+        let process = self.queue.iter().find(|process| process.id == id)?;
+
+        match process.kill.as_ref()? {
+            PendingKill { signal: ProcessKillSignal::RequestClose, refused: false, result: None, .. } => Some(ProcessSignal::CloseRequested),
+            _ => None,
+        }
+    }
+
+    /// Refuse a pending [`ProcessKillSignal::RequestClose`], returns false when
+    /// there was none.
+    pub fn refuse_close(&mut self, id: ProcessId) -> bool {
+        let pending = self.queue.iter_mut()
+            .find(|process| process.id == id)
+            .and_then(|process| process.kill.as_mut());
+
+        match pending {
+            Some(pending) if pending.signal == ProcessKillSignal::RequestClose => {
+                pending.refused = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Run `handler` (a user address) on [`ProcessKillSignal::GracefulForcedClose`]
+    /// instead of whatever process `id` is doing, it has to exit when done.
+    pub fn set_close_handler(&mut self, id: ProcessId, handler: Option<usize>) -> bool {
+        match self.queue.iter_mut().find(|process| process.id == id) {
+            Some(process) => {
+                process.close_handler = handler;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns false when there is no process `id`, the new priority applies
+    /// from its next turn.
+    pub fn set_priority(&mut self, id: ProcessId, priority: ProcessPriority) -> bool {
+        match self.scheduling_mut(id) {
+            Some(scheduling) => {
+                scheduling.priority = priority;
+                true
+            }
+            None => false,
         }
     }
 }
 
 // Running processes
 impl ProcessManager {
-    pub fn run(&mut self) -> Option<ProcessRunStatistics> {
-        for process in self.queue.iter_mut() {
-            
+    /// Called on every timer tick with the context of whatever was running,
+    /// returns the process to continue once its turn is over (round robin,
+    /// the kernel first).
+    pub fn schedule(&mut self, context: usize) -> Option<ContextSwitch> {
+        let running = self.running;
+        let slice_left = self.slice_left.saturating_sub(1);
+        self.ticks += 1;
+
+        // The running process can be gone already, its context is useless then
+        if let Some(current) = self.scheduling_mut(running) {
+            current.ticks += 1;
+            current.context = context;
+            current.in_syscall = in_syscall();
+        }
+
+        self.settle_kills();
+
+        if slice_left > 0 && self.is_ready(running) {
+            self.slice_left = slice_left;
+            return None;
+        }
+
+        let next = self.next_after(running);
+        let scheduling = self.scheduling_mut(next).expect("Next process doesn't exist");
+        let time_slice = scheduling.priority.time_slice();
+
+        if next == running {
+            self.slice_left = time_slice;
+            return None;
+        }
+
+        scheduling.switches += 1;
+        let context = scheduling.context;
+        set_in_syscall(scheduling.in_syscall);
+        self.slice_left = time_slice;
+
+        self.running = next;
+        cpu_local().current_process.store(next, Ordering::Relaxed);
+
+        Some(match self.queue.iter().find(|process| process.id == next) {
+            Some(process) => ContextSwitch {
+                context,
+                address_space: Some(process.address_space),
+                kernel_stack_top: Some(process.kernel_stack.top()),
+            },
+            None => ContextSwitch {
+                context,
+                address_space: None,
+                kernel_stack_top: None,
+            },
+        })
+    }
+
+    /// CPU time used by process `id` so far, [`KERNEL_PROCESS_ID`] gives the
+    /// kernel's.
+    pub fn statistics(&self, id: ProcessId) -> Option<ProcessRunStatistics> {
+        let scheduling = if id == KERNEL_PROCESS_ID {
+            &self.kernel
+        } else {
+            &self.queue.iter().find(|process| process.id == id)?.scheduling
+        };
+
+        Some(ProcessRunStatistics {
+            id,
+            priority: scheduling.priority,
+            ticks: scheduling.ticks,
+            switches: scheduling.switches,
+        })
+    }
+
+    /// [`ProcessManager::statistics`] of the kernel and every process.
+    pub fn all_statistics(&self) -> Vec<ProcessRunStatistics> {
+        core::iter::once(KERNEL_PROCESS_ID)
+            .chain(self.queue.iter().map(|process| process.id))
+            .filter_map(|id| self.statistics(id))
+            .collect()
+    }
+
+    fn scheduling_mut(&mut self, id: ProcessId) -> Option<&mut Scheduling> {
+        if id == KERNEL_PROCESS_ID {
+            Some(&mut self.kernel)
+        } else {
+            self.queue.iter_mut().find(|process| process.id == id).map(|process| &mut process.scheduling)
         }
+    }
+
+    fn is_ready(&self, id: ProcessId) -> bool {
+        id == KERNEL_PROCESS_ID || self.queue.iter().any(|process| process.id == id && process.state == ProcessState::Ready)
+    }
 
-        None
+    /// The ready process after `id` in the queue, wrapping around to the kernel.
+    fn next_after(&self, id: ProcessId) -> ProcessId {
+        // Ids only go up, so this also works when `id` was removed
+        self.queue.iter()
+            .filter(|process| process.id > id && process.state == ProcessState::Ready)
+            .map(|process| process.id)
+            .next()
+            .unwrap_or(KERNEL_PROCESS_ID)
+    }
+
+    /// Move pending kills along, called on every tick once the context of the
+    /// running process is saved. Terminates processes that aren't in a
+    /// syscall, starts close handlers, escalates kills that timed out to
+    /// [`ProcessKillSignal::Terminate`] and wakes whoever waits for a result.
+    fn settle_kills(&mut self) {
+        let ticks = self.ticks;
+        let redirect_context = self.redirect_context;
+
+        for process in self.queue.iter_mut() {
+            let in_syscall = process.scheduling.in_syscall;
+            if process.terminate && !in_syscall {
+                process.exit(KILLED_EXIT_CODE);
+            }
+
+            let pending = match process.kill.as_mut() {
+                Some(pending) if pending.result.is_none() => pending,
+                _ => continue,
+            };
+
+            let expired = ticks >= pending.deadline;
+
+            let result = match pending.signal {
+                _ if process.state != ProcessState::Ready => Some(Ok(())),
+                ProcessKillSignal::RequestClose if pending.refused || expired => Some(Err(ProcessKillError::RecuestRefused)),
+                ProcessKillSignal::GracefulForcedClose if expired => {
+                    process.terminate = true;
+                    Some(Err(ProcessKillError::GracefulCloseHanderTimeout))
+                }
+                ProcessKillSignal::GracefulForcedClose => {
+                    // Wait for the process to be in user mode, it can't be
+                    // interrupted in the middle of a syscall
+                    if !pending.handler_started && !in_syscall {
+                        if let (Some(redirect), Some(handler)) = (redirect_context, process.close_handler) {
+                            pending.handler_started = unsafe { redirect(process.scheduling.context, handler) };
+                        }
+                    }
+                    None
+                }
+                ProcessKillSignal::ForceClose if !in_syscall => {
+                    process.state = ProcessState::Exited(KILLED_EXIT_CODE);
+                    REAPER_WAKER.wake();
+                    Some(Ok(()))
+                }
+                ProcessKillSignal::ForceClose if expired => {
+                    process.terminate = true;
+                    Some(Err(ProcessKillError::ForceCloseTimeout))
+                }
+                _ => None,
+            };
+
+            if let Some(result) = result {
+                pending.result = Some(result);
+                if let Some(waker) = pending.waker.as_ref() {
+                    waker.wake_by_ref(); // Dropping it could free memory, which isn't allowed here
+                }
+            }
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct ProcessRunStatistics {
+    pub id: ProcessId,
+    pub priority: ProcessPriority,
+    pub ticks: u64,    // Timer ticks spent running
+    pub switches: u64, // Times the process was switched to
+}
 
+impl ProcessRunStatistics {
+    pub fn cpu_time(&self) -> Duration {
+        Duration::from_micros(self.ticks * 1_000_000 / SCHEDULER_FREQUENCY as u64)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,184 @@
+//! Executor that runs its tasks on every processor.
+//!
+//! Each processor has its own queue of ready tasks. A woken task goes back to
+//! the queue of the processor that ran it last, and a processor that runs out
+//! of tasks steals them from the queues of the others. Pinned tasks have a
+//! separate queue that is never stolen from, for work that has to stay on
+//! one processor.
+
+use core::{future::Future, pin::Pin, sync::atomic::{AtomicBool, AtomicUsize, Ordering}, task::{Context, Poll, Waker}};
+
+use alloc::{boxed::Box, sync::Arc, task::Wake, vec::Vec};
+use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+
+use crate::{constants::MAXIMUM_CONCURRENT_TASKS, kernel::{cpu::Cpus, interrupts::Interrupts}};
+
+type SharedFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct Queues {
+    pinned: ArrayQueue<Arc<SharedTask>>,
+    shared: ArrayQueue<Arc<SharedTask>>,
+}
+
+struct SharedTask {
+    future: Mutex<Option<SharedFuture>>,    // `None` once it finished
+    cpu: AtomicUsize,                       // Queue it is put in when woken
+    pinned: bool,
+    queued: AtomicBool,                     // Already in a queue, waking does nothing
+    repoll: AtomicBool,                     // Woken while a processor polled it, that one queues it again
+    queues: Arc<Vec<Queues>>,
+    wake_cpu: fn(usize),
+}
+
+impl SharedTask {
+    fn schedule(self: &Arc<Self>) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let cpu = self.cpu.load(Ordering::Relaxed);
+        let queues = &self.queues[cpu];
+        let queue = if self.pinned { &queues.pinned } else { &queues.shared };
+        if queue.push(self.clone()).is_err() {
+            panic!("Task queue of processor {} full!", cpu);
+        }
+        (self.wake_cpu)(cpu);
+    }
+}
+
+impl Wake for SharedTask {
+    fn wake(self: Arc<Self>) {
+        self.schedule();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.schedule();
+    }
+}
+
+pub struct SharedExecutor {
+    queues: Arc<Vec<Queues>>,
+    wake_cpu: fn(usize),
+}
+
+impl SharedExecutor {
+    /// An executor for `cpus` processors, they start running it with
+    /// [`SharedExecutor::run`].
+    pub fn new<C: Cpus>(cpus: usize) -> SharedExecutor {
+        let queues = (0..cpus)
+            .map(|_| Queues {
+                pinned: ArrayQueue::new(MAXIMUM_CONCURRENT_TASKS),
+                shared: ArrayQueue::new(MAXIMUM_CONCURRENT_TASKS),
+            })
+            .collect();
+
+        SharedExecutor {
+            queues: Arc::new(queues),
+            wake_cpu: C::wake,
+        }
+    }
+
+    /// Run `future` on whichever processor has the least to do.
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) {
+        let cpu = (0..self.queues.len())
+            .min_by_key(|&cpu| self.queues[cpu].shared.len())
+            .unwrap_or(0);
+        self.spawn_task(Box::pin(future), cpu, false);
+    }
+
+    /// Run `future` on processor `cpu` only.
+    pub fn spawn_pinned(&self, cpu: usize, future: impl Future<Output = ()> + Send + 'static) {
+        assert!(cpu < self.queues.len(), "No processor {}", cpu);
+        self.spawn_task(Box::pin(future), cpu, true);
+    }
+
+    fn spawn_task(&self, future: SharedFuture, cpu: usize, pinned: bool) {
+        Arc::new(SharedTask {
+            future: Mutex::new(Some(future)),
+            cpu: AtomicUsize::new(cpu),
+            pinned,
+            queued: AtomicBool::new(false),
+            repoll: AtomicBool::new(false),
+            queues: self.queues.clone(),
+            wake_cpu: self.wake_cpu,
+        }).schedule();
+    }
+
+    /// Run tasks on this processor, forever.
+    pub fn run<Arch: Cpus + Interrupts>(&self) -> ! {
+        let cpu = Arch::current();
+        loop {
+            while self.run_next(cpu) {}
+            self.sleep_when_idle::<Arch>(cpu);
+        }
+    }
+
+    /// Poll the next task for processor `cpu`, false when there is none.
+    pub fn run_next(&self, cpu: usize) -> bool {
+        match self.next_task(cpu) {
+            Some(task) => {
+                self.poll(task, cpu);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// A pinned task, a task from its own queue, or one stolen from the
+    /// queue of another processor, in that order.
+    fn next_task(&self, cpu: usize) -> Option<Arc<SharedTask>> {
+        let own = &self.queues[cpu];
+        own.pinned.pop()
+            .or_else(|| own.shared.pop())
+            .or_else(|| {
+                let count = self.queues.len();
+                (1..count).find_map(|offset| self.queues[(cpu + offset) % count].shared.pop())
+            })
+    }
+
+    fn poll(&self, task: Arc<SharedTask>, cpu: usize) {
+        task.queued.store(false, Ordering::Release);
+
+        // When another processor is still polling it, that one sees this after
+        // unlocking and queues it again, instead of this one spinning on it
+        task.repoll.store(true, Ordering::SeqCst);
+        let mut future = match task.future.try_lock() {
+            Some(future) => future,
+            None => return,
+        };
+        task.repoll.store(false, Ordering::SeqCst);
+
+        if !task.pinned {
+            task.cpu.store(cpu, Ordering::Relaxed);
+        }
+
+        let finished = match future.as_mut() {
+            Some(inner) => {
+                let waker = Waker::from(task.clone());
+                let mut context = Context::from_waker(&waker);
+                inner.as_mut().poll(&mut context) == Poll::Ready(())
+            }
+            None => false,
+        };
+
+        if finished {
+            *future = None;
+        }
+
+        drop(future);
+        if task.repoll.swap(false, Ordering::SeqCst) {
+            task.schedule();
+        }
+    }
+
+    fn sleep_when_idle<Arch: Interrupts>(&self, cpu: usize) {
+        let own = &self.queues[cpu];
+        Arch::disable();
+        if own.pinned.is_empty() && own.shared.is_empty() {
+            Arch::enable_and_halt();
+        } else {
+            Arch::enable();
+        }
+    }
+}
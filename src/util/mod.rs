@@ -0,0 +1 @@
+pub mod ring_buffer;
\ No newline at end of file
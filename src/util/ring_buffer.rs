@@ -0,0 +1,96 @@
+use alloc::{boxed::Box, vec};
+
+/// Fixed capacity FIFO byte buffer.
+///
+/// Pushing never overwrites unread data, it only copies as many bytes as
+/// there is room for and reports how many that were.
+pub struct RingBuffer {
+    data: Box<[u8]>,
+    head: usize, // Index of the oldest byte
+    len: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> RingBuffer {
+        assert!(capacity > 0, "RingBuffer capacity must be non-zero");
+
+        RingBuffer {
+            data: vec![0; capacity].into_boxed_slice(),
+            head: 0,
+            len: 0,
+        }
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn free(&self) -> usize {
+        self.capacity() - self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.len == self.capacity()
+    }
+
+    /// Append as much of `bytes` as fits, returns the amount of bytes copied.
+    pub fn push(&mut self, bytes: &[u8]) -> usize {
+        let count = bytes.len().min(self.free());
+        let tail = (self.head + self.len) % self.capacity();
+        let first = count.min(self.capacity() - tail);
+
+        self.data[tail..tail + first].copy_from_slice(&bytes[..first]);
+        self.data[..count - first].copy_from_slice(&bytes[first..count]);
+        self.len += count;
+
+        count
+    }
+
+    /// Move the oldest bytes into `buffer`, returns the amount of bytes copied.
+    pub fn pop(&mut self, buffer: &mut [u8]) -> usize {
+        let count = buffer.len().min(self.len);
+        let first = count.min(self.capacity() - self.head);
+
+        buffer[..first].copy_from_slice(&self.data[self.head..self.head + first]);
+        buffer[first..count].copy_from_slice(&self.data[..count - first]);
+        self.head = (self.head + count) % self.capacity();
+        self.len -= count;
+
+        count
+    }
+
+    /// Hand all buffered bytes to `f` (in at most two contiguous slices) and
+    /// empty the buffer.
+    pub fn drain(&mut self, mut f: impl FnMut(&[u8])) {
+        if self.is_empty() {
+            return;
+        }
+
+        let first = self.len.min(self.capacity() - self.head);
+
+        f(&self.data[self.head..self.head + first]);
+        if first < self.len {
+            f(&self.data[..self.len - first]);
+        }
+
+        self.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}